@@ -0,0 +1,377 @@
+use std::collections::HashMap;
+use std::io;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{Shutdown, TcpListener, TcpStream};
+use std::old_io::timer::Timer;
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::thread::Thread;
+use std::time::Duration;
+use time;
+
+use crypto::util::fixed_time_eq;
+
+use battle_state::ClientPacketId;
+use login::LoginCommand;
+use net::{ClientId, OutPacket, ServerSlot, ServerSlotId};
+use sector_data::SectorId;
+use sector_state::{SectorCommand, ShipInfo};
+use star_map_server::StarMapCommand;
+
+// Default address for the admin console. Only reachable from the server's own machine.
+pub static DEFAULT_ADMIN_ADDRESS: &'static str = "127.0.0.1:30001";
+
+// How long an admin gets to send the password, and how long a logged in admin can sit idle before
+// being hung up on, in milliseconds. Only one admin can be connected at a time, so an abandoned
+// connection would lock everyone else out.
+static LOGIN_TIMEOUT: i64 = 30000;
+static IDLE_TIMEOUT: i64 = 300000;
+
+// How long to wait before answering a wrong password, and how many wrong passwords in a row lock
+// the console for how long, in milliseconds
+static FAILED_LOGIN_DELAY: i64 = 2000;
+static MAX_FAILED_LOGINS: u32 = 5;
+static LOCKOUT_TIME: i64 = 600000;
+
+static HELP: &'static str = "\
+Commands:
+  clients                          List connected clients with their slots and ships
+  sectors                          List sectors with all their ships
+  kick <client>                    Disconnect a client
+  broadcast <message>              Show a message to every player
+  endturn <sector>                 Simulate a sector's turn right now
+  spawnai <sector> <level> [name]  Add an AI ship to a sector
+  shutdown                         Log everyone out and stop the server
+  quit                             Close the console
+";
+
+// Everything the admin console can send commands to
+struct AdminConsole {
+    slot: ServerSlot,
+    login: Sender<LoginCommand>,
+    star_map: Sender<StarMapCommand>,
+    
+    // Wrong passwords since the last time an admin logged in, and when the console opens again
+    // after too many of them
+    failed_logins: u32,
+    locked_until: time::Timespec,
+}
+
+// What a console command wants done with the console afterwards
+enum CommandResult {
+    Continue,
+    Quit,     // Close this admin's connection
+    Shutdown, // The server is stopped, close everything
+}
+
+/// Runs a text console on a TCP port that lets admins manage the server. Admins have to send the
+/// password as the first line. Only one admin can be connected at a time, and admins that go idle
+/// get disconnected so they don't lock everyone else out. Wrong passwords are answered slowly,
+/// and too many in a row lock the console for a while. Returns once an admin shuts the server down.
+pub fn run_admin_console(address: &str, password: String, slot: ServerSlot,
+                         login: Sender<LoginCommand>, star_map: Sender<StarMapCommand>) {
+    let listener =
+        match TcpListener::bind(address) {
+            Ok(listener) => listener,
+            Err(e) => panic!("Admin console failed to listen on address {}: {}", address, e),
+        };
+    
+    println!("Admin console listening on {}", address);
+    
+    let mut console = AdminConsole {
+        slot: slot,
+        login: login,
+        star_map: star_map,
+        failed_logins: 0,
+        locked_until: time::get_time(),
+    };
+    
+    for stream in listener.incoming() {
+        let stream =
+            match stream {
+                Ok(stream) => stream,
+                Err(e) => {
+                    println!("Incoming admin connection failed: {}", e);
+                    continue;
+                },
+            };
+        
+        match console.handle_admin(stream, password.as_slice()) {
+            Ok(true) => { return; },
+            Ok(false) => {},
+            Err(e) => { println!("Lost admin connection: {}", e); },
+        }
+    }
+}
+
+impl AdminConsole {
+    // Talks to one admin until they leave. Returns true if they shut the server down.
+    fn handle_admin(&mut self, mut stream: TcpStream, password: &str) -> io::Result<bool> {
+        if time::get_time() < self.locked_until {
+            println!("Turning away admin, the console is locked");
+            try!(write!(&mut stream, "Too many wrong passwords. Try again later.\n"));
+            return Ok(false);
+        }
+        
+        let reader = BufReader::new(try!(stream.try_clone()));
+        let mut lines = reader.lines();
+        
+        // Hangs up if the admin goes quiet. Every line from the admin resets the timeout.
+        let (activity_sender, activity_receiver) = channel();
+        let watchdog_stream = try!(stream.try_clone());
+        Thread::spawn(move || {
+            watch_idle(watchdog_stream, activity_receiver);
+        });
+        
+        try!(write!(&mut stream, "password: "));
+        let attempt = match lines.next() { Some(line) => try!(line), None => { return Ok(false); } };
+        if !fixed_time_eq(attempt.trim().as_bytes(), password.as_bytes()) {
+            println!("Admin failed to log in");
+            
+            self.failed_logins += 1;
+            if self.failed_logins >= MAX_FAILED_LOGINS {
+                println!("Locking the admin console after {} wrong passwords", self.failed_logins);
+                self.failed_logins = 0;
+                self.locked_until = time::get_time() + Duration::milliseconds(LOCKOUT_TIME);
+            }
+            
+            // Nobody else can try while this connection waits
+            Timer::new().ok().expect("Failed to create admin login timer").sleep(Duration::milliseconds(FAILED_LOGIN_DELAY));
+            try!(write!(&mut stream, "Wrong password\n"));
+            return Ok(false);
+        }
+        
+        println!("Admin logged in");
+        self.failed_logins = 0;
+        activity_sender.send(IDLE_TIMEOUT);
+        try!(write!(&mut stream, "Logged in. Type help for a list of commands.\n> "));
+        
+        for line in lines {
+            let line = try!(line);
+            activity_sender.send(IDLE_TIMEOUT);
+            let words: Vec<&str> = line.as_slice().words().collect();
+            
+            let result =
+                if words.is_empty() {
+                    CommandResult::Continue
+                } else {
+                    try!(self.handle_command(&mut stream, words[0], &words[1..], line.as_slice()))
+                };
+            
+            match result {
+                CommandResult::Continue => { try!(write!(&mut stream, "> ")); },
+                CommandResult::Quit => { return Ok(false); },
+                CommandResult::Shutdown => { return Ok(true); },
+            }
+        }
+        
+        Ok(false)
+    }
+    
+    fn handle_command(&self, out: &mut TcpStream, command: &str, args: &[&str], line: &str) -> io::Result<CommandResult> {
+        match command {
+            "help" => { try!(write!(out, "{}", HELP)); },
+            "clients" => { try!(self.list_clients(out)); },
+            "sectors" => { try!(self.list_sectors(out)); },
+            "kick" => {
+                match args.get(0).and_then(|s| s.parse::<ClientId>().ok()) {
+                    Some(client_id) => {
+                        self.slot.kick_client(client_id);
+                        try!(write!(out, "Kicked client {}\n", client_id));
+                    },
+                    None => { try!(write!(out, "Usage: kick <client>\n")); },
+                }
+            },
+            "broadcast" => {
+                // Everything after the command word is the message
+                let message = line.trim()[command.len()..].trim().to_string();
+                if message.is_empty() {
+                    try!(write!(out, "Usage: broadcast <message>\n"));
+                } else {
+                    self.broadcast(message);
+                    try!(write!(out, "Sent\n"));
+                }
+            },
+            "endturn" => {
+                match parse_sector(args.get(0)) {
+                    Some(sector_id) => {
+                        let (done_sender, done_receiver) = channel();
+                        self.star_map.send(StarMapCommand::Sector(sector_id, SectorCommand::EndTurn(done_sender)));
+                        match done_receiver.recv() {
+                            Ok(()) => { try!(write!(out, "Ended the turn in sector {}\n", sector_id.0)); },
+                            Err(_) => { try!(write!(out, "No such sector\n")); },
+                        }
+                    },
+                    None => { try!(write!(out, "Usage: endturn <sector>\n")); },
+                }
+            },
+            "spawnai" => {
+                let level = args.get(1).and_then(|s| s.parse::<u8>().ok()).unwrap_or(0);
+                match parse_sector(args.get(0)) {
+                    Some(sector_id) if level > 0 => {
+                        let name = args.get(2).map(|s| s.to_string()).unwrap_or("ai".to_string());
+                        let (ship_sender, ship_receiver) = channel();
+                        self.star_map.send(StarMapCommand::Sector(sector_id, SectorCommand::SpawnAi(name, level, ship_sender)));
+                        match ship_receiver.recv() {
+                            Ok(ship_id) => { try!(write!(out, "Spawned AI ship {} in sector {}\n", ship_id, sector_id.0)); },
+                            Err(_) => { try!(write!(out, "No such sector\n")); },
+                        }
+                    },
+                    _ => { try!(write!(out, "Usage: spawnai <sector> <level> [name]\n")); },
+                }
+            },
+            "shutdown" => {
+                try!(write!(out, "Shutting down...\n"));
+                self.shutdown();
+                try!(write!(out, "Server stopped\n"));
+                
+                // Stopping the star map ends the server process
+                self.star_map.send(StarMapCommand::Shutdown);
+                return Ok(CommandResult::Shutdown);
+            },
+            "quit" => { return Ok(CommandResult::Quit); },
+            _ => { try!(write!(out, "Unknown command '{}'. Type help for a list of commands.\n", command)); },
+        }
+        
+        Ok(CommandResult::Continue)
+    }
+    
+    fn list_clients(&self, out: &mut TcpStream) -> io::Result<()> {
+        let clients = self.slot.list_clients();
+        let sectors =
+            match self.get_sectors() {
+                Some(sectors) => sectors,
+                None => { return write!(out, "The star map isn't running\n"); },
+            };
+        
+        // Find out which sector each slot is and which ship each client flies
+        let mut slot_sectors: HashMap<ServerSlotId, SectorId> = HashMap::new();
+        let mut client_ships: HashMap<ClientId, ShipInfo> = HashMap::new();
+        for (sector_id, slot_id, ships) in sectors.into_iter() {
+            slot_sectors.insert(slot_id, sector_id);
+            for ship in ships.into_iter() {
+                if let Some(client_id) = ship.client_id {
+                    client_ships.insert(client_id, ship);
+                }
+            }
+        }
+        
+        try!(write!(out, "{} clients connected\n", clients.len()));
+        for client in clients.iter() {
+            try!(write!(out, "client {} in slot {}", client.client_id, client.slot_id));
+            if let Some(sector_id) = slot_sectors.get(&client.slot_id) {
+                try!(write!(out, " (sector {})", sector_id.0));
+            }
+            if let Some(latency) = client.latency {
+                try!(write!(out, ", ping {} ms", latency));
+            }
+            if let Some(ship) = client_ships.get(&client.client_id) {
+                try!(write!(out, ", ship {} '{}' level {} hp {}", ship.id, ship.name, ship.level, ship.hp));
+            }
+            try!(write!(out, "\n"));
+        }
+        
+        Ok(())
+    }
+    
+    fn list_sectors(&self, out: &mut TcpStream) -> io::Result<()> {
+        let sectors =
+            match self.get_sectors() {
+                Some(sectors) => sectors,
+                None => { return write!(out, "The star map isn't running\n"); },
+            };
+        
+        for (sector_id, slot_id, ships) in sectors.into_iter() {
+            try!(write!(out, "sector {} in slot {}, {} ships\n", sector_id.0, slot_id, ships.len()));
+            for ship in ships.iter() {
+                let pilot =
+                    match ship.client_id {
+                        Some(client_id) => format!("client {}", client_id),
+                        None => "AI".to_string(),
+                    };
+                try!(write!(out, "  ship {} '{}' level {} hp {} ({})\n", ship.id, ship.name, ship.level, ship.hp, pilot));
+            }
+        }
+        
+        Ok(())
+    }
+    
+    // Asks the star map for its sectors and each sector for its ships. None if the star map is gone.
+    fn get_sectors(&self) -> Option<Vec<(SectorId, ServerSlotId, Vec<ShipInfo>)>> {
+        let (sectors_sender, sectors_receiver) = channel();
+        self.star_map.send(StarMapCommand::ListSectors(sectors_sender));
+        let sectors =
+            match sectors_receiver.recv() {
+                Ok(sectors) => sectors,
+                Err(_) => { return None; },
+            };
+        
+        Some(sectors.into_iter()
+            .map(|(sector_id, slot_id)| {
+                let (ships_sender, ships_receiver) = channel();
+                self.star_map.send(StarMapCommand::Sector(sector_id, SectorCommand::ListShips(ships_sender)));
+                (sector_id, slot_id, ships_receiver.recv().unwrap_or(vec!()))
+            })
+            .collect())
+    }
+    
+    fn broadcast(&self, message: String) {
+        println!("Admin broadcast: {}", message);
+        
+        let mut packet = OutPacket::new();
+        packet.write(&ClientPacketId::ServerMessage).ok().expect("Failed to write server message packet ID");
+        packet.write(&message).ok().expect("Failed to write server message");
+        self.slot.broadcast_all(packet);
+    }
+    
+    // Gets everyone's account back to the login server and disconnects everyone
+    fn shutdown(&self) {
+        self.broadcast("The server is shutting down".to_string());
+        
+        // No new players
+        let (done_sender, done_receiver) = channel();
+        self.login.send(LoginCommand::CloseLogins(done_sender));
+        done_receiver.recv();
+        
+        // Every account back to the login server
+        let (done_sender, done_receiver) = channel();
+        self.star_map.send(StarMapCommand::LogOutAll(done_sender));
+        done_receiver.recv();
+        
+        // Make sure the login server has taken them all back
+        let (done_sender, done_receiver) = channel();
+        self.login.send(LoginCommand::Sync(done_sender));
+        done_receiver.recv();
+        
+        // Kick anyone left and keep new players out
+        self.slot.shutdown_server();
+    }
+}
+
+// Shuts the admin's connection down if they don't send anything in time. Each message is the
+// timeout until the next one, and the connection starts out with the login timeout. Stops once
+// the admin's session is over.
+fn watch_idle(stream: TcpStream, activity_receiver: Receiver<i64>) {
+    let mut stream = stream;
+    let mut timer = Timer::new().ok().expect("Failed to create admin idle timer");
+    let mut timeout = LOGIN_TIMEOUT;
+    loop {
+        let timeout_receiver = timer.oneshot(Duration::milliseconds(timeout));
+        select! {
+            _ = timeout_receiver.recv() => {
+                println!("Admin connection timed out");
+                stream.shutdown(Shutdown::Both);
+                return;
+            },
+            activity = activity_receiver.recv() => {
+                match activity {
+                    Ok(next_timeout) => { timeout = next_timeout; },
+                    Err(_) => { return; }, // Admin is gone
+                }
+            }
+        }
+    }
+}
+
+fn parse_sector(arg: Option<&&str>) -> Option<SectorId> {
+    arg.and_then(|s| s.parse().ok()).map(|id| SectorId(id))
+}
@@ -7,6 +7,9 @@ use ship::{Ship, ShipRef};
 use module;
 use module::{IModule, EngineModule, ProjectileWeaponModule, ShieldModule};
 
+// Names of the AI ships every sector starts with. Players can't take these names.
+pub static AI_SHIP_NAMES: [&'static str; 4] = ["n00bslayer808", "thing1", "thing2", "daisy_girl"];
+
 pub fn run_ai(ship: &mut Ship, enemy_ships: &Vec<ShipRef>) {
     // Random number generater
     let mut rng = rand::thread_rng();
@@ -1,11 +1,15 @@
 use std::rc::Rc;
 use std::cell::RefCell;
+use std::cmp;
 use std::collections::HashMap;
 
+use environment::EnvironmentEvent;
 use module::{ModulePlans, NetworkTarget};
 use net::{ClientId, InPacket, OutPacket};
+use sector_data::SectorEnvironment;
 use ship::{ShipId, ShipNetworked, ShipRef};
 use sim::SimEvents;
+use sim_events::{DamageEvent, FireEvent};
 
 #[cfg(feature = "client")]
 use sim::SimEffects;
@@ -19,6 +23,10 @@ pub struct BattleContext {
     pub ships: HashMap<ShipId, ShipRef>,
     pub ships_client_id: HashMap<ClientId, ShipRef>,
     pub ships_list: Vec<ShipRef>,
+    
+    // What space is like where the battle is, and what it's doing to the ships this turn
+    pub environment: SectorEnvironment,
+    pub environment_events: Vec<EnvironmentEvent>,
 }
 
 impl BattleContext {
@@ -40,6 +48,8 @@ impl BattleContext {
             ships: ships_map,
             ships_client_id: ships_client_id_map,
             ships_list: ships,
+            environment: SectorEnvironment::Normal,
+            environment_events: vec!(),
         }
     }
     
@@ -101,6 +111,14 @@ impl BattleContext {
         }
     }
     
+    /// Hands a client's ship over to a different client ID, like when a client reconnects.
+    pub fn change_client_id(&mut self, old_client_id: ClientId, new_client_id: ClientId) {
+        if let Some(ship) = self.ships_client_id.remove(&old_client_id) {
+            ship.borrow_mut().client_id = Some(new_client_id);
+            self.ships_client_id.insert(new_client_id, ship);
+        }
+    }
+    
     pub fn remove_ship(&mut self, ship_id: ShipId) {
         self.on_ship_removed(ship_id);
     
@@ -130,6 +148,36 @@ impl BattleContext {
     }
     
     pub fn before_simulation(&mut self, events: &mut SimEvents) {
+        for ship in self.ships_list.iter() {
+            ship.borrow_mut().damage_log.clear();
+        }
+        
+        // Knock out modules before they get to do anything, and line up the environment's hits
+        for event in self.environment_events.iter() {
+            match *event {
+                EnvironmentEvent::Hit(ship_id, module_index, tick, damage) => {
+                    if let Some(ship) = self.ships.get(&ship_id) {
+                        if let Some(module) = ship.borrow().modules.get(module_index as usize) {
+                            events.add(tick, box DamageEvent::new(None, ship.clone(), module.clone(), damage));
+                        }
+                    }
+                },
+                EnvironmentEvent::Burn(ship_id, module_index, tick, damage) => {
+                    if let Some(ship) = self.ships.get(&ship_id) {
+                        if let Some(module) = ship.borrow().modules.get(module_index as usize) {
+                            events.add(tick, box FireEvent::new(ship.clone(), module.clone(), damage));
+                        }
+                    }
+                },
+                EnvironmentEvent::Depower(ship_id, module_index) => {
+                    if let Some(ship) = self.ships.get(&ship_id) {
+                        ship.borrow_mut().depower_module(module_index as usize);
+                    }
+                },
+                EnvironmentEvent::HoldShields => {},
+            }
+        }
+        
         for ship in self.ships_list.iter() {
             ship.borrow().before_simulation(events, ship);
         }
@@ -150,8 +198,21 @@ impl BattleContext {
     }
     
     pub fn after_simulation(&self) {
+        let hold_shields = self.environment_events.iter().any(|event| {
+            match *event {
+                EnvironmentEvent::HoldShields => true,
+                _ => false,
+            }
+        });
+        
         for ship in self.ships_list.iter() {
-            ship.borrow_mut().after_simulation();
+            let mut ship = ship.borrow_mut();
+            let shields = ship.state.shields;
+            ship.after_simulation();
+            
+            if hold_shields {
+                ship.state.shields = cmp::min(ship.state.shields, shields);
+            }
         }
     }
     
@@ -173,9 +234,11 @@ impl BattleContext {
             packet.write(&ship.borrow().id);
             ship.borrow().write_results(packet);
         }
+        
+        packet.write(&self.environment_events).ok().expect("Failed to write environment events");
     }
     
-    pub fn read_results(&self, packet: &mut InPacket) {
+    pub fn read_results(&mut self, packet: &mut InPacket) {
         let num_ships: u32 = packet.read().unwrap();
         for _ in 0 .. num_ships {
             let ship_id = packet.read().unwrap();
@@ -183,6 +246,8 @@ impl BattleContext {
             
             ship.borrow_mut().read_results(self, packet);
         }
+        
+        self.environment_events = packet.read().ok().expect("Failed to read environment events");
     }
 }
 
@@ -192,8 +257,18 @@ pub enum ServerPacketId {
     Plan, // Player's plans
 }
 
-// Packets sent from server to client
-#[derive(Debug, PartialEq, RustcEncodable, RustcDecodable)]
+// Packets sent from server to client. Every packet from the server starts with one of these.
+#[derive(Clone, Copy, Debug, PartialEq, RustcEncodable, RustcDecodable)]
 pub enum ClientPacketId {
-    SimResults, // Calculated simulation results from server
+    StarMap,       // List of sectors on the star map
+    JoinSector,    // Player's ship, whether to start at simulation, and the sector's ships
+    NewShips,      // Ships added to and removed from the sector
+    SimResults,    // Calculated simulation results from server
+    SessionToken,  // Token for resuming the session if the connection drops
+    ResumeFailed,  // Session couldn't be resumed, so the client has to log in again
+    ServerMessage, // Message from the server's admin to show the player
+    LoginResult,   // Whether a login worked, with a summary of the account if it did
+    Progress,      // The player's account progress, after joining a sector and whenever it changes
+    JumpFailed,    // The server wouldn't let the player's ship jump, and why
+    SectorStatus,  // Who's in each sector, sent every so often
 }
\ No newline at end of file
@@ -0,0 +1,81 @@
+#![crate_name = "reforge_bot"]
+#![crate_type = "bin"]
+#![feature(box_syntax)]
+#![feature(rand)]
+#![feature(core)]
+#![feature(os)]
+#![feature(io)]
+#![feature(fs)]
+#![feature(old_io)]
+#![feature(alloc)]
+#![feature(thread_sleep)]
+#![feature(collections)]
+#![feature(std_misc)]
+
+extern crate bincode;
+extern crate crypto;
+extern crate flate2;
+extern crate time;
+extern crate rustc_serialize;
+
+use std::os;
+use std::rand;
+
+use bot_client::BotClient;
+use net::Client;
+use net_sim::NetworkConditions;
+
+mod ai;
+mod battle_state;
+mod bot_client;
+mod environment;
+mod login;
+mod module;
+mod net;
+mod net_sim;
+mod sector_data;
+mod ship;
+mod sim;
+mod sim_events;
+mod vec;
+
+fn main() {
+    let args = os::args();
+    
+    // Usage: reforge_bot [host] [username] [password] [--jump-chance <chance>] [--netsim <settings>]
+    let positional: Vec<String> =
+        args.iter().skip(1)
+            .take_while(|arg| !arg.as_slice().starts_with("--"))
+            .map(|arg| arg.clone())
+            .collect();
+    
+    let host = positional.get(0).map(|s| s.clone()).unwrap_or("localhost:30000".to_string());
+    let username = positional.get(1).map(|s| s.clone()).unwrap_or(format!("bot_{}", rand::random::<u16>() % 10000));
+    let password = positional.get(2).map(|s| s.clone()).unwrap_or("bot_password".to_string());
+    
+    let client =
+        match NetworkConditions::from_args(args.as_slice()) {
+            Some(conditions) => {
+                println!("Simulating network conditions: {:?}", conditions);
+                Client::new_simulated(host.as_slice(), conditions)
+            },
+            None => Client::new(host.as_slice()),
+        };
+    let client =
+        match client {
+            Ok(client) => client,
+            Err(e) => {
+                println!("Bot {}: {}", username, e);
+                return;
+            },
+        };
+    
+    let mut bot = BotClient::new(client, username);
+    
+    if let Some(i) = args.iter().position(|arg| arg.as_slice() == "--jump-chance") {
+        let jump_chance = args.get(i + 1).and_then(|s| s.parse().ok()).expect("Missing chance for --jump-chance");
+        bot.set_jump_chance(jump_chance);
+    }
+    
+    bot.run(password);
+}
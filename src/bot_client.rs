@@ -0,0 +1,415 @@
+use std::ops::DerefMut;
+use std::rand;
+use std::rand::Rng;
+use std::sync::mpsc::Sender;
+use std::thread;
+use std::time::Duration;
+use time;
+
+use ai::run_ai;
+use battle_state::{BattleContext, ClientPacketId, ServerPacketId};
+use login::{LoginError, LoginPacket, LoginResponse, SessionToken};
+use net::{Client, InPacket, OutPacket};
+use sector_data::{JumpError, SectorData, SectorEnvironment, SectorId};
+use ship::{ShipId, ShipNetworked, ShipRef};
+use sim::SimEvents;
+
+// How many times to try reconnecting after the connection drops, and how long to wait between tries in milliseconds
+static RECONNECT_ATTEMPTS: u32 = 5;
+static RECONNECT_DELAY: i64 = 2000;
+
+// Things a bot reports while it plays, for load testing
+pub enum BotEvent {
+    LoggedIn(i64),    // Time from sending the login to joining a sector, in milliseconds
+    TurnResults(i64), // Time from sending plans to getting the turn's results, in milliseconds
+    Latency(u32),     // Round trip time to the server, in milliseconds
+    Dropped,          // Plans were sent but the turn's results never came
+    Disconnected,     // Lost the connection to the server
+    Stopped,          // The bot gave up
+}
+
+// Plays the game without a window. Does everything the real client does, except its ship is
+// flown by the AI.
+pub struct BotClient {
+    client: Client,
+    username: String,
+    password: String,
+    
+    sectors: Vec<SectorData>,
+    session_token: Option<SessionToken>,
+    
+    // The battle in the bot's current sector and the bot's ship in it
+    context: BattleContext,
+    ship: Option<ShipRef>,
+    
+    // Sector the bot is in, and the sector it asked to jump to if it's waiting on a jump
+    current_sector: Option<SectorId>,
+    pending_jump: Option<SectorId>,
+    
+    // Chance of jumping to a random sector each turn
+    jump_chance: f64,
+    
+    // Set when simulation results arrive. Planning starts once the ships that came out of the
+    // simulation arrive too.
+    results_received: bool,
+    
+    // If set, the bot heads for the sector at this index in the star map before doing anything else
+    spread_index: Option<usize>,
+    
+    // Where to report what happens, and when the login and the last plans were sent
+    events: Option<Sender<BotEvent>>,
+    login_sent_at: Option<time::Timespec>,
+    plans_sent_at: Option<time::Timespec>,
+}
+
+impl BotClient {
+    pub fn new(client: Client, username: String) -> BotClient {
+        BotClient {
+            client: client,
+            username: username,
+            password: String::new(),
+            sectors: vec!(),
+            session_token: None,
+            context: BattleContext::new(vec!()),
+            ship: None,
+            current_sector: None,
+            pending_jump: None,
+            jump_chance: 0.0,
+            results_received: false,
+            spread_index: None,
+            events: None,
+            login_sent_at: None,
+            plans_sent_at: None,
+        }
+    }
+    
+    /// Makes the bot jump to a random sector with the given chance each turn. By default bots
+    /// stay in the first sector they join.
+    pub fn set_jump_chance(&mut self, jump_chance: f64) {
+        self.jump_chance = jump_chance;
+    }
+    
+    /// Makes the bot head for the sector at the given index in the star map, one jump lane at a
+    /// time. Used to spread a lot of bots across the sectors.
+    pub fn set_spread_index(&mut self, index: usize) {
+        self.spread_index = Some(index);
+    }
+    
+    /// Makes the bot report how it's doing through the given channel.
+    pub fn set_event_sender(&mut self, events: Sender<BotEvent>) {
+        self.events = Some(events);
+    }
+    
+    /// Logs in and plays until the connection is lost for good or the session ends. The account
+    /// gets registered if it doesn't exist yet.
+    pub fn run(&mut self, password: String) {
+        self.password = password;
+        
+        let login = LoginPacket::Login(self.username.clone(), self.password.clone());
+        self.send_login(login);
+        
+        loop {
+            let packet =
+                match self.client.receive() {
+                    Ok(packet) => packet,
+                    Err(e) => {
+                        println!("Bot {} lost connection to server: {}", self.username, e);
+                        self.report(BotEvent::Disconnected);
+                        if self.plans_sent_at.take().is_some() {
+                            self.report(BotEvent::Dropped);
+                        }
+                        
+                        if !self.resume_session() {
+                            break;
+                        }
+                        continue;
+                    },
+                };
+            
+            if !self.handle_packet(packet) {
+                break;
+            }
+        }
+        
+        self.report(BotEvent::Stopped);
+    }
+    
+    fn send_login(&mut self, login: LoginPacket) {
+        let mut packet = OutPacket::new();
+        packet.write(&login).ok().expect("Failed to write login packet");
+        self.client.send(&packet);
+        self.login_sent_at = Some(time::now().to_timespec());
+    }
+    
+    fn report(&self, event: BotEvent) {
+        if let Some(ref events) = self.events {
+            events.send(event);
+        }
+    }
+    
+    // Milliseconds since the given time, if there is one
+    fn elapsed_since(since: Option<time::Timespec>) -> Option<i64> {
+        since.map(|since| (time::now().to_timespec() - since).num_milliseconds())
+    }
+    
+    // Dispatches a packet from the server by its ID. Returns false if the bot has to stop.
+    fn handle_packet(&mut self, mut packet: InPacket) -> bool {
+        let id: ClientPacketId =
+            match packet.read() {
+                Ok(id) => id,
+                Err(e) => {
+                    println!("Bot {} received invalid packet from server: {}", self.username, e);
+                    return true;
+                },
+            };
+        
+        match id {
+            ClientPacketId::StarMap => {
+                self.sectors = packet.read().ok().expect("Failed to read star map");
+            },
+            ClientPacketId::SessionToken => {
+                self.session_token = Some(packet.read().ok().expect("Failed to read session token"));
+            },
+            ClientPacketId::ServerMessage => {
+                let message: String = packet.read().ok().expect("Failed to read server message");
+                println!("Bot {} got message from server: {}", self.username, message);
+            },
+            ClientPacketId::Progress => {}, // Bots don't care how they're doing
+            ClientPacketId::SectorStatus => {}, // Bots pick where to jump at random
+            ClientPacketId::JumpFailed => {
+                let error: JumpError = packet.read().ok().expect("Failed to read jump error");
+                println!("Bot {} couldn't jump: {}", self.username, error);
+                self.pending_jump = None;
+            },
+            ClientPacketId::ResumeFailed => {
+                println!("Bot {} failed to resume its session", self.username);
+                return false;
+            },
+            ClientPacketId::LoginResult => {
+                match packet.read().ok().expect("Failed to read login response") {
+                    LoginResponse::Success(summary) => {
+                        self.current_sector = Some(summary.sector);
+                    },
+                    LoginResponse::Failure(LoginError::NoSuchAccount) => {
+                        println!("Bot {} has no account yet, registering", self.username);
+                        let register = LoginPacket::Register(self.username.clone(), self.password.clone());
+                        self.send_login(register);
+                    },
+                    LoginResponse::Failure(e) => {
+                        println!("Bot {} failed to log in: {}", self.username, e);
+                        return false;
+                    },
+                }
+            },
+            ClientPacketId::JoinSector => {
+                let my_ship: ShipNetworked = packet.read().ok().expect("Failed to read my Ship");
+                let _start_at_sim: bool = packet.read().ok().expect("Failed to read start_at_sim from server");
+                let ships: Vec<ShipNetworked> = packet.read().ok().expect("Failed to read ships from server");
+                let environment: SectorEnvironment = packet.read().ok().expect("Failed to read sector environment");
+                
+                // Start over in the new sector
+                self.context = BattleContext::new(vec!());
+                self.context.environment = environment;
+                self.context.add_networked_ships(ships);
+                self.ship = Some(self.context.add_networked_ship(my_ship));
+                
+                // Plans sent before a jump don't get results from the old sector
+                self.plans_sent_at = None;
+                
+                if let Some(sector_id) = self.pending_jump.take() {
+                    self.current_sector = Some(sector_id);
+                }
+                
+                if let Some(login_time) = BotClient::elapsed_since(self.login_sent_at.take()) {
+                    self.report(BotEvent::LoggedIn(login_time));
+                }
+                
+                println!("Bot {} joined a sector", self.username);
+            },
+            ClientPacketId::NewShips => {
+                self.handle_new_ships_packet(&mut packet);
+                
+                // New ships come after every simulation, which is when the next turn's planning starts
+                if self.results_received && self.ship.is_some() {
+                    self.plan();
+                }
+                self.results_received = false;
+            },
+            ClientPacketId::SimResults => {
+                self.context.read_results(&mut packet);
+                self.simulate();
+                self.results_received = true;
+                
+                if let Some(results_time) = BotClient::elapsed_since(self.plans_sent_at.take()) {
+                    self.report(BotEvent::TurnResults(results_time));
+                }
+                if let Some(latency) = self.client.get_latency() {
+                    self.report(BotEvent::Latency(latency));
+                }
+            },
+        }
+        
+        true
+    }
+    
+    // Runs the simulation the results are for, so the bot's view of the battle stays up to date
+    fn simulate(&mut self) {
+        let mut sim_events = SimEvents::new();
+        
+        self.context.before_simulation(&mut sim_events);
+        for tick in 0..100 {
+            sim_events.apply_tick(tick);
+        }
+        self.context.after_simulation();
+    }
+    
+    // Lets the AI plan the bot's next turn and sends the plans to the server
+    fn plan(&mut self) {
+        let ship = self.ship.as_ref().expect("Bot must have a ship to plan").clone();
+        let ship_id = ship.borrow().id;
+        
+        let enemies = self.context.ships_list.iter().filter(|s| s.borrow().id != ship_id).map(|s| s.clone()).collect();
+        run_ai(ship.borrow_mut().deref_mut(), &enemies);
+        
+        // Maybe go somewhere else
+        let target_sector = self.pick_jump();
+        ship.borrow_mut().target_sector = target_sector;
+        self.pending_jump = target_sector;
+        
+        // Same as ClientBattleState::build_plans_packet
+        let mut packet = OutPacket::new();
+        packet.write(&ServerPacketId::Plan).ok().expect("Failed to write plan packet ID");
+        packet.write(&ship.borrow().target_sector).ok().expect("Failed to write bot's target sector");
+        packet.write(&ship.borrow().get_module_plans()).ok().expect("Failed to write bot's plans");
+        self.client.send(&packet);
+        
+        // The last plans never got results if new ones are sent first
+        if self.plans_sent_at.is_some() {
+            self.report(BotEvent::Dropped);
+        }
+        self.plans_sent_at = Some(time::now().to_timespec());
+    }
+    
+    fn pick_jump(&mut self) -> Option<SectorId> {
+        let mut rng = rand::thread_rng();
+        
+        let current_sector =
+            match self.current_sector {
+                Some(current_sector) => current_sector,
+                None => { return None; },
+            };
+        
+        // Head for the bot's assigned sector first
+        if let Some(index) = self.spread_index {
+            if !self.sectors.is_empty() {
+                let assigned_sector = self.sectors[index % self.sectors.len()].id;
+                match self.next_jump_towards(current_sector, assigned_sector) {
+                    Some(next_sector) => { return Some(next_sector); },
+                    None => { self.spread_index = None; }, // Made it, or there's no way there
+                }
+            }
+        }
+        
+        if self.jump_chance <= 0.0 || rng.gen::<f64>() >= self.jump_chance {
+            return None;
+        }
+        
+        let destinations: Vec<SectorId> =
+            match self.sectors.iter().find(|s| s.id == current_sector) {
+                Some(sector) => sector.neighbours.clone(),
+                None => vec!(),
+            };
+        if destinations.is_empty() {
+            return None;
+        }
+        
+        Some(destinations[rng.gen_range(0, destinations.len())])
+    }
+    
+    // First jump on the shortest path between two sectors, if they're different and connected
+    fn next_jump_towards(&self, from: SectorId, to: SectorId) -> Option<SectorId> {
+        if from == to {
+            return None;
+        }
+        
+        // Search outwards from the destination, so the sector the search reaches `from` through is
+        // the next jump
+        let mut visited = vec![to];
+        let mut to_visit = vec![to];
+        while !to_visit.is_empty() {
+            let mut next = vec!();
+            for &sector_id in to_visit.iter() {
+                let neighbours =
+                    match self.sectors.iter().find(|s| s.id == sector_id) {
+                        Some(sector) => sector.neighbours.clone(),
+                        None => vec!(),
+                    };
+                for neighbour in neighbours.into_iter() {
+                    if neighbour == from {
+                        return Some(sector_id);
+                    }
+                    if !visited.contains(&neighbour) {
+                        visited.push(neighbour);
+                        next.push(neighbour);
+                    }
+                }
+            }
+            to_visit = next;
+        }
+        
+        None
+    }
+    
+    fn handle_new_ships_packet(&mut self, packet: &mut InPacket) {
+        let ships_to_add: Vec<ShipNetworked> = packet.read().ok().expect("Failed to read ships to add from packet");
+        let ships_to_remove: Vec<ShipId> = packet.read().ok().expect("Failed to read ships to remove from packet");
+        
+        let player_ship_id = self.ship.as_ref().map(|s| s.borrow().id);
+        
+        for ship in ships_to_remove.into_iter() {
+            self.context.remove_ship(ship);
+            
+            if Some(ship) == player_ship_id {
+                // Our ship left, so there's nothing to plan for until the next sector is joined
+                self.ship = None;
+            }
+        }
+        
+        for ship in ships_to_add.into_iter() {
+            if Some(ship.id) == player_ship_id {
+                // Our ship died and got replaced
+                self.ship = Some(self.context.add_networked_ship(ship));
+            } else {
+                self.context.add_networked_ship(ship);
+            }
+        }
+    }
+    
+    // Reconnects and asks to be put back into the session. Returns false if that's not possible.
+    fn resume_session(&mut self) -> bool {
+        let session_token =
+            match self.session_token {
+                Some(session_token) => session_token,
+                None => { return false; },
+            };
+        
+        for _ in 0..RECONNECT_ATTEMPTS {
+            match self.client.reconnect() {
+                Ok(client) => {
+                    self.client = client;
+                    
+                    let mut packet = OutPacket::new();
+                    packet.write(&LoginPacket::Resume(session_token)).ok().expect("Failed to write resume packet");
+                    self.client.send(&packet);
+                    return true;
+                },
+                Err(e) => {
+                    println!("Bot {} failed to reconnect: {}", self.username, e);
+                    thread::sleep(Duration::milliseconds(RECONNECT_DELAY));
+                },
+            }
+        }
+        
+        false
+    }
+}
\ No newline at end of file
@@ -5,6 +5,7 @@
 #![feature(core)]
 #![feature(os)]
 #![feature(io)]
+#![feature(fs)]
 #![feature(old_io)]
 #![feature(alloc)]
 #![feature(thread_sleep)]
@@ -12,6 +13,8 @@
 #![feature(std_misc)]
 
 extern crate bincode;
+extern crate crypto;
+extern crate flate2;
 extern crate time;
 extern crate rustc_serialize;
 
@@ -44,16 +47,18 @@ use asset_store::AssetStore;
 use battle_state::BattleContext;
 use battle_type::BattleType;
 use client_battle_state::ClientBattleState;
-use client_state::run_client_state_manager;
-use login::LoginPacket;
+use client_state::{LoginFailure, run_client_state_manager, wait_for_login};
+use login::AccountManager;
 use login_screen::LoginScreen;
 use main_menu::{MainMenu, MainMenuSelection};
 use net::{Client, OutPacket};
+use net_sim::NetworkConditions;
 use star_map_gui::StarMapGui;
 use tutorial_state::TutorialState;
 
 // Server stuff
-use net::Server;
+use galaxy::Galaxy;
+use net::{LocalConnector, Server};
 use star_map_server::StarMapServer;
 
 #[macro_use]
@@ -65,12 +70,15 @@ mod battle_state;
 mod battle_type;
 mod client_battle_state;
 mod client_state;
+mod environment;
 mod gui;
 mod login;
 mod login_screen;
 mod main_menu;
 mod module;
 mod net;
+mod net_sim;
+mod replay;
 mod sector_data;
 mod sector_state;
 mod ship;
@@ -84,8 +92,59 @@ mod tutorial_state;
 mod vec;
 
 // server stuff
+mod galaxy;
 mod star_map_server;
 
+// Starts a server in this process that can only be connected to through the returned connector
+fn start_local_server(conditions: Option<NetworkConditions>, capture_path: Option<&String>, heartbeat_timeout: Option<i64>) -> LocalConnector {
+    let mut server = Server::new();
+    if let Some(conditions) = conditions {
+        server.set_network_conditions(conditions);
+    }
+    if let Some(heartbeat_timeout) = heartbeat_timeout {
+        server.set_heartbeat_timeout(heartbeat_timeout);
+    }
+    if let Some(capture_path) = capture_path {
+        server.set_capture_file(&Path::new(capture_path)).ok().expect("Failed to create capture file");
+    }
+    let connector = server.create_local_connector();
+    
+    let galaxy =
+        match Galaxy::load(&Path::new(galaxy::DEFAULT_GALAXY_PATH)) {
+            Ok(galaxy) => galaxy,
+            Err(e) => panic!("{}", e),
+        };
+    let mut account_manager = AccountManager::new();
+    account_manager.set_start_sector(galaxy.get_start_sector());
+    
+    let login_slot = server.create_slot();
+    let star_map_slot = server.create_slot();
+    let star_map_slot_id = star_map_slot.get_id();
+    let (star_map_account_sender, star_map_account_receiver) = channel();
+    let (resume_sender, resume_receiver) = channel();
+    let (logout_sender, logout_receiver) = channel();
+    let (login_command_sender, login_command_receiver) = channel();
+    let (star_map_command_sender, star_map_command_receiver) = channel();
+    
+    Builder::new().name("server_master".to_string()).spawn(move || {
+        server.run();
+    });
+    
+    Builder::new().name("login_server".to_string()).spawn(move || {
+        // Nothing sends commands to a local server, but the channel has to stay open
+        let _login_command_sender = login_command_sender;
+        login::run_login_server(account_manager, login_slot, star_map_slot_id, star_map_account_sender, resume_sender, logout_receiver, login_command_receiver);
+    });
+    
+    Builder::new().name("star_map_server".to_string()).spawn(move || {
+        let _star_map_command_sender = star_map_command_sender;
+        let mut star_map_server = StarMapServer::new(star_map_slot, logout_sender, None, galaxy);
+        star_map_server.run(star_map_account_receiver, resume_receiver, star_map_command_receiver);
+    });
+    
+    connector
+}
+
 #[cfg(feature = "client")]
 fn main () {
     let opengl = shader_version::OpenGL::_3_2;
@@ -133,7 +192,13 @@ fn main () {
     main_menu.run(&window, &mut gl, |window, gl, menu_bg, selection| {
         match selection {
             MainMenuSelection::Multiplayer => {
-                if let Some((username, password)) = LoginScreen::new().run(&window, gl, &mut glyph_cache, menu_bg) {
+                let mut login_screen = LoginScreen::new();
+                
+                // Failed logins leave the connection open, so retries reuse it. With --local, a
+                // connection that drops goes back to the same server instead of starting another.
+                let mut connection: Option<Client> = None;
+                let mut local_server: Option<LocalConnector> = None;
+                while let Some(login) = login_screen.run(&window, gl, &mut glyph_cache, menu_bg) {
                     // Check for IP address in args
                     /*
                     let mut ip_address =
@@ -152,34 +217,76 @@ fn main () {
                     //let ip_address = String::from_str("localhost:30000");
                     let ip_address = String::from_str("104.131.129.181:30000");
                     
-                    // Start a local server
-                    let mut server = Server::new();
-                    let login_slot = server.create_slot();
-                    let star_map_slot = server.create_slot();
-                    let star_map_slot_id = star_map_slot.get_id();
-                    let (star_map_account_sender, star_map_account_receiver) = channel();
+                    let args = os::args();
                     
-                    Builder::new().name("server_master".to_string()).spawn(move || {
-                        server.listen("localhost:30000");
-                    });
+                    // Simulate a bad connection if asked to with --netsim
+                    let conditions = NetworkConditions::from_args(args.as_slice());
                     
-                    Builder::new().name("login_server".to_string()).spawn(move || {
-                        login::run_login_server(login_slot, star_map_slot_id, star_map_account_sender);
-                    });
+                    // With --capture <capture file>, the local server records all the traffic
+                    let capture_path = args.iter().position(|arg| arg.as_slice() == "--capture").and_then(|i| args.get(i + 1));
                     
-                    Builder::new().name("star_map_server".to_string()).spawn(move || {
-                        let mut star_map_server = StarMapServer::new(star_map_slot);
-                        star_map_server.run(star_map_account_receiver);
-                    });
+                    // With --heartbeat-timeout <milliseconds>, give up on a server that goes quiet for that long
+                    let heartbeat_timeout: Option<i64> =
+                        args.iter().position(|arg| arg.as_slice() == "--heartbeat-timeout").map(|i| {
+                            args.get(i + 1).and_then(|s| s.parse().ok()).expect("Missing milliseconds for --heartbeat-timeout")
+                        });
                     
-                    // Connect to server
-                    let mut client = Client::new(ip_address.as_slice());
+                    // Connect to server. With --local, run the whole server in this process instead.
+                    // With --replay <capture file> <client ID>, watch what a captured client was sent.
+                    let mut client =
+                        if let Some(client) = connection.take() {
+                            client
+                        } else if let Some(i) = args.iter().position(|arg| arg.as_slice() == "--replay") {
+                            let path = args.get(i + 1).expect("Missing capture file for --replay");
+                            let client_id = args.get(i + 2).and_then(|s| s.parse().ok()).expect("Missing client ID for --replay");
+                            let records = net::read_capture(&Path::new(path)).ok().expect("Failed to read capture file");
+                            Client::new_local(&replay::replay_to_client(records, client_id))
+                        } else if args.iter().any(|arg| arg.as_slice() == "--local") {
+                            if local_server.is_none() {
+                                local_server = Some(start_local_server(conditions, capture_path, heartbeat_timeout));
+                            }
+                            Client::new_local(local_server.as_ref().unwrap())
+                        } else {
+                            let client =
+                                match conditions {
+                                    Some(conditions) => Client::new_simulated(ip_address.as_slice(), conditions),
+                                    None => Client::new(ip_address.as_slice()),
+                                };
+                            match client {
+                                Ok(client) => client,
+                                Err(e) => {
+                                    // Let the player see what went wrong and try again
+                                    login_screen.set_message(format!("{}", e));
+                                    continue;
+                                },
+                            }
+                        };
+                    if let Some(heartbeat_timeout) = heartbeat_timeout {
+                        client.set_timeout(heartbeat_timeout);
+                    }
 
                     let mut packet = OutPacket::new();
-                    packet.write(&LoginPacket{username: username, password: password});
+                    packet.write(&login);
                     client.send(&packet);
                     
-                    run_client_state_manager(&window, gl, &mut glyph_cache, &asset_store, client);
+                    let summary =
+                        match wait_for_login(&mut client) {
+                            Ok(summary) => summary,
+                            Err(LoginFailure::Rejected(message)) => {
+                                // Back to the login screen to try again on the same connection
+                                login_screen.set_message(message);
+                                connection = Some(client);
+                                continue;
+                            },
+                            Err(LoginFailure::ConnectionLost(message)) => {
+                                // Back to the login screen, and connect again next time
+                                login_screen.set_message(message);
+                                continue;
+                            },
+                        };
+                    
+                    run_client_state_manager(&window, gl, &mut glyph_cache, &asset_store, client, summary);
+                    break;
                 }
             },
             MainMenuSelection::Tutorial => {                
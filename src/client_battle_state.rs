@@ -1,5 +1,4 @@
 use std::cell::RefCell;
-use std::io;
 use std::ops::{Deref, DerefMut};
 use std::rc::Rc;
 use std::thread;
@@ -13,12 +12,20 @@ use sdl2_window::Sdl2Window;
 
 use asset_store::AssetStore;
 use battle_state::{BattleContext, ClientPacketId, ServerPacketId, TICKS_PER_SECOND};
+use login::AccountProgress;
 use net::{Client, InPacket, OutPacket};
-use sector_data::SectorData;
+use sector_data::{JumpError, SectorData, SectorStatus};
 use ship::{Ship, ShipId, ShipNetworked, ShipRef};
 use sim::{SimEvents, SimEffects};
 use space_gui::SpaceGui;
 
+// Why a battle stopped running
+pub enum BattleExit {
+    Left,             // Player closed the window or jumped away
+    Packet(InPacket), // Packet arrived that the client state manager needs to handle
+    ConnectionLost,   // Connection to the server dropped
+}
+
 pub struct ClientBattleState<'a> {
     client: &'a mut Client,
     
@@ -27,6 +34,11 @@ pub struct ClientBattleState<'a> {
     
     // The player's ship
     player_ship: ShipRef,
+    
+    // Packet that arrived during the battle but belongs to the client state manager
+    leftover_packet: Option<InPacket>,
+    
+    connection_lost: bool,
 }
 
 impl<'a> ClientBattleState<'a> {
@@ -36,30 +48,41 @@ impl<'a> ClientBattleState<'a> {
             client: client,
             context: context,
             player_ship: player_ship,
+            leftover_packet: None,
+            connection_lost: false,
         }
     }
     
-    pub fn run(&mut self, window: &Rc<RefCell<Sdl2Window>>, gl: &mut Gl, glyph_cache: &mut GlyphCache, asset_store: &AssetStore, sectors: Vec<SectorData>, start_at_sim: bool) {
+    /// Runs the battle until the player leaves the sector, a packet for the client state manager
+    /// arrives, or the connection drops.
+    pub fn run(&mut self, window: &Rc<RefCell<Sdl2Window>>, gl: &mut Gl, glyph_cache: &mut GlyphCache, asset_store: &AssetStore, sectors: Vec<SectorData>, pilot: &String, start_at_sim: bool) -> BattleExit {
         use window::ShouldClose;
         use quack::Get;
     
         let ref mut gui = SpaceGui::new(asset_store, &self.context, sectors, self.player_ship.borrow().id);
+        gui.set_pilot(pilot.clone());
     
         let ref mut sim_effects = SimEffects::new();
         
         // TODO display joining screen here
         
-        // Get first turn's results
-        self.receive_new_ships(gui);
-        while self.try_receive_simulation_results().is_err() { }
-        self.receive_new_ships(gui);
-        thread::sleep(Duration::milliseconds(1500));
+        if start_at_sim {
+            // The other players are watching results this ship's state already includes, so there's
+            // nothing to simulate, but there's still time to plan the next turn alongside them
+            self.run_simulation_phase(window, gl, glyph_cache, asset_store, gui, sim_effects, false);
+        } else {
+            // Get first turn's results
+            self.receive_until(gui, ClientPacketId::NewShips);
+            self.receive_until(gui, ClientPacketId::SimResults);
+            self.receive_until(gui, ClientPacketId::NewShips);
+            thread::sleep(Duration::milliseconds(1500));
+        }
     
-        loop {
+        while !self.is_interrupted() {
             ////////////////////////////////
             // Simulate
             
-            self.run_simulation_phase(window, gl, glyph_cache, asset_store, gui, sim_effects);
+            self.run_simulation_phase(window, gl, glyph_cache, asset_store, gui, sim_effects, true);
             
             // Check if it's time to exit
             let ShouldClose(should_close) = window.borrow().get();
@@ -70,15 +93,33 @@ impl<'a> ClientBattleState<'a> {
                 break;
             }
         }
+        
+        if self.connection_lost {
+            BattleExit::ConnectionLost
+        } else {
+            match self.leftover_packet.take() {
+                Some(packet) => BattleExit::Packet(packet),
+                None => BattleExit::Left,
+            }
+        }
+    }
+    
+    // True if the battle has to stop because of something from the server
+    fn is_interrupted(&self) -> bool {
+        self.leftover_packet.is_some() || self.connection_lost
     }
     
-    fn run_simulation_phase(&mut self, window: &Rc<RefCell<Sdl2Window>>, gl: &mut Gl, glyph_cache: &mut GlyphCache, asset_store: &AssetStore, gui: &mut SpaceGui, mut sim_effects: &mut SimEffects) {
+    // Shows the last results while the player plans the next turn. Without simulate, the ships just sit
+    // there, for when the results are already part of the ships' state.
+    fn run_simulation_phase(&mut self, window: &Rc<RefCell<Sdl2Window>>, gl: &mut Gl, glyph_cache: &mut GlyphCache, asset_store: &AssetStore, gui: &mut SpaceGui, mut sim_effects: &mut SimEffects, simulate: bool) {
         let mut sim_events = SimEvents::new();
             
         // Before simulation
         sim_effects.reset();
-        self.context.before_simulation(&mut sim_events);
-        self.context.add_simulation_effects(asset_store, &mut sim_effects);
+        if simulate {
+            self.context.before_simulation(&mut sim_events);
+            self.context.add_simulation_effects(asset_store, &mut sim_effects);
+        }
         
         // Simulation
         let start_time = time::now().to_timespec();
@@ -106,9 +147,7 @@ impl<'a> ClientBattleState<'a> {
             }
             
             // Break once we receive sim results
-            if plans_sent && !results_received && self.try_receive_new_ships(gui).is_ok() {
-                println!("Receiving results");
-                while self.try_receive_simulation_results().is_err() { }
+            if plans_sent && !results_received && self.try_receive_until(gui, ClientPacketId::SimResults) {
                 println!("Received results at {}", elapsed_seconds);
                 results_received = true;
             }
@@ -117,16 +156,24 @@ impl<'a> ClientBattleState<'a> {
                 break;
             }
             
+            // Leave the battle if the server moved us somewhere else or the connection dropped
+            if self.is_interrupted() {
+                break;
+            }
+            
             // Calculate current tick
             let tick = (elapsed_time.num_milliseconds() as u32)/(1000/TICKS_PER_SECOND);
             
             // Simulate any new ticks
-            for t in next_tick .. next_tick+tick-next_tick+1 {
-                sim_events.apply_tick(t);
+            if simulate {
+                for t in next_tick .. next_tick+tick-next_tick+1 {
+                    sim_events.apply_tick(t);
+                }
             }
             next_tick = tick+1;
         
             // Forward events to GUI
+            gui.set_latency(self.client.get_latency());
             gui.event(&e, &self.player_ship);
             
             // Render GUI
@@ -138,9 +185,11 @@ impl<'a> ClientBattleState<'a> {
         }
         
         // After simulation
-        self.context.after_simulation();
+        if simulate {
+            self.context.after_simulation();
+        }
         
-        self.receive_new_ships(gui);
+        self.receive_until(gui, ClientPacketId::NewShips);
     }
     
     fn build_plans_packet(&mut self) -> OutPacket {
@@ -156,32 +205,98 @@ impl<'a> ClientBattleState<'a> {
         packet
     }
     
-    fn try_receive_simulation_results(&mut self) -> io::Result<()> {
-        let mut packet = try!(self.client.try_receive());
-        match packet.read::<ClientPacketId>() {
-            Ok(ref id) if *id != ClientPacketId::SimResults => panic!("Expected SimResults, got something else"),
-            Err(e) => panic!("Failed to read simulation results packet ID: {}", e),
-            _ => {}, // All good!
-        };
-        
-        // Results packet has both plans and results
-        self.context.read_results(&mut packet);
-        
-        Ok(())
+    // Handles packets in the order they arrive until one of the given kind has been handled. Stops
+    // early if a packet for the client state manager arrives.
+    fn receive_until(&mut self, gui: &mut SpaceGui, kind: ClientPacketId) {
+        while !self.is_interrupted() {
+            let packet =
+                match self.client.receive() {
+                    Ok(packet) => packet,
+                    Err(e) => {
+                        println!("Lost connection to server: {}", e);
+                        self.connection_lost = true;
+                        break;
+                    },
+                };
+            if self.handle_packet(gui, packet) == Some(kind) {
+                break;
+            }
+        }
     }
     
-    fn try_receive_new_ships(&mut self, gui: &mut SpaceGui) -> io::Result<()> {
-        let mut packet = try!(self.client.try_receive());
+    // Same as receive_until, but only handles packets that already arrived. Returns true if a
+    // packet of the given kind was handled.
+    fn try_receive_until(&mut self, gui: &mut SpaceGui, kind: ClientPacketId) -> bool {
+        use std::io::ErrorKind;
         
-        self.handle_new_ships_packet(gui, &mut packet);
+        while !self.is_interrupted() {
+            let packet =
+                match self.client.try_receive() {
+                    Ok(packet) => packet,
+                    Err(ref e) if e.kind() == ErrorKind::TimedOut => { break; }, // Nothing yet
+                    Err(e) => {
+                        println!("Lost connection to server: {}", e);
+                        self.connection_lost = true;
+                        break;
+                    },
+                };
+            if self.handle_packet(gui, packet) == Some(kind) {
+                return true;
+            }
+        }
         
-        Ok(())
+        false
     }
     
-    fn receive_new_ships(&mut self, gui: &mut SpaceGui) {
-        let mut packet = self.client.receive();
+    // Dispatches a packet from the server by its ID. Returns the ID of the handled packet.
+    fn handle_packet(&mut self, gui: &mut SpaceGui, mut packet: InPacket) -> Option<ClientPacketId> {
+        let id: ClientPacketId =
+            match packet.read() {
+                Ok(id) => id,
+                Err(e) => {
+                    println!("Received invalid packet from server: {}", e);
+                    return None;
+                },
+            };
+        
+        match id {
+            ClientPacketId::StarMap => {
+                let sectors: Vec<SectorData> = packet.read().ok().expect("Failed to read star map");
+                gui.set_sectors(sectors);
+            },
+            ClientPacketId::JoinSector | ClientPacketId::SessionToken | ClientPacketId::ResumeFailed |
+            ClientPacketId::LoginResult => {
+                // Belongs to the client state manager. Rewind it so the manager can read the ID.
+                packet.rewind();
+                self.leftover_packet = Some(packet);
+            },
+            ClientPacketId::NewShips => {
+                self.handle_new_ships_packet(gui, &mut packet);
+            },
+            ClientPacketId::SimResults => {
+                // Results packet has both plans and results
+                self.context.read_results(&mut packet);
+            },
+            ClientPacketId::ServerMessage => {
+                let message: String = packet.read().ok().expect("Failed to read server message");
+                gui.show_server_message(message);
+            },
+            ClientPacketId::Progress => {
+                let progress: AccountProgress = packet.read().ok().expect("Failed to read account progress");
+                gui.set_progress(progress);
+            },
+            ClientPacketId::JumpFailed => {
+                let error: JumpError = packet.read().ok().expect("Failed to read jump error");
+                self.player_ship.borrow_mut().target_sector = None;
+                gui.show_server_message(format!("Couldn't jump: {}", error));
+            },
+            ClientPacketId::SectorStatus => {
+                let statuses: Vec<SectorStatus> = packet.read().ok().expect("Failed to read sector statuses");
+                gui.set_sector_statuses(statuses);
+            },
+        }
         
-        self.handle_new_ships_packet(gui, &mut packet);
+        Some(id)
     }
     
     fn handle_new_ships_packet(&mut self, gui: &mut SpaceGui, packet: &mut InPacket) {
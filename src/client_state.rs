@@ -1,15 +1,18 @@
 use std::rc::Rc;
 use std::cell::RefCell;
+use std::thread;
+use std::time::Duration;
 
 use opengl_graphics::Gl;
 use opengl_graphics::glyph_cache::GlyphCache;
 use sdl2_window::Sdl2Window;
 
 use asset_store::AssetStore;
-use battle_state::BattleContext;
-use client_battle_state::ClientBattleState;
-use net::Client;
-use sector_data::SectorData;
+use battle_state::{BattleContext, ClientPacketId};
+use client_battle_state::{BattleExit, ClientBattleState};
+use login::{AccountSummary, LoginPacket, LoginResponse, SessionToken};
+use net::{Client, InPacket, OutPacket};
+use sector_data::{SectorData, SectorEnvironment};
 use ship::{ShipNetworked};
 
 pub enum ClientState {
@@ -17,30 +20,180 @@ pub enum ClientState {
     Respawn,
 }
 
-pub fn run_client_state_manager(window: &Rc<RefCell<Sdl2Window>>, gl: &mut Gl, glyph_cache: &mut GlyphCache, asset_store: &AssetStore, mut client: Client) {
-    // Receive the star map
-    let mut packet = client.receive();
-    let sectors: Vec<SectorData> = packet.read().ok().expect("Failed to read star map");
+// How many times to try reconnecting after the connection drops, and how long to wait between tries in milliseconds
+static RECONNECT_ATTEMPTS: u32 = 5;
+static RECONNECT_DELAY: i64 = 2000;
+
+pub fn run_client_state_manager(window: &Rc<RefCell<Sdl2Window>>, gl: &mut Gl, glyph_cache: &mut GlyphCache, asset_store: &AssetStore,
+                                mut client: Client, summary: AccountSummary) {
+    let mut sectors: Vec<SectorData> = vec!();
+    
+    // Who the player logged in as, for the star map
+    let pilot =
+        format!("{}{} flying {} (level {})",
+                if summary.new_account { "new pilot " } else { "" }, summary.username, summary.ship_name, summary.ship_level);
+    
+    // Packet left over from the last battle that still needs handling
+    let mut next_packet: Option<InPacket> = None;
+    
+    // Lets us get back into the session if the connection drops
+    let mut session_token: Option<SessionToken> = None;
+    
+    // Whether the connection needs to be reestablished before going on
+    let mut connection_lost = false;
     
     loop {
-        // Receive the ships from the server
-        let mut packet = client.receive();
-        let my_ship: ShipNetworked = packet.read().ok().expect("Failed to read my Ship");
-        let start_at_sim = packet.read().ok().expect("Failed to read start_at_sim from server");
-        let ships: Vec<ShipNetworked> = match packet.read() {
-            Ok(ships) => ships,
-            Err(e) => panic!("Unable to receive ships froms server: {}", e),
-        };
+        if connection_lost {
+            let session_token =
+                match session_token {
+                    Some(session_token) => session_token,
+                    None => { return; }, // Not logged in yet, so there's no session to get back into
+                };
+            
+            client =
+                match resume_session(&client, session_token) {
+                    Some(client) => client,
+                    None => {
+                        println!("Failed to reconnect to server");
+                        return;
+                    },
+                };
+            connection_lost = false;
+        }
+        
+        let mut packet =
+            match next_packet.take() {
+                Some(packet) => packet,
+                None => match client.receive() {
+                    Ok(packet) => packet,
+                    Err(e) => {
+                        println!("Lost connection to server: {}", e);
+                        connection_lost = true;
+                        continue;
+                    },
+                },
+            };
+        
+        let id: ClientPacketId =
+            match packet.read() {
+                Ok(id) => id,
+                Err(e) => {
+                    println!("Received invalid packet from server: {}", e);
+                    continue;
+                },
+            };
         
-        // Create the battle state
-        let mut battle_context = BattleContext::new(vec!());
+        match id {
+            ClientPacketId::StarMap => {
+                // Receive the star map
+                sectors = packet.read().ok().expect("Failed to read star map");
+            },
+            ClientPacketId::JoinSector => {
+                // Receive the ships from the server
+                let my_ship: ShipNetworked = packet.read().ok().expect("Failed to read my Ship");
+                let start_at_sim = packet.read().ok().expect("Failed to read start_at_sim from server");
+                let ships: Vec<ShipNetworked> = match packet.read() {
+                    Ok(ships) => ships,
+                    Err(e) => panic!("Unable to receive ships froms server: {}", e),
+                };
+                let environment: SectorEnvironment = packet.read().ok().expect("Failed to read sector environment");
+                
+                // Create the battle state
+                let mut battle_context = BattleContext::new(vec!());
+                battle_context.environment = environment;
+                
+                // Add the ships
+                battle_context.add_networked_ships(ships);
+                battle_context.add_networked_ship(my_ship);
+                
+                let mut battle = ClientBattleState::new(&mut client, battle_context);
+
+                match battle.run(window, gl, glyph_cache, asset_store, sectors.clone(), &pilot, start_at_sim) {
+                    BattleExit::Left => {},
+                    BattleExit::Packet(packet) => { next_packet = Some(packet); },
+                    BattleExit::ConnectionLost => { connection_lost = true; },
+                }
+            },
+            ClientPacketId::SessionToken => {
+                session_token = Some(packet.read().ok().expect("Failed to read session token"));
+            },
+            ClientPacketId::ResumeFailed => {
+                // The session is over. Go back to the menu so the player can log in again.
+                println!("Failed to resume session");
+                return;
+            },
+            ClientPacketId::ServerMessage => {
+                let message: String = packet.read().ok().expect("Failed to read server message");
+                println!("Message from server: {}", message);
+            },
+            ClientPacketId::SectorStatus => {}, // Only shown in battle, and there's another one coming soon
+            id => {
+                println!("Ignoring {:?} packet received outside of a sector", id);
+            },
+        }
+    }
+}
+
+/// Why a login didn't go through, with a message to show the player.
+pub enum LoginFailure {
+    Rejected(String),       // The server turned it down, but the connection can be used to try again
+    ConnectionLost(String), // Trying again needs a new connection
+}
+
+/// Waits for the server's answer to a login. Returns the account summary if the login worked.
+pub fn wait_for_login(client: &mut Client) -> Result<AccountSummary, LoginFailure> {
+    loop {
+        let mut packet =
+            match client.receive() {
+                Ok(packet) => packet,
+                Err(e) => { return Err(LoginFailure::ConnectionLost(format!("Lost connection to server: {}", e))); },
+            };
         
-        // Add the ships
-        battle_context.add_networked_ships(ships);
-        battle_context.add_networked_ship(my_ship);
+        match packet.read() {
+            Ok(ClientPacketId::LoginResult) => {},
+            Ok(ClientPacketId::SectorStatus) => { continue; }, // Goes out to everyone, logged in or not
+            Ok(ClientPacketId::ServerMessage) => {
+                let message: String = packet.read().ok().expect("Failed to read server message");
+                println!("Message from server: {}", message);
+                continue;
+            },
+            Ok(id) => {
+                println!("Ignoring {:?} packet received before logging in", id);
+                continue;
+            },
+            Err(e) => {
+                println!("Received invalid packet from server: {}", e);
+                continue;
+            },
+        }
         
-        let mut battle = ClientBattleState::new(&mut client, battle_context);
+        return match packet.read() {
+            Ok(LoginResponse::Success(summary)) => Ok(summary),
+            Ok(LoginResponse::Failure(e)) => Err(LoginFailure::Rejected(format!("{}", e))),
+            Err(e) => Err(LoginFailure::Rejected(format!("Received invalid login response from server: {}", e))),
+        };
+    }
+}
 
-        battle.run(window, gl, glyph_cache, asset_store, sectors.clone(), start_at_sim);
+// Reconnects to the server and asks to be put back into the session. The server answers with
+// either a join packet for the sector the player was in or a resume failed packet.
+fn resume_session(client: &Client, session_token: SessionToken) -> Option<Client> {
+    for attempt in 0..RECONNECT_ATTEMPTS {
+        println!("Reconnecting to server, attempt {}", attempt + 1);
+        
+        match client.reconnect() {
+            Ok(mut client) => {
+                let mut packet = OutPacket::new();
+                packet.write(&LoginPacket::Resume(session_token)).ok().expect("Failed to write resume packet");
+                client.send(&packet);
+                return Some(client);
+            },
+            Err(e) => {
+                println!("Failed to reconnect: {}", e);
+                thread::sleep(Duration::milliseconds(RECONNECT_DELAY));
+            },
+        }
     }
+    
+    None
 }
\ No newline at end of file
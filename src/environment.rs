@@ -0,0 +1,95 @@
+use std::rand;
+use std::rand::Rng;
+
+use battle_state::BattleContext;
+use module::ModuleBase;
+use sector_data::SectorEnvironment;
+use ship::{Ship, ShipId};
+
+// Ticks in a turn's simulation
+static SIM_TICKS: u32 = 100;
+
+// Nebula: shields only recharge on every this many turns
+static NEBULA_SHIELD_PERIOD: u32 = 2;
+
+// Asteroid field: chance each turn of a ship getting hit, and how hard
+static ASTEROID_HIT_CHANCE: f64 = 0.3;
+static ASTEROID_DAMAGE: u8 = 1;
+
+// Star corona: how often the star flares up, how many of each ship's modules catch fire, and how badly
+static CORONA_PERIOD: u32 = 3;
+static CORONA_MODULES_BURNED: u32 = 2;
+static CORONA_DAMAGE: u8 = 1;
+
+// Ion storm: chance each turn of one of a ship's powered modules getting knocked out
+static ION_STORM_CHANCE: f64 = 0.25;
+
+/// Something a sector's environment does during a turn's simulation. The server decides on these
+/// and sends them with the turn's results, so clients simulate the same thing.
+#[derive(Clone, Debug, RustcEncodable, RustcDecodable)]
+pub enum EnvironmentEvent {
+    Hit(ShipId, u32, u32, u8),  // Damage a ship's module (ship, module index, tick, damage)
+    Burn(ShipId, u32, u32, u8), // Set a ship's module on fire (ship, module index, tick, damage)
+    Depower(ShipId, u32),       // Knock out a ship's powered module before the simulation starts (ship, module index)
+    HoldShields,                // Shields don't recharge after this simulation
+}
+
+/// Decides what the sector's environment does to the ships in it this turn.
+pub fn roll_environment_events(environment: SectorEnvironment, context: &BattleContext, turn_number: u32) -> Vec<EnvironmentEvent> {
+    let mut rng = rand::thread_rng();
+    let mut events = vec!();
+    
+    match environment {
+        SectorEnvironment::Normal => {},
+        SectorEnvironment::Nebula => {
+            if turn_number % NEBULA_SHIELD_PERIOD != 0 {
+                events.push(EnvironmentEvent::HoldShields);
+            }
+        },
+        SectorEnvironment::AsteroidField => {
+            for ship in context.ships_list.iter() {
+                let ship = ship.borrow();
+                if rng.gen::<f64>() < ASTEROID_HIT_CHANCE {
+                    if let Some(module) = pick_module(&mut rng, &ship, |m| m.get_hp() > 0) {
+                        events.push(EnvironmentEvent::Hit(ship.id, module, rng.gen_range(0, SIM_TICKS), ASTEROID_DAMAGE));
+                    }
+                }
+            }
+        },
+        SectorEnvironment::StarCorona => {
+            if turn_number % CORONA_PERIOD == CORONA_PERIOD - 1 {
+                // Everything burns at the same time
+                let tick = rng.gen_range(0, SIM_TICKS);
+                for ship in context.ships_list.iter() {
+                    let ship = ship.borrow();
+                    for _ in 0 .. CORONA_MODULES_BURNED {
+                        if let Some(module) = pick_module(&mut rng, &ship, |m| m.get_hp() > 0) {
+                            events.push(EnvironmentEvent::Burn(ship.id, module, tick, CORONA_DAMAGE));
+                        }
+                    }
+                }
+            }
+        },
+        SectorEnvironment::IonStorm => {
+            for ship in context.ships_list.iter() {
+                let ship = ship.borrow();
+                if rng.gen::<f64>() < ION_STORM_CHANCE {
+                    if let Some(module) = pick_module(&mut rng, &ship, |m| m.powered && m.get_power() > 0) {
+                        events.push(EnvironmentEvent::Depower(ship.id, module));
+                    }
+                }
+            }
+        },
+    }
+    
+    events
+}
+
+// Index of a random one of the ship's modules that passes the filter
+fn pick_module<R: Rng, F: Fn(&ModuleBase) -> bool>(rng: &mut R, ship: &Ship, filter: F) -> Option<u32> {
+    let modules: Vec<u32> = ship.modules.iter().enumerate()
+        .filter(|&(_, module)| filter(module.borrow().get_base()))
+        .map(|(index, _)| index as u32)
+        .collect();
+    rng.choose(modules.as_slice()).map(|&index| index)
+}
@@ -0,0 +1,261 @@
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+
+use rustc_serialize::json;
+
+use sector_data::{SectorData, SectorEnvironment, SectorId};
+use vec::Vec2;
+
+// Where the galaxy gets loaded from unless the server is told otherwise
+pub static DEFAULT_GALAXY_PATH: &'static str = "content/galaxy.json";
+
+// Sector flags
+pub static FLAG_START: &'static str = "start"; // New players start here, and so do players whose sector is gone
+
+static KNOWN_FLAGS: [&'static str; 1] = [FLAG_START];
+
+// An AI ship a sector starts with
+#[derive(Clone, RustcDecodable)]
+pub struct AiShipDef {
+    pub name: String,
+    pub level: u8,
+}
+
+// One sector in the galaxy definition file
+#[derive(RustcDecodable)]
+pub struct SectorDef {
+    pub id: u32,
+    pub name: String,
+    
+    // Position on the star map
+    pub x: f64,
+    pub y: f64,
+    
+    // How dangerous the sector is, starting at 0
+    pub tier: u8,
+    pub environment: SectorEnvironment,
+    
+    pub ai_ships: Vec<AiShipDef>,
+    pub flags: Vec<String>,
+    
+    // IDs of the sectors there are jump lanes to. Lanes go both ways.
+    pub lanes: Vec<u32>,
+}
+
+impl SectorDef {
+    pub fn get_id(&self) -> SectorId {
+        SectorId(self.id)
+    }
+    
+    pub fn has_flag(&self, flag: &str) -> bool {
+        self.flags.iter().any(|f| f.as_slice() == flag)
+    }
+    
+    /// What clients get told about the sector.
+    pub fn to_sector_data(&self) -> SectorData {
+        SectorData {
+            id: self.get_id(),
+            name: self.name.clone(),
+            map_position: Vec2 { x: self.x, y: self.y },
+            environment: self.environment,
+            neighbours: self.lanes.iter().map(|&id| SectorId(id)).collect(),
+        }
+    }
+}
+
+/// Every sector the star map runs, as laid out in a galaxy definition file. The file is JSON:
+///
+/// {"sectors": [{"id": 0, "name": "Haven", "x": 50.0, "y": 50.0, "tier": 0, "environment": "Normal", "ai_ships": [], "flags": ["start"], "lanes": []}]}
+#[derive(RustcDecodable)]
+pub struct Galaxy {
+    pub sectors: Vec<SectorDef>,
+}
+
+impl Galaxy {
+    /// Loads and checks a galaxy definition file.
+    pub fn load(path: &Path) -> Result<Galaxy, String> {
+        let mut text = String::new();
+        try!(File::open(path).and_then(|mut file| file.read_to_string(&mut text)).map_err(|e| {
+            format!("Failed to read galaxy file {}: {}", path.display(), e)
+        }));
+        
+        let mut galaxy: Galaxy = try!(json::decode(text.as_slice()).map_err(|e| {
+            format!("Failed to parse galaxy file {}: {:?}", path.display(), e)
+        }));
+        try!(galaxy.validate());
+        galaxy.link_lanes();
+        
+        Ok(galaxy)
+    }
+    
+    /// Adds a jump lane between two sectors, in both directions.
+    pub fn add_lane(&mut self, a: u32, b: u32) {
+        for sector in self.sectors.iter_mut() {
+            if sector.id == a && !sector.lanes.contains(&b) {
+                sector.lanes.push(b);
+            } else if sector.id == b && !sector.lanes.contains(&a) {
+                sector.lanes.push(a);
+            }
+        }
+    }
+    
+    // Files only have to list a lane on one of its ends, so fill in the other end
+    fn link_lanes(&mut self) {
+        let mut lanes = vec!();
+        for sector in self.sectors.iter() {
+            for &other in sector.lanes.iter() {
+                lanes.push((sector.id, other));
+            }
+        }
+        
+        for (a, b) in lanes.into_iter() {
+            self.add_lane(a, b);
+        }
+    }
+    
+    fn validate(&self) -> Result<(), String> {
+        if self.sectors.is_empty() {
+            return Err("The galaxy has no sectors".to_string());
+        }
+        
+        let mut ids = HashSet::new();
+        for sector in self.sectors.iter() {
+            if !ids.insert(sector.id) {
+                return Err(format!("There's more than one sector {}", sector.id));
+            }
+            
+            for flag in sector.flags.iter() {
+                if !KNOWN_FLAGS.iter().any(|known| *known == flag.as_slice()) {
+                    return Err(format!("Sector {} has unknown flag '{}'", sector.id, flag));
+                }
+            }
+            
+            for &lane in sector.lanes.iter() {
+                if lane == sector.id {
+                    return Err(format!("Sector {} has a jump lane to itself", sector.id));
+                }
+                if !self.sectors.iter().any(|other| other.id == lane) {
+                    return Err(format!("Sector {} has a jump lane to sector {}, which doesn't exist", sector.id, lane));
+                }
+            }
+            
+            if sector.ai_ships.iter().any(|ai| ai.level == 0) {
+                return Err(format!("Sector {} has a level 0 AI ship, levels start at 1", sector.id));
+            }
+        }
+        
+        Ok(())
+    }
+    
+    /// Sector new players start in. That's the first sector flagged as a start sector, or the first
+    /// sector if none are.
+    pub fn get_start_sector(&self) -> SectorId {
+        self.sectors.iter()
+            .find(|sector| sector.has_flag(FLAG_START))
+            .unwrap_or(&self.sectors[0])
+            .get_id()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+    
+    use rustc_serialize::json;
+    
+    use super::{DEFAULT_GALAXY_PATH, Galaxy};
+    
+    // Decodes a galaxy file's contents and checks them the same way loading the file does
+    fn check(text: &str) -> Result<Galaxy, String> {
+        let galaxy: Galaxy = try!(json::decode(text).map_err(|e| format!("{:?}", e)));
+        try!(galaxy.validate());
+        Ok(galaxy)
+    }
+    
+    #[test]
+    fn default_galaxy_loads() {
+        assert!(Galaxy::load(&Path::new(DEFAULT_GALAXY_PATH)).is_ok());
+    }
+    
+    #[test]
+    fn accepts_valid_galaxy() {
+        let galaxy = check(r#"{"sectors": [
+            {"id": 0, "name": "Haven", "x": 50.0, "y": 50.0, "tier": 0, "environment": "Normal", "ai_ships": [], "flags": ["start"], "lanes": [1]},
+            {"id": 1, "name": "Rim", "x": 80.0, "y": 20.0, "tier": 1, "environment": "Nebula", "ai_ships": [{"name": "pirate", "level": 2}], "flags": [], "lanes": []}
+        ]}"#);
+        assert!(galaxy.is_ok());
+    }
+    
+    #[test]
+    fn rejects_empty_galaxy() {
+        assert!(check(r#"{"sectors": []}"#).is_err());
+    }
+    
+    #[test]
+    fn rejects_duplicate_sector_ids() {
+        assert!(check(r#"{"sectors": [
+            {"id": 4, "name": "Haven", "x": 0.0, "y": 0.0, "tier": 0, "environment": "Normal", "ai_ships": [], "flags": [], "lanes": []},
+            {"id": 4, "name": "Haven again", "x": 10.0, "y": 0.0, "tier": 0, "environment": "Normal", "ai_ships": [], "flags": [], "lanes": []}
+        ]}"#).is_err());
+    }
+    
+    #[test]
+    fn rejects_unknown_flags() {
+        assert!(check(r#"{"sectors": [
+            {"id": 0, "name": "Haven", "x": 0.0, "y": 0.0, "tier": 0, "environment": "Normal", "ai_ships": [], "flags": ["sart"], "lanes": []}
+        ]}"#).is_err());
+    }
+    
+    #[test]
+    fn rejects_bad_lanes() {
+        // To itself
+        assert!(check(r#"{"sectors": [
+            {"id": 0, "name": "Haven", "x": 0.0, "y": 0.0, "tier": 0, "environment": "Normal", "ai_ships": [], "flags": [], "lanes": [0]}
+        ]}"#).is_err());
+        
+        // To a sector that isn't there
+        assert!(check(r#"{"sectors": [
+            {"id": 0, "name": "Haven", "x": 0.0, "y": 0.0, "tier": 0, "environment": "Normal", "ai_ships": [], "flags": [], "lanes": [1]},
+            {"id": 1, "name": "Rim", "x": 10.0, "y": 0.0, "tier": 0, "environment": "Normal", "ai_ships": [], "flags": [], "lanes": [5]}
+        ]}"#).is_err());
+    }
+    
+    #[test]
+    fn links_lanes_both_ways() {
+        let mut galaxy = check(r#"{"sectors": [
+            {"id": 0, "name": "Haven", "x": 0.0, "y": 0.0, "tier": 0, "environment": "Normal", "ai_ships": [], "flags": [], "lanes": [1, 2]},
+            {"id": 1, "name": "Rim", "x": 10.0, "y": 0.0, "tier": 0, "environment": "Normal", "ai_ships": [], "flags": [], "lanes": []},
+            {"id": 2, "name": "Drift", "x": 20.0, "y": 0.0, "tier": 0, "environment": "Normal", "ai_ships": [], "flags": [], "lanes": [0]}
+        ]}"#).unwrap();
+        galaxy.link_lanes();
+        
+        assert_eq!(galaxy.sectors[0].lanes, vec![1, 2]);
+        assert_eq!(galaxy.sectors[1].lanes, vec![0]);
+        assert_eq!(galaxy.sectors[2].lanes, vec![0]);
+    }
+    
+    #[test]
+    fn rejects_level_0_ai_ships() {
+        assert!(check(r#"{"sectors": [
+            {"id": 0, "name": "Haven", "x": 0.0, "y": 0.0, "tier": 0, "environment": "Normal", "ai_ships": [{"name": "pirate", "level": 0}], "flags": [], "lanes": []}
+        ]}"#).is_err());
+    }
+    
+    #[test]
+    fn finds_start_sector() {
+        let galaxy = check(r#"{"sectors": [
+            {"id": 3, "name": "Rim", "x": 0.0, "y": 0.0, "tier": 0, "environment": "Normal", "ai_ships": [], "flags": [], "lanes": []},
+            {"id": 7, "name": "Haven", "x": 10.0, "y": 0.0, "tier": 0, "environment": "Normal", "ai_ships": [], "flags": ["start"], "lanes": []}
+        ]}"#).unwrap();
+        assert_eq!(galaxy.get_start_sector().0, 7);
+        
+        // Without a start flag, the first sector will do
+        let galaxy = check(r#"{"sectors": [
+            {"id": 3, "name": "Rim", "x": 0.0, "y": 0.0, "tier": 0, "environment": "Normal", "ai_ships": [], "flags": [], "lanes": []},
+            {"id": 7, "name": "Haven", "x": 10.0, "y": 0.0, "tier": 0, "environment": "Normal", "ai_ships": [], "flags": [], "lanes": []}
+        ]}"#).unwrap();
+        assert_eq!(galaxy.get_start_sector().0, 3);
+    }
+}
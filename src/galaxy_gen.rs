@@ -0,0 +1,300 @@
+use std::ascii::AsciiExt;
+use std::cmp;
+use std::collections::HashSet;
+use std::rand::{Rng, SeedableRng, XorShiftRng};
+
+use galaxy::{AiShipDef, FLAG_START, Galaxy, SectorDef};
+use sector_data::SectorEnvironment;
+use vec::{Vec2, Vec2f};
+
+// Sectors generated galaxies get unless told otherwise
+pub static DEFAULT_GALAXY_SECTORS: u32 = 12;
+
+// Area of the star map sectors get placed in
+static MAP_MIN: Vec2f = Vec2 { x: 30.0, y: 30.0 };
+static MAP_MAX: Vec2f = Vec2 { x: 760.0, y: 330.0 };
+
+// How close sectors are allowed to be, and how many tries each sector gets to find a spot that far from the others
+static MIN_SECTOR_DISTANCE: f64 = 60.0;
+static PLACEMENT_TRIES: u32 = 50;
+
+// Chance of a sector getting an extra lane to its nearest sector it isn't already linked to
+static EXTRA_LANE_CHANCE: f64 = 0.4;
+
+// Chance of a sector other than the start having an environment other than normal space
+static SPECIAL_ENVIRONMENT_CHANCE: f64 = 0.5;
+
+static SPECIAL_ENVIRONMENTS: [SectorEnvironment; 4] = [
+    SectorEnvironment::Nebula,
+    SectorEnvironment::AsteroidField,
+    SectorEnvironment::StarCorona,
+    SectorEnvironment::IonStorm,
+];
+
+// The most dangerous a sector can be
+static MAX_TIER: u8 = 5;
+
+static MAX_AI_SHIPS: u32 = 4;
+
+// Generated AI ships get names starting with this. Usernames can't have dashes in them, so no
+// player can ever register one of these names.
+static AI_NAME_PREFIX: &'static str = "ai-";
+
+static NAME_STARTS: [&'static str; 12] = ["Al", "Bel", "Cor", "Dra", "Es", "Fen", "Gal", "Hel", "Ix", "Ka", "Lor", "Myr"];
+static NAME_MIDDLES: [&'static str; 8] = ["a", "e", "i", "o", "u", "ae", "io", "ar"];
+static NAME_ENDS: [&'static str; 10] = ["don", "ris", "tis", "nar", "x", "los", "th", "mir", "vek", "sus"];
+static NAME_SUFFIXES: [&'static str; 6] = ["", "", " Prime", " Reach", " Expanse", " Drift"];
+
+/// Generates a galaxy of sector_count sectors joined by jump lanes. The same seed always gives the
+/// same galaxy. Sector 0 is the start sector, and sectors get more dangerous the more jumps away
+/// from it they are.
+pub fn generate_galaxy(seed: u64, sector_count: u32) -> Galaxy {
+    if sector_count == 0 {
+        panic!("Can't generate a galaxy with no sectors");
+    }
+    
+    // XorShift won't take an all zero seed, so mix in some constants
+    let mut rng: XorShiftRng = SeedableRng::from_seed([seed as u32 ^ 0x193a6754, (seed >> 32) as u32 ^ 0xa8a7d469, 0x97830e05, 0x113ba7bb]);
+    
+    // Place the sectors
+    let mut positions: Vec<Vec2f> = vec!();
+    for _ in 0 .. sector_count {
+        let mut position = random_position(&mut rng);
+        for _ in 0 .. PLACEMENT_TRIES {
+            if positions.iter().all(|p| (*p - position).length() >= MIN_SECTOR_DISTANCE) {
+                break;
+            }
+            position = random_position(&mut rng);
+        }
+        positions.push(position);
+    }
+    
+    // Name them, making sure no two sectors share a name
+    let mut used_names = HashSet::new();
+    let mut names = vec!();
+    for i in 0 .. sector_count {
+        let mut name = random_name(&mut rng);
+        if used_names.contains(&name) {
+            name = format!("{} {}", name, i);
+        }
+        used_names.insert(name.clone());
+        names.push(name);
+    }
+    
+    let mut galaxy = Galaxy {
+        sectors: positions.iter().zip(names.into_iter()).enumerate().map(|(id, (position, name))| {
+            SectorDef {
+                id: id as u32,
+                name: name,
+                x: position.x,
+                y: position.y,
+                tier: 0,
+                environment: SectorEnvironment::Normal,
+                ai_ships: vec!(),
+                flags: vec!(),
+                lanes: vec!(),
+            }
+        }).collect(),
+    };
+    galaxy.sectors[0].flags.push(FLAG_START.to_string());
+    
+    // Join every sector up with a minimum spanning tree so any sector can be reached from any other
+    let mut linked = vec![false; sector_count as usize];
+    linked[0] = true;
+    for _ in 1 .. sector_count {
+        let mut shortest: Option<(usize, usize, f64)> = None;
+        for from in (0 .. sector_count as usize).filter(|&i| linked[i]) {
+            for to in (0 .. sector_count as usize).filter(|&i| !linked[i]) {
+                let distance = (positions[from] - positions[to]).length();
+                match shortest {
+                    Some((_, _, shortest_distance)) if shortest_distance <= distance => {},
+                    _ => { shortest = Some((from, to, distance)); },
+                }
+            }
+        }
+        
+        let (from, to, _) = shortest.unwrap();
+        linked[to] = true;
+        galaxy.add_lane(from as u32, to as u32);
+    }
+    
+    // Throw in some extra lanes so there's more than one way to get places
+    for from in 0 .. sector_count as usize {
+        if rng.gen::<f64>() >= EXTRA_LANE_CHANCE {
+            continue;
+        }
+        
+        let nearest = (0 .. sector_count as usize)
+            .filter(|&to| to != from && !galaxy.sectors[from].lanes.contains(&(to as u32)))
+            .map(|to| (to, (positions[from] - positions[to]).length()))
+            .fold(None, |nearest: Option<(usize, f64)>, (to, distance)| {
+                match nearest {
+                    Some((_, nearest_distance)) if nearest_distance <= distance => nearest,
+                    _ => Some((to, distance)),
+                }
+            });
+        
+        if let Some((to, _)) = nearest {
+            galaxy.add_lane(from as u32, to as u32);
+        }
+    }
+    
+    // Sectors are as dangerous as they are jumps away from the start
+    let jumps = jumps_from_start(&galaxy);
+    for (sector, jumps) in galaxy.sectors.iter_mut().zip(jumps.into_iter()) {
+        sector.tier = cmp::min(jumps, MAX_TIER as u32) as u8;
+        
+        // The start sector is safe
+        if sector.tier > 0 {
+            if rng.gen::<f64>() < SPECIAL_ENVIRONMENT_CHANCE {
+                sector.environment = *rng.choose(&SPECIAL_ENVIRONMENTS).unwrap();
+            }
+            
+            let num_ai_ships = rng.gen_range(1, MAX_AI_SHIPS + 1);
+            for i in 0 .. num_ai_ships {
+                sector.ai_ships.push(AiShipDef {
+                    name: format!("{}{}_{}", AI_NAME_PREFIX, sector.name.to_ascii_lowercase().replace(" ", "_"), i + 1),
+                    level: sector.tier + 1,
+                });
+            }
+        }
+    }
+    
+    galaxy
+}
+
+fn random_position(rng: &mut XorShiftRng) -> Vec2f {
+    Vec2 {
+        x: rng.gen_range(MAP_MIN.x, MAP_MAX.x),
+        y: rng.gen_range(MAP_MIN.y, MAP_MAX.y),
+    }
+}
+
+fn random_name(rng: &mut XorShiftRng) -> String {
+    format!("{}{}{}{}",
+        rng.choose(&NAME_STARTS).unwrap(),
+        rng.choose(&NAME_MIDDLES).unwrap(),
+        rng.choose(&NAME_ENDS).unwrap(),
+        rng.choose(&NAME_SUFFIXES).unwrap()
+    )
+}
+
+// Fewest jumps it takes to get to each sector from sector 0
+fn jumps_from_start(galaxy: &Galaxy) -> Vec<u32> {
+    let mut jumps: Vec<Option<u32>> = galaxy.sectors.iter().map(|_| None).collect();
+    jumps[0] = Some(0);
+    
+    let mut to_visit = vec![0u32];
+    while !to_visit.is_empty() {
+        let mut next = vec!();
+        for &id in to_visit.iter() {
+            let sector_jumps = jumps[id as usize].unwrap();
+            for &lane in galaxy.sectors[id as usize].lanes.iter() {
+                if jumps[lane as usize].is_none() {
+                    jumps[lane as usize] = Some(sector_jumps + 1);
+                    next.push(lane);
+                }
+            }
+        }
+        to_visit = next;
+    }
+    
+    jumps.into_iter().map(|j| j.unwrap_or(0)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use galaxy::{FLAG_START, Galaxy};
+    use login::validate_username;
+    use super::generate_galaxy;
+    
+    static SEEDS: [u64; 5] = [0, 1, 42, 0xdeadbeef, 0xffffffffffffffff];
+    static SIZES: [u32; 5] = [1, 2, 5, 12, 40];
+    
+    // Which sectors can be reached from sector 0
+    fn reachable(galaxy: &Galaxy) -> Vec<bool> {
+        let mut reached = vec![false; galaxy.sectors.len()];
+        reached[0] = true;
+        let mut to_visit = vec![0u32];
+        while let Some(id) = to_visit.pop() {
+            for &lane in galaxy.sectors[id as usize].lanes.iter() {
+                if !reached[lane as usize] {
+                    reached[lane as usize] = true;
+                    to_visit.push(lane);
+                }
+            }
+        }
+        reached
+    }
+    
+    #[test]
+    fn same_seed_gives_same_galaxy() {
+        for &seed in SEEDS.iter() {
+            let a = generate_galaxy(seed, 12);
+            let b = generate_galaxy(seed, 12);
+            for (a, b) in a.sectors.iter().zip(b.sectors.iter()) {
+                assert_eq!(a.name, b.name);
+                assert_eq!((a.x, a.y), (b.x, b.y));
+                assert_eq!(a.tier, b.tier);
+                assert_eq!(a.environment, b.environment);
+                assert_eq!(a.lanes, b.lanes);
+                assert_eq!(a.ai_ships.iter().map(|ai| (ai.name.clone(), ai.level)).collect::<Vec<(String, u8)>>(),
+                           b.ai_ships.iter().map(|ai| (ai.name.clone(), ai.level)).collect::<Vec<(String, u8)>>());
+            }
+        }
+    }
+    
+    #[test]
+    fn different_seeds_give_different_galaxies() {
+        let a = generate_galaxy(1, 12);
+        let b = generate_galaxy(2, 12);
+        assert!(a.sectors.iter().zip(b.sectors.iter()).any(|(a, b)| (a.x, a.y) != (b.x, b.y)));
+    }
+    
+    #[test]
+    fn every_sector_is_reachable() {
+        for &seed in SEEDS.iter() {
+            for &size in SIZES.iter() {
+                let galaxy = generate_galaxy(seed, size);
+                assert_eq!(galaxy.sectors.len(), size as usize);
+                assert!(reachable(&galaxy).iter().all(|&reached| reached), "seed {} size {} isn't connected", seed, size);
+            }
+        }
+    }
+    
+    #[test]
+    fn lanes_go_both_ways() {
+        for &seed in SEEDS.iter() {
+            let galaxy = generate_galaxy(seed, 20);
+            for sector in galaxy.sectors.iter() {
+                for &lane in sector.lanes.iter() {
+                    assert!(lane != sector.id);
+                    assert!(galaxy.sectors[lane as usize].lanes.contains(&sector.id));
+                }
+            }
+        }
+    }
+    
+    #[test]
+    fn start_sector_is_safe() {
+        for &seed in SEEDS.iter() {
+            let galaxy = generate_galaxy(seed, 12);
+            let start = &galaxy.sectors[0];
+            assert!(start.has_flag(FLAG_START));
+            assert_eq!(start.tier, 0);
+            assert!(start.ai_ships.is_empty());
+            assert!(galaxy.sectors[1..].iter().all(|sector| sector.tier > 0 && !sector.ai_ships.is_empty()));
+        }
+    }
+    
+    #[test]
+    fn ai_ship_names_cant_be_registered() {
+        for &seed in SEEDS.iter() {
+            for sector in generate_galaxy(seed, 12).sectors.iter() {
+                for ai in sector.ai_ships.iter() {
+                    assert!(validate_username(ai.name.as_slice()).is_err(), "{} could be registered", ai.name);
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,203 @@
+#![crate_name = "reforge_load_test"]
+#![crate_type = "bin"]
+#![feature(box_syntax)]
+#![feature(rand)]
+#![feature(core)]
+#![feature(os)]
+#![feature(io)]
+#![feature(fs)]
+#![feature(old_io)]
+#![feature(alloc)]
+#![feature(thread_sleep)]
+#![feature(collections)]
+#![feature(std_misc)]
+
+extern crate bincode;
+extern crate crypto;
+extern crate flate2;
+extern crate time;
+extern crate rustc_serialize;
+
+use std::old_io::timer::Timer;
+use std::os;
+use std::sync::mpsc::channel;
+use std::thread::{self, Builder};
+use std::time::Duration;
+
+use bot_client::{BotClient, BotEvent};
+use net::Client;
+use net_sim::NetworkConditions;
+
+mod ai;
+mod battle_state;
+mod bot_client;
+mod environment;
+mod login;
+mod module;
+mod net;
+mod net_sim;
+mod sector_data;
+mod ship;
+mod sim;
+mod sim_events;
+mod vec;
+
+// How often to print the numbers so far, in milliseconds
+static REPORT_INTERVAL: i64 = 5000;
+
+// Everything the bots reported so far
+struct LoadStats {
+    bots: u32,
+    logged_in: u32,
+    stopped: u32,
+    disconnects: u32,
+    dropped: u32,
+    login_times: Vec<i64>,
+    results_times: Vec<i64>,
+    latencies: Vec<i64>,
+}
+
+impl LoadStats {
+    fn new(bots: u32) -> LoadStats {
+        LoadStats {
+            bots: bots,
+            logged_in: 0,
+            stopped: 0,
+            disconnects: 0,
+            dropped: 0,
+            login_times: vec!(),
+            results_times: vec!(),
+            latencies: vec!(),
+        }
+    }
+    
+    fn add_event(&mut self, event: BotEvent) {
+        match event {
+            BotEvent::LoggedIn(login_time) => {
+                self.logged_in += 1;
+                self.login_times.push(login_time);
+            },
+            BotEvent::TurnResults(results_time) => { self.results_times.push(results_time); },
+            BotEvent::Latency(latency) => { self.latencies.push(latency as i64); },
+            BotEvent::Dropped => { self.dropped += 1; },
+            BotEvent::Disconnected => { self.disconnects += 1; },
+            BotEvent::Stopped => { self.stopped += 1; },
+        }
+    }
+    
+    fn print(&mut self) {
+        println!("=== {} bots: {} logged in, {} stopped, {} disconnects, {} dropped turns ===",
+                 self.bots, self.logged_in, self.stopped, self.disconnects, self.dropped);
+        print_times("Login", &mut self.login_times);
+        print_times("Turn results", &mut self.results_times);
+        print_times("Round trip", &mut self.latencies);
+    }
+}
+
+fn print_times(name: &str, times: &mut Vec<i64>) {
+    if times.is_empty() {
+        println!("{}: no samples", name);
+        return;
+    }
+    
+    times.sort();
+    let total = times.iter().fold(0, |total, &t| total + t);
+    let percentile = |p: usize| times[(times.len() - 1)*p/100];
+    println!("{}: {} samples, avg {}ms, median {}ms, 95th {}ms, max {}ms",
+             name, times.len(), total/(times.len() as i64), percentile(50), percentile(95), times[times.len() - 1]);
+}
+
+fn main() {
+    let args = os::args();
+    
+    // Usage: reforge_load_test [host] [--bots <count>] [--ramp <ms between logins>] [--duration <seconds>]
+    //                          [--jump-chance <chance>] [--netsim <settings>]
+    let host =
+        match args.get(1) {
+            Some(arg) if !arg.as_slice().starts_with("--") => arg.clone(),
+            _ => "localhost:30000".to_string(),
+        };
+    let option = |name: &str| args.iter().position(|arg| arg.as_slice() == name).and_then(|i| args.get(i + 1));
+    
+    let bot_count: u32 = option("--bots").map(|s| s.parse().ok().expect("Invalid --bots")).unwrap_or(100);
+    let ramp: i64 = option("--ramp").map(|s| s.parse().ok().expect("Invalid --ramp")).unwrap_or(50);
+    let duration: Option<i64> = option("--duration").map(|s| s.parse().ok().expect("Invalid --duration"));
+    let jump_chance: f64 = option("--jump-chance").map(|s| s.parse().ok().expect("Invalid --jump-chance")).unwrap_or(0.1);
+    let conditions = NetworkConditions::from_args(args.as_slice());
+    
+    println!("Starting {} bots against {}", bot_count, host);
+    
+    let (event_sender, event_receiver) = channel();
+    
+    // Log the bots in gradually, so the numbers are about playing rather than everyone connecting at once
+    let starter_host = host.clone();
+    Builder::new().name("load_test_starter".to_string()).spawn(move || {
+        for i in 0..bot_count {
+            let host = starter_host.clone();
+            let event_sender = event_sender.clone();
+            
+            Builder::new()
+                .name(format!("bot_{}", i))
+                .stack_size(8388608)
+                .spawn(move || {
+                    let username = format!("loadtest_{}", i);
+                    let client =
+                        match conditions {
+                            Some(conditions) => Client::new_simulated(host.as_slice(), conditions),
+                            None => Client::new(host.as_slice()),
+                        };
+                    let client =
+                        match client {
+                            Ok(client) => client,
+                            Err(e) => {
+                                println!("Bot {}: {}", username, e);
+                                event_sender.send(BotEvent::Stopped);
+                                return;
+                            },
+                        };
+                    
+                    let mut bot = BotClient::new(client, username);
+                    bot.set_jump_chance(jump_chance);
+                    bot.set_spread_index(i as usize);
+                    bot.set_event_sender(event_sender);
+                    bot.run("loadtest".to_string());
+                });
+            
+            thread::sleep(Duration::milliseconds(ramp));
+        }
+    });
+    
+    let mut stats = LoadStats::new(bot_count);
+    
+    let mut timer = Timer::new().ok().expect("Failed to create timer");
+    let report_timer = timer.periodic(Duration::milliseconds(REPORT_INTERVAL));
+    
+    // Without a duration, run until every bot stops
+    let mut end_timer = Timer::new().ok().expect("Failed to create timer");
+    let (_end_sender, end) = channel();
+    let end =
+        match duration {
+            Some(duration) => end_timer.oneshot(Duration::seconds(duration)),
+            None => end,
+        };
+    
+    loop {
+        select! {
+            event = event_receiver.recv() => {
+                match event {
+                    Ok(event) => { stats.add_event(event); },
+                    Err(_) => { break; }, // Every bot is done
+                }
+            },
+            _ = report_timer.recv() => {
+                stats.print();
+            },
+            _ = end.recv() => {
+                break;
+            }
+        }
+    }
+    
+    println!("Load test finished");
+    stats.print();
+}
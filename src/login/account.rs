@@ -1,47 +1,189 @@
+use std::ascii::AsciiExt;
+use std::cmp::min;
 use std::collections::HashMap;
+use std::fmt;
+use std::rand;
 use std::string::String;
+use std::time::Duration;
+use time;
 
+use ai::AI_SHIP_NAMES;
 use net::ClientId;
 use ship::ShipStored;
 use sector_data::SectorId;
+use super::AccountDbRef;
+use super::password::PasswordHash;
+use super::progress::AccountProgress;
 
 pub type AccountBox = Box<Account>;
 
-#[derive(PartialEq)]
+// Secret given to a client at login so it can get back into its session after a dropped connection
+pub type SessionToken = u64;
+
+// Allowed username and password lengths, in characters
+static MIN_USERNAME_LENGTH: usize = 3;
+static MAX_USERNAME_LENGTH: usize = 16;
+static MIN_PASSWORD_LENGTH: usize = 6;
+static MAX_PASSWORD_LENGTH: usize = 64;
+
+// Names nobody can register, on top of the AI ship names
+static RESERVED_USERNAMES: [&'static str; 4] = ["ai", "admin", "server", "reforge"];
+
+// Wrong passwords allowed for an account before it has to wait between tries
+static FREE_LOGIN_FAILURES: u32 = 3;
+
+// Wait after the first wrong password past the free ones, in ms. It doubles with each one after that.
+static LOGIN_BACKOFF: i64 = 1000;
+static MAX_LOGIN_BACKOFF: i64 = 60000;
+
+#[derive(Debug, PartialEq, RustcEncodable, RustcDecodable)]
 pub enum LoginError {
     NoSuchAccount,
     WrongPassword,
     AlreadyLoggedIn,
+    
+    // Registration errors
+    UsernameTaken,
+    UsernameReserved,
+    BadUsernameLength,
+    BadUsernameCharacters,
+    BadPasswordLength,
+    PasswordIsUsername,
+    
+    // Errors that go away if the client waits
+    TooManyFailures,
+    ServerBusy,
+}
+
+impl fmt::Display for LoginError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            LoginError::NoSuchAccount => write!(f, "There is no account with that username. Register to make one."),
+            LoginError::WrongPassword => write!(f, "Wrong password"),
+            LoginError::AlreadyLoggedIn => write!(f, "That account is already logged in"),
+            LoginError::UsernameTaken => write!(f, "That username is taken"),
+            LoginError::UsernameReserved => write!(f, "That username is reserved"),
+            LoginError::BadUsernameLength =>
+                write!(f, "Usernames must be {} to {} characters long", MIN_USERNAME_LENGTH, MAX_USERNAME_LENGTH),
+            LoginError::BadUsernameCharacters => write!(f, "Usernames can only have letters, numbers and underscores"),
+            LoginError::BadPasswordLength =>
+                write!(f, "Passwords must be {} to {} characters long", MIN_PASSWORD_LENGTH, MAX_PASSWORD_LENGTH),
+            LoginError::PasswordIsUsername => write!(f, "Your password can't be your username"),
+            LoginError::TooManyFailures => write!(f, "Too many wrong passwords. Wait a bit and try again."),
+            LoginError::ServerBusy => write!(f, "The server is busy. Try again in a moment."),
+        }
+    }
 }
 
 pub struct Account {
     pub username: String,
-    pub password: String,
+    pub password: PasswordHash,
     pub ship: Option<ShipStored>,
     pub client_id: Option<ClientId>,
     pub sector: SectorId,
+    pub session_token: Option<SessionToken>,
+    pub progress: AccountProgress,
+}
+
+impl Account {
+    /// Returns true if the password is this account's password.
+    pub fn check_password(&self, password: &str) -> bool {
+        self.password.verify(password)
+    }
+    
+    /// Replaces the account's password.
+    pub fn set_password(&mut self, password: &str) {
+        self.password = PasswordHash::new(password);
+    }
+}
+
+// Wrong passwords in a row for an account
+struct LoginFailures {
+    count: u32,
+    locked_until: time::Timespec, // No logins are checked before this
 }
 
 pub struct AccountManager {
     accounts: HashMap<String, Option<AccountBox>>,
+    
+    // Accounts that had wrong passwords since their last login
+    failures: HashMap<String, LoginFailures>,
+    
+    // Session tokens of logged in accounts, mapped to the accounts' usernames
+    sessions: HashMap<SessionToken, String>,
+    
+    // Where accounts get saved, if anywhere
+    db: Option<AccountDbRef>,
+    
+    // Sector new accounts start in
+    start_sector: SectorId,
 }
 
 impl AccountManager {
+    /// Creates an account manager that forgets everything when it's gone.
     pub fn new() -> AccountManager {
         AccountManager {
             accounts: HashMap::new(),
+            failures: HashMap::new(),
+            sessions: HashMap::new(),
+            db: None,
+            start_sector: SectorId(0),
         }
     }
     
+    /// Creates an account manager with all the accounts saved in the database. Accounts are saved
+    /// back to it whenever they're created or logged out.
+    pub fn with_db(db: AccountDbRef) -> AccountManager {
+        let records = db.lock().unwrap().load_records();
+        println!("Loaded {} accounts", records.len());
+        
+        let mut accounts = HashMap::new();
+        for record in records.into_iter() {
+            accounts.insert(record.username.clone(), Some(Box::new(record.to_account())));
+        }
+        
+        AccountManager {
+            accounts: accounts,
+            failures: HashMap::new(),
+            sessions: HashMap::new(),
+            db: Some(db),
+            start_sector: SectorId(0),
+        }
+    }
+    
+    /// Sets the sector new accounts start in. Sector 0 unless this is called.
+    pub fn set_start_sector(&mut self, sector_id: SectorId) {
+        self.start_sector = sector_id;
+    }
+    
+    /// Creates a new account if the username and password are allowed and the username isn't
+    /// taken. Usernames are compared without case.
+    pub fn register_account(&mut self, username: String, password: String) -> Result<(), LoginError> {
+        try!(validate_username(username.as_slice()));
+        try!(validate_password(username.as_slice(), password.as_slice()));
+        
+        let lowercase = username.to_ascii_lowercase();
+        if self.accounts.keys().any(|other| other.to_ascii_lowercase() == lowercase) {
+            return Err(LoginError::UsernameTaken);
+        }
+        
+        self.create_account(username, password);
+        Ok(())
+    }
+    
     /// Creates a new account with no ship and no client ID
     pub fn create_account(&mut self, username: String, password: String) {
-        self.accounts.insert(username.clone(), Some(Box::new(Account {
-            username: username,
-            password: password,
+        let account = Box::new(Account {
+            username: username.clone(),
+            password: PasswordHash::new(password.as_slice()),
             ship: None,
             client_id: None,
-            sector: SectorId(0),
-        })));
+            sector: self.start_sector,
+            session_token: None,
+            progress: AccountProgress::new(),
+        });
+        self.save(&account);
+        self.accounts.insert(username, Some(account));
     }
     
     /// Attempts to log an account in and returns the AccountBox on success.
@@ -49,22 +191,44 @@ impl AccountManager {
     pub fn login_account(&mut self, username: String, password: String, client_id: ClientId) -> Result<AccountBox, LoginError> {
         use std::collections::hash_map::Entry;
     
-        if let Entry::Occupied(mut account_entry) = self.accounts.entry(username) {
+        let session_token = self.new_session_token();
+        
+        // Don't even hash the password while the account has to wait
+        if let Some(failures) = self.failures.get(&username) {
+            if time::get_time() < failures.locked_until {
+                return Err(LoginError::TooManyFailures);
+            }
+        }
+    
+        if let Entry::Occupied(mut account_entry) = self.accounts.entry(username.clone()) {
             // Make sure the account is available to log into
             if account_entry.get().is_none() {
                 // This account is set to None, which means it's already logged in.
                 Err(LoginError::AlreadyLoggedIn)
             } else {
                 // Verify password
-                if account_entry.get().as_ref().unwrap().password == password {
+                if account_entry.get().as_ref().unwrap().check_password(password.as_slice()) {
                     // All good, log the account in
                     
+                    // Redo hashes made with older settings now that we have the password. The
+                    // account gets saved when it logs out.
+                    if account_entry.get().as_ref().unwrap().password.is_outdated() {
+                        account_entry.get_mut().as_mut().unwrap().set_password(password.as_slice());
+                    }
+                    
+                    self.failures.remove(&username);
+                    
                     // Set the client ID
                     account_entry.get_mut().as_mut().unwrap().client_id = Some(client_id);
                     
+                    // Start a new session
+                    self.sessions.insert(session_token, username);
+                    account_entry.get_mut().as_mut().unwrap().session_token = Some(session_token);
+                    
                     // Remove the account and replace it with None to show the account is logged in.
                     Ok(account_entry.insert(None).unwrap())
                 } else {
+                    add_login_failure(&mut self.failures, &username);
                     Err(LoginError::WrongPassword)
                 }
             }
@@ -72,4 +236,185 @@ impl AccountManager {
             Err(LoginError::NoSuchAccount)
         }
     }
-}
\ No newline at end of file
+    
+    /// Changes the password of an account that isn't logged in. The old password has to be right.
+    pub fn change_password(&mut self, username: &str, old_password: &str, new_password: &str) -> Result<(), LoginError> {
+        try!(validate_password(username, new_password));
+        
+        {
+            let account =
+                match self.accounts.get_mut(username) {
+                    Some(&mut Some(ref mut account)) => account,
+                    Some(&mut None) => { return Err(LoginError::AlreadyLoggedIn); },
+                    None => { return Err(LoginError::NoSuchAccount); },
+                };
+            
+            if !account.check_password(old_password) {
+                return Err(LoginError::WrongPassword);
+            }
+            
+            account.set_password(new_password);
+        }
+        
+        if let Some(&Some(ref account)) = self.accounts.get(username) {
+            self.save(account);
+        }
+        
+        Ok(())
+    }
+    
+    /// Returns a logged in account to the manager so it can be logged into again.
+    pub fn logout_account(&mut self, mut account: AccountBox) {
+        // End the account's session
+        if let Some(session_token) = account.session_token.take() {
+            self.sessions.remove(&session_token);
+        }
+        
+        account.client_id = None;
+        self.save(&account);
+        self.accounts.insert(account.username.clone(), Some(account));
+    }
+    
+    fn save(&self, account: &Account) {
+        if let Some(ref db) = self.db {
+            if let Err(e) = db.lock().unwrap().save(account) {
+                println!("Failed to save account {}: {}", account.username, e);
+            }
+        }
+    }
+    
+    /// Returns true if the session token belongs to a logged in account.
+    pub fn is_session_active(&self, session_token: SessionToken) -> bool {
+        self.sessions.contains_key(&session_token)
+    }
+    
+    fn new_session_token(&self) -> SessionToken {
+        loop {
+            let session_token = rand::random();
+            if !self.sessions.contains_key(&session_token) {
+                return session_token;
+            }
+        }
+    }
+}
+
+// Counts a wrong password for an account and makes it wait longer before the next try
+fn add_login_failure(failures: &mut HashMap<String, LoginFailures>, username: &String) {
+    use std::collections::hash_map::Entry;
+    
+    let failures = match failures.entry(username.clone()) {
+        Entry::Occupied(entry) => entry.into_mut(),
+        Entry::Vacant(entry) => entry.insert(LoginFailures { count: 0, locked_until: time::get_time() }),
+    };
+    
+    failures.count += 1;
+    if failures.count > FREE_LOGIN_FAILURES {
+        let doublings = min(failures.count - FREE_LOGIN_FAILURES - 1, 6);
+        let backoff = min(LOGIN_BACKOFF << doublings as usize, MAX_LOGIN_BACKOFF);
+        failures.locked_until = time::get_time() + Duration::milliseconds(backoff);
+    }
+}
+
+/// Checks that a username is allowed to be registered, not counting whether it's taken.
+pub fn validate_username(username: &str) -> Result<(), LoginError> {
+    let length = username.chars().count();
+    if length < MIN_USERNAME_LENGTH || length > MAX_USERNAME_LENGTH {
+        return Err(LoginError::BadUsernameLength);
+    }
+    
+    let allowed = |c: char| match c { 'a'...'z' | 'A'...'Z' | '0'...'9' | '_' => true, _ => false };
+    if !username.chars().all(allowed) {
+        return Err(LoginError::BadUsernameCharacters);
+    }
+    
+    let lowercase = username.to_ascii_lowercase();
+    if RESERVED_USERNAMES.iter().chain(AI_SHIP_NAMES.iter()).any(|name| name.to_ascii_lowercase() == lowercase) {
+        return Err(LoginError::UsernameReserved);
+    }
+    
+    Ok(())
+}
+
+/// Checks that a password is allowed for an account with the given username.
+pub fn validate_password(username: &str, password: &str) -> Result<(), LoginError> {
+    let length = password.chars().count();
+    if length < MIN_PASSWORD_LENGTH || length > MAX_PASSWORD_LENGTH {
+        return Err(LoginError::BadPasswordLength);
+    }
+    
+    if password.to_ascii_lowercase() == username.to_ascii_lowercase() {
+        return Err(LoginError::PasswordIsUsername);
+    }
+    
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::iter;
+    
+    use super::{AccountManager, FREE_LOGIN_FAILURES, LoginError, validate_password, validate_username};
+    
+    #[test]
+    fn accepts_normal_usernames() {
+        assert_eq!(validate_username("bob"), Ok(()));
+        assert_eq!(validate_username("Space_Pilot_42"), Ok(()));
+        assert_eq!(validate_username("abcdefghijklmnop"), Ok(()));
+    }
+    
+    #[test]
+    fn rejects_usernames_of_the_wrong_length() {
+        assert_eq!(validate_username(""), Err(LoginError::BadUsernameLength));
+        assert_eq!(validate_username("ab"), Err(LoginError::BadUsernameLength));
+        assert_eq!(validate_username("abcdefghijklmnopq"), Err(LoginError::BadUsernameLength));
+    }
+    
+    #[test]
+    fn rejects_usernames_with_other_characters() {
+        assert_eq!(validate_username("bob smith"), Err(LoginError::BadUsernameCharacters));
+        assert_eq!(validate_username("ai-haven_1"), Err(LoginError::BadUsernameCharacters));
+        assert_eq!(validate_username("bob!"), Err(LoginError::BadUsernameCharacters));
+        assert_eq!(validate_username("bøb"), Err(LoginError::BadUsernameCharacters));
+    }
+    
+    #[test]
+    fn rejects_reserved_usernames_in_any_case() {
+        assert_eq!(validate_username("admin"), Err(LoginError::UsernameReserved));
+        assert_eq!(validate_username("ADMIN"), Err(LoginError::UsernameReserved));
+        assert_eq!(validate_username("Thing1"), Err(LoginError::UsernameReserved));
+        assert_eq!(validate_username("n00bslayer808"), Err(LoginError::UsernameReserved));
+    }
+    
+    #[test]
+    fn checks_password_length() {
+        assert_eq!(validate_password("bob", "hunter22"), Ok(()));
+        assert_eq!(validate_password("bob", "short"), Err(LoginError::BadPasswordLength));
+        let longest: String = iter::repeat('a').take(64).collect();
+        let too_long: String = iter::repeat('a').take(65).collect();
+        assert_eq!(validate_password("bob", longest.as_slice()), Ok(()));
+        assert_eq!(validate_password("bob", too_long.as_slice()), Err(LoginError::BadPasswordLength));
+    }
+    
+    #[test]
+    fn rejects_the_username_as_password() {
+        assert_eq!(validate_password("spacebob", "spacebob"), Err(LoginError::PasswordIsUsername));
+        assert_eq!(validate_password("spacebob", "SpaceBob"), Err(LoginError::PasswordIsUsername));
+    }
+    
+    #[test]
+    fn makes_accounts_wait_after_too_many_wrong_passwords() {
+        let mut manager = AccountManager::new();
+        manager.create_account("bob".to_string(), "password1".to_string());
+        
+        for _ in 0..FREE_LOGIN_FAILURES {
+            let result = manager.login_account("bob".to_string(), "wrong".to_string(), 1);
+            assert_eq!(result.err(), Some(LoginError::WrongPassword));
+        }
+        let result = manager.login_account("bob".to_string(), "wrong".to_string(), 1);
+        assert_eq!(result.err(), Some(LoginError::WrongPassword));
+        
+        // Even the right password has to wait now
+        let result = manager.login_account("bob".to_string(), "password1".to_string(), 1);
+        assert_eq!(result.err(), Some(LoginError::TooManyFailures));
+    }
+}
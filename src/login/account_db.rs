@@ -0,0 +1,418 @@
+use std::collections::HashMap;
+use std::fs;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::{BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use time;
+
+use bincode::{encode_into, decode_from, SizeLimit};
+
+use sector_data::SectorId;
+use ship::{Ship, ShipStored};
+use super::Account;
+use super::password::PasswordHash;
+use super::progress::AccountProgress;
+
+// The log gets rewritten with only the newest entry for each account once it has this many times
+// more entries than there are accounts
+static COMPACT_RATIO: usize = 4;
+
+// Logs shorter than this never get rewritten, so small databases don't get rewritten all the time
+static MIN_COMPACT_LENGTH: usize = 100;
+
+// Version written at the start of every record. Bump this whenever the record's layout changes.
+// Records of any other version are skipped.
+static RECORD_VERSION: u32 = 4;
+
+pub type AccountDbRef = Arc<Mutex<AccountDb>>;
+
+// Everything saved about an account
+#[derive(RustcEncodable, RustcDecodable)]
+pub struct AccountRecord {
+    pub username: String,
+    pub password: PasswordHash,
+    pub ship: Option<ShipStored>,
+    pub sector: SectorId,
+    pub progress: AccountProgress,
+}
+
+impl AccountRecord {
+    /// Turns the record back into a logged out account.
+    pub fn to_account(self) -> Account {
+        Account {
+            username: self.username,
+            password: self.password,
+            ship: self.ship,
+            client_id: None,
+            sector: self.sector,
+            session_token: None,
+            progress: self.progress,
+        }
+    }
+}
+
+// Encodes the same as an AccountRecord, but borrows everything so accounts can be saved without
+// copying their ships
+#[derive(RustcEncodable)]
+struct AccountRecordRef<'a> {
+    username: &'a String,
+    password: &'a PasswordHash,
+    ship: Option<&'a ShipStored>,
+    sector: SectorId,
+    progress: &'a AccountProgress,
+}
+
+// One entry in the log file. An entry cut off by a crash fails to decode or doesn't match its
+// checksum, and everything from there on is ignored.
+#[derive(RustcEncodable, RustcDecodable)]
+struct LogEntry {
+    checksum: u32,
+    record: Vec<u8>, // Record version followed by the encoded AccountRecord
+}
+
+/// Keeps accounts in a file. Saving an account appends its record to the end of the file, so a
+/// crash can only ever lose the save that was being written. The newest record of each account
+/// is the one that counts. Every so often the file is rewritten with only the newest records, by
+/// writing a new file and moving it over the old one.
+pub struct AccountDb {
+    path: PathBuf,
+    file: File,
+    
+    // Newest encoded record of each account, by username
+    records: HashMap<String, Vec<u8>>,
+    
+    // Number of entries in the file
+    log_length: usize,
+}
+
+impl AccountDb {
+    /// Opens the database at the given path, creating it if it doesn't exist.
+    pub fn open(path: &Path) -> io::Result<AccountDb> {
+        let mut records = HashMap::new();
+        let mut damaged = false;
+        
+        if let Ok(file) = File::open(path) {
+            let mut reader = BufReader::new(file);
+            
+            while let Ok(entry) = decode_from(&mut reader, SizeLimit::Infinite) {
+                let entry: LogEntry = entry;
+                if checksum(entry.record.as_slice()) != entry.checksum {
+                    println!("Account database {} has a damaged entry, ignoring the rest of it", path.display());
+                    damaged = true;
+                    break;
+                }
+                
+                // One bad record shouldn't lock everyone else out of their accounts
+                let record =
+                    match decode_record(entry.record.as_slice()) {
+                        Ok(record) => record,
+                        Err(e) => {
+                            println!("Warning: skipping account record in {} that failed to decode: {}", path.display(), e);
+                            damaged = true;
+                            continue;
+                        },
+                    };
+                records.insert(record.username, entry.record);
+            }
+        }
+        
+        // Keep the damaged file around so whatever couldn't be read can still be recovered by hand
+        if damaged {
+            let backup_path = backup_path(path, time::get_time().sec);
+            if fs::metadata(&backup_path).is_ok() {
+                return Err(io::Error::new(io::ErrorKind::PathAlreadyExists, "Account database backup already exists",
+                                          Some(format!("{}", backup_path.display()))));
+            }
+            try!(fs::rename(path, &backup_path));
+            println!("Moved damaged account database to {}", backup_path.display());
+        }
+        
+        let file = try!(OpenOptions::new().write(true).append(true).create(true).open(path));
+        let mut db = AccountDb {
+            path: path.to_path_buf(),
+            file: file,
+            records: records,
+            log_length: 0,
+        };
+        
+        // Start off with a clean file, without anything a crash left at the end
+        try!(db.compact());
+        
+        Ok(db)
+    }
+    
+    /// Opens the database for sharing between threads.
+    pub fn open_shared(path: &Path) -> io::Result<AccountDbRef> {
+        let db = try!(AccountDb::open(path));
+        Ok(Arc::new(Mutex::new(db)))
+    }
+    
+    /// Decodes the newest record of every account.
+    pub fn load_records(&self) -> Vec<AccountRecord> {
+        self.records.values()
+            .map(|record| decode_record(record.as_slice()).ok().expect("Failed to decode account record"))
+            .collect()
+    }
+    
+    /// Saves an account with its ship in storage. It's on disk once this returns.
+    pub fn save(&mut self, account: &Account) -> io::Result<()> {
+        self.save_record(&AccountRecordRef {
+            username: &account.username,
+            password: &account.password,
+            ship: account.ship.as_ref(),
+            sector: account.sector,
+            progress: &account.progress,
+        })
+    }
+    
+    /// Saves an account whose ship is out in a sector.
+    pub fn save_in_sector(&mut self, account: &Account, ship: &Ship) -> io::Result<()> {
+        let ship = ShipStored::from_ship_ref(ship);
+        self.save_record(&AccountRecordRef {
+            username: &account.username,
+            password: &account.password,
+            ship: Some(&ship),
+            sector: account.sector,
+            progress: &account.progress,
+        })
+    }
+    
+    fn save_record(&mut self, record: &AccountRecordRef) -> io::Result<()> {
+        let data = try!(encode_record(record));
+        
+        try!(write_entry(&mut self.file, data.as_slice()));
+        try!(self.file.sync_data());
+        
+        self.records.insert(record.username.clone(), data);
+        self.log_length += 1;
+        
+        if self.log_length > MIN_COMPACT_LENGTH && self.log_length > self.records.len()*COMPACT_RATIO {
+            try!(self.compact());
+        }
+        
+        Ok(())
+    }
+    
+    // Rewrites the file with only the newest record of each account
+    fn compact(&mut self) -> io::Result<()> {
+        let mut temp_path = self.path.clone();
+        temp_path.set_extension("tmp");
+        
+        {
+            let mut temp_file = try!(File::create(&temp_path));
+            for record in self.records.values() {
+                try!(write_entry(&mut temp_file, record.as_slice()));
+            }
+            try!(temp_file.sync_all());
+        }
+        
+        // Moving the new file over the old one either happens completely or not at all
+        try!(fs::rename(&temp_path, &self.path));
+        try!(sync_dir(&self.path));
+        
+        self.file = try!(OpenOptions::new().write(true).append(true).open(&self.path));
+        self.log_length = self.records.len();
+        
+        Ok(())
+    }
+}
+
+// Where a damaged database found at the given time gets moved to. The time keeps one damaged
+// database from replacing the backup of an older one.
+fn backup_path(path: &Path, time: i64) -> PathBuf {
+    let mut backup_path = path.to_path_buf();
+    backup_path.set_extension(format!("{}.bak", time));
+    backup_path
+}
+
+// Makes sure a rename in the directory holding path is on disk, not just the file it renamed
+fn sync_dir(path: &Path) -> io::Result<()> {
+    let dir =
+        match path.parent() {
+            Some(dir) if dir != Path::new("") => dir,
+            _ => Path::new("."),
+        };
+    try!(File::open(dir)).sync_all()
+}
+
+fn encode_record(record: &AccountRecordRef) -> io::Result<Vec<u8>> {
+    let mut data = vec!();
+    try!(encode_into(&RECORD_VERSION, &mut data, SizeLimit::Infinite).and_then(|_| {
+        encode_into(record, &mut data, SizeLimit::Infinite)
+    }).map_err(|e| {
+        io::Error::new(io::ErrorKind::Other, "Failed to encode account record", Some(format!("{}", e)))
+    }));
+    Ok(data)
+}
+
+fn decode_record(data: &[u8]) -> io::Result<AccountRecord> {
+    let mut reader = data;
+    let version: u32 = try!(decode_from(&mut reader, SizeLimit::Infinite).map_err(|e| {
+        io::Error::new(io::ErrorKind::InvalidInput, "Failed to decode account record version", Some(format!("{}", e)))
+    }));
+    if version != RECORD_VERSION {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "Unknown account record version", Some(format!("{}", version))));
+    }
+    
+    decode_from(&mut reader, SizeLimit::Infinite).map_err(|e| {
+        io::Error::new(io::ErrorKind::InvalidInput, "Failed to decode account record", Some(format!("{}", e)))
+    })
+}
+
+fn write_entry<W: Write>(writer: &mut W, record: &[u8]) -> io::Result<()> {
+    let entry = LogEntry {
+        checksum: checksum(record),
+        record: record.to_vec(),
+    };
+    
+    // Encode into memory first so the whole entry goes out in one write
+    let mut data = vec!();
+    try!(encode_into(&entry, &mut data, SizeLimit::Infinite).map_err(|e| {
+        io::Error::new(io::ErrorKind::Other, "Failed to encode account database entry", Some(format!("{}", e)))
+    }));
+    writer.write_all(data.as_slice())
+}
+
+// Adler-32 checksum
+fn checksum(data: &[u8]) -> u32 {
+    let mut a = 1u32;
+    let mut b = 0u32;
+    for byte in data.iter() {
+        a = (a + *byte as u32) % 65521;
+        b = (b + a) % 65521;
+    }
+    (b << 16) | a
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use std::fs::{File, OpenOptions};
+    use std::io::Read;
+    use std::path::{Path, PathBuf};
+    use std::rand;
+    
+    use bincode::{encode_into, SizeLimit};
+    
+    use login::password::PasswordHash;
+    use login::progress::AccountProgress;
+    use sector_data::SectorId;
+    use super::{AccountDb, AccountRecord, LogEntry, RECORD_VERSION, checksum, write_entry};
+    
+    // A database path nothing else uses
+    fn temp_path(name: &str) -> PathBuf {
+        let mut path = env::temp_dir();
+        path.push(format!("reforge_{}_{}.db", name, rand::random::<u32>()));
+        path
+    }
+    
+    fn make_record(username: &str, sector: u32) -> AccountRecord {
+        AccountRecord {
+            username: username.to_string(),
+            password: PasswordHash::new("password"),
+            ship: None,
+            sector: SectorId(sector),
+            progress: AccountProgress::new(),
+        }
+    }
+    
+    fn read_file(path: &Path) -> Vec<u8> {
+        let mut data = vec!();
+        File::open(path).unwrap().read_to_end(&mut data).unwrap();
+        data
+    }
+    
+    // Backups of the database at the path
+    fn find_backups(path: &Path) -> Vec<PathBuf> {
+        let stem = path.file_stem().unwrap().to_str().unwrap().to_string();
+        fs::read_dir(&env::temp_dir()).unwrap()
+            .map(|entry| entry.unwrap().path())
+            .filter(|other| {
+                let name = other.file_name().unwrap().to_str().unwrap();
+                name.starts_with(format!("{}.", stem).as_slice()) && name.ends_with(".bak")
+            })
+            .collect()
+    }
+    
+    fn sector_of(records: &Vec<AccountRecord>, username: &str) -> Option<u32> {
+        records.iter().find(|record| record.username.as_slice() == username).map(|record| record.sector.0)
+    }
+    
+    #[test]
+    fn keeps_newest_record_of_each_account() {
+        let path = temp_path("newest");
+        {
+            let mut db = AccountDb::open(&path).unwrap();
+            db.save(&make_record("alice", 1).to_account()).unwrap();
+            db.save(&make_record("bob", 2).to_account()).unwrap();
+            db.save(&make_record("alice", 3).to_account()).unwrap();
+        }
+        
+        let records = AccountDb::open(&path).unwrap().load_records();
+        assert_eq!(records.len(), 2);
+        assert_eq!(sector_of(&records, "alice"), Some(3));
+        assert_eq!(sector_of(&records, "bob"), Some(2));
+        
+        fs::remove_file(&path);
+    }
+    
+    #[test]
+    fn ignores_everything_after_a_damaged_entry() {
+        let path = temp_path("damaged");
+        {
+            let mut db = AccountDb::open(&path).unwrap();
+            db.save(&make_record("alice", 1).to_account()).unwrap();
+        }
+        {
+            let mut file = OpenOptions::new().write(true).append(true).open(&path).unwrap();
+            let record = vec![1u8, 2, 3, 4];
+            encode_into(&LogEntry { checksum: checksum(record.as_slice()) + 1, record: record }, &mut file, SizeLimit::Infinite).unwrap();
+            
+            let mut data = vec!();
+            encode_into(&RECORD_VERSION, &mut data, SizeLimit::Infinite).unwrap();
+            encode_into(&make_record("bob", 2), &mut data, SizeLimit::Infinite).unwrap();
+            write_entry(&mut file, data.as_slice()).unwrap();
+        }
+        let damaged = read_file(&path);
+        
+        let records = AccountDb::open(&path).unwrap().load_records();
+        assert_eq!(records.len(), 1);
+        assert_eq!(sector_of(&records, "alice"), Some(1));
+        
+        // The damaged file is kept as it was
+        let backups = find_backups(&path);
+        assert_eq!(backups.len(), 1);
+        assert!(read_file(&backups[0]) == damaged);
+        
+        fs::remove_file(&path);
+        fs::remove_file(&backups[0]);
+    }
+    
+    #[test]
+    fn skips_records_that_fail_to_decode() {
+        let path = temp_path("version");
+        {
+            let mut db = AccountDb::open(&path).unwrap();
+            db.save(&make_record("alice", 1).to_account()).unwrap();
+        }
+        {
+            let mut file = OpenOptions::new().write(true).append(true).open(&path).unwrap();
+            let mut data = vec!();
+            encode_into(&999u32, &mut data, SizeLimit::Infinite).unwrap();
+            encode_into(&make_record("bob", 2), &mut data, SizeLimit::Infinite).unwrap();
+            write_entry(&mut file, data.as_slice()).unwrap();
+        }
+        
+        let records = AccountDb::open(&path).unwrap().load_records();
+        assert_eq!(records.len(), 1);
+        assert_eq!(sector_of(&records, "alice"), Some(1));
+        let backups = find_backups(&path);
+        assert_eq!(backups.len(), 1);
+        
+        fs::remove_file(&path);
+        fs::remove_file(&backups[0]);
+    }
+}
@@ -1,5 +1,27 @@
+use sector_data::SectorId;
+use super::{AccountProgress, LoginError, SessionToken};
+
 #[derive(RustcEncodable, RustcDecodable)]
-pub struct LoginPacket {
+pub enum LoginPacket {
+    Login(String, String),    // Log into an account (username, password)
+    Resume(SessionToken),     // Get back into a session after a dropped connection (session_token)
+    Register(String, String), // Create an account and log into it (username, password)
+}
+
+// Server's answer to a Login packet, sent in a LoginResult packet
+#[derive(RustcEncodable, RustcDecodable)]
+pub enum LoginResponse {
+    Success(AccountSummary), // Logged in. The star map and the player's sector come next.
+    Failure(LoginError),     // Not logged in or registered. The client can try again on the same connection.
+}
+
+// What a player gets told about their account when they log in
+#[derive(RustcEncodable, RustcDecodable)]
+pub struct AccountSummary {
     pub username: String,
-    pub password: String,
+    pub new_account: bool, // Whether the account was just created by this login
+    pub ship_name: String,
+    pub ship_level: u8,
+    pub sector: SectorId,
+    pub progress: AccountProgress,
 }
\ No newline at end of file
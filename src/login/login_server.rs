@@ -1,6 +1,10 @@
-use std::sync::mpsc::Sender;
+use std::sync::mpsc::{Receiver, Sender};
+use time;
 
+use battle_state::ClientPacketId;
 use net::{
+    ClientId,
+    OutPacket,
     ServerSlot,
     ServerSlotId,
     SlotInMsg,
@@ -10,51 +14,176 @@ use super::{
     AccountBox,
     AccountManager,
     LoginError,
+    SessionToken,
 };
-use super::LoginPacket;
+use super::{AccountSummary, LoginPacket, LoginResponse};
 use ship::{Ship, ShipId, ShipStored};
 
-pub fn run_login_server(slot: ServerSlot, star_map_slot_id: ServerSlotId, star_map_chan: Sender<AccountBox>) {
-    let mut account_manager = AccountManager::new();
+// Most password hashes the login server will work out in a second. Hashing is slow on purpose, so
+// this keeps a flood of logins from tying up the server.
+static MAX_HASHES_PER_SECOND: u32 = 20;
+
+// Admin commands for the login server. Each one carries a channel to say when it's done.
+pub enum LoginCommand {
+    CloseLogins(Sender<()>), // Stop letting anyone log in, for shutting down
+    Sync(Sender<()>),        // Answer once every account logged out before now has been taken back
+}
+
+pub fn run_login_server(mut account_manager: AccountManager, slot: ServerSlot, star_map_slot_id: ServerSlotId,
+                        star_map_chan: Sender<AccountBox>, resume_chan: Sender<(SessionToken, ClientId)>,
+                        logout_chan: Receiver<AccountBox>, command_chan: Receiver<LoginCommand>) {
+    // Set once the server starts shutting down
+    let mut logins_closed = false;
+    
+    // Password hashes worked out since the start of the current second
+    let mut hash_second = time::get_time().sec;
+    let mut hashes_this_second = 0;
+
+    let slot_receiver = slot.get_receiver();
 
     loop {
-        match slot.receive() {
+        let slot_msg =
+            select! {
+                account = logout_chan.recv() => {
+                    // Take back the accounts of clients that disconnected
+                    let account = account.ok().expect("Logout channel closed");
+                    println!("Account {} logged out", account.username);
+                    account_manager.logout_account(account);
+                    continue;
+                },
+                command = command_chan.recv() => {
+                    match command.ok().expect("Login command channel closed") {
+                        LoginCommand::CloseLogins(done) => {
+                            logins_closed = true;
+                            done.send(());
+                        },
+                        LoginCommand::Sync(done) => {
+                            // Everything logged out before the command was sent is already waiting
+                            while let Ok(account) = logout_chan.try_recv() {
+                                println!("Account {} logged out", account.username);
+                                account_manager.logout_account(account);
+                            }
+                            done.send(());
+                        },
+                    }
+                    continue;
+                },
+                slot_msg = slot_receiver.recv() => slot_msg.ok().expect("Login server slot closed")
+            };
+        
+        match slot_msg {
             SlotInMsg::Joined(client_id) => {
                 println!("Client {} logging in...", client_id);
             },
+            SlotInMsg::ReceivedPacket(client_id, _) if logins_closed => {
+                println!("Turning away client {}, the server is shutting down", client_id);
+                slot.disconnect_client(client_id);
+            },
             SlotInMsg::ReceivedPacket(client_id, mut packet) => {
-                let LoginPacket{username: username, password: password} = packet.read().ok().expect("Failed to receive login packet");
+                let (username, password, register) =
+                    match packet.read() {
+                        Ok(LoginPacket::Login(username, password)) => (username, password, false),
+                        Ok(LoginPacket::Register(username, password)) => (username, password, true),
+                        Ok(LoginPacket::Resume(session_token)) => {
+                            if account_manager.is_session_active(session_token) {
+                                // The account is still out in a sector. Let the star map send the client back to it.
+                                // The request has to get there before the client does, like accounts do.
+                                resume_chan.send((session_token, client_id));
+                                slot.transfer_client(client_id, star_map_slot_id);
+                            } else {
+                                send_resume_failed(&slot, client_id);
+                            }
+                            continue;
+                        },
+                        Err(e) => {
+                            println!("Received invalid login packet from client {}: {}", client_id, e);
+                            slot.disconnect_client(client_id);
+                            continue;
+                        },
+                    };
                 
-                match account_manager.login_account(username.clone(), password.clone(), client_id) {
-                    Ok(account) => {
-                        // Login ok
-                        slot.transfer_client(account.client_id.expect("This must have a client ID"), star_map_slot_id);
-                        star_map_chan.send(account);
-                    },
-                    Err(ref e) if *e == LoginError::NoSuchAccount => {
-                        // Account doesn't exist yet, make it
-                        account_manager.create_account(username.clone(), password.clone());
-                        
-                        // Log into the new account
-                        if let Ok(mut account) = account_manager.login_account(username.clone(), password.clone(), client_id) {
-                            // Create ships
-                            let player_ship = ShipStored::from_ship(Ship::generate(client_id as ShipId, username.clone(), 5));
-                            
-                            account.ship = Some(player_ship);
-                            
-                            slot.transfer_client(account.client_id.expect("This must have a client ID"), star_map_slot_id);
-                            star_map_chan.send(account);
-                            
-                        } else {
-                            panic!("Failed to log into newly created account");
+                // Registering hashes the password once to make the account and again to log in
+                let hashes = if register { 2 } else { 1 };
+                let now = time::get_time().sec;
+                if now != hash_second {
+                    hash_second = now;
+                    hashes_this_second = 0;
+                }
+                if hashes_this_second + hashes > MAX_HASHES_PER_SECOND {
+                    send_login_response(&slot, client_id, LoginResponse::Failure(LoginError::ServerBusy));
+                    continue;
+                }
+                hashes_this_second += hashes;
+                
+                let login_result =
+                    if register {
+                        account_manager.register_account(username.clone(), password.clone())
+                            .and_then(|_| account_manager.login_account(username.clone(), password.clone(), client_id))
+                    } else {
+                        account_manager.login_account(username.clone(), password.clone(), client_id)
+                    };
+                
+                match login_result {
+                    Ok(mut account) => {
+                        // Login ok. New accounts get their first ship.
+                        if account.ship.is_none() {
+                            account.ship = Some(ShipStored::from_ship(Ship::generate(client_id as ShipId, username.clone(), 5)));
                         }
+                        
+                        // Ship IDs only need to be unique among connected players, and a saved ship
+                        // could have any old client ID
+                        account.ship.as_mut().unwrap().id = client_id as ShipId;
+                        
+                        let summary = AccountSummary {
+                            username: account.username.clone(),
+                            new_account: register,
+                            ship_name: account.ship.as_ref().unwrap().name.clone(),
+                            ship_level: account.ship.as_ref().unwrap().level,
+                            sector: account.sector,
+                            progress: account.progress.clone(),
+                        };
+                        send_login_response(&slot, client_id, LoginResponse::Success(summary));
+                        send_session_token(&slot, &account);
+                        
+                        // The account has to get to the star map before its client does, so the star
+                        // map can tell whether it's still waiting on the account when the client disconnects.
+                        star_map_chan.send(account);
+                        slot.transfer_client(client_id, star_map_slot_id);
                     },
                     Err(e) => {
-                        // TODO tell the client a login error occurred.
+                        // Leave the client here so it can try again
+                        println!("Client {} failed to log in as {}: {}", client_id, username, e);
+                        send_login_response(&slot, client_id, LoginResponse::Failure(e));
                     },
                 }
             },
-            _ => {},
+            SlotInMsg::Disconnected(client_id) => {
+                // Accounts are only ever held here while logged out, so there's nothing to clean up.
+                println!("Client {} disconnected before logging in", client_id);
+            },
         }
     }
+}
+
+fn send_login_response(slot: &ServerSlot, client_id: ClientId, response: LoginResponse) {
+    let mut packet = OutPacket::new();
+    packet.write(&ClientPacketId::LoginResult).ok().expect("Failed to write login result packet ID");
+    packet.write(&response).ok().expect("Failed to write login response");
+    slot.send(client_id, packet);
+}
+
+fn send_session_token(slot: &ServerSlot, account: &AccountBox) {
+    let client_id = account.client_id.expect("This must have a client ID");
+    let session_token = account.session_token.expect("Logged in accounts must have a session token");
+    
+    let mut packet = OutPacket::new();
+    packet.write(&ClientPacketId::SessionToken).ok().expect("Failed to write session token packet ID");
+    packet.write(&session_token).ok().expect("Failed to write session token");
+    slot.send(client_id, packet);
+}
+
+pub fn send_resume_failed(slot: &ServerSlot, client_id: ClientId) {
+    let mut packet = OutPacket::new();
+    packet.write(&ClientPacketId::ResumeFailed).ok().expect("Failed to write resume failed packet ID");
+    slot.send(client_id, packet);
 }
\ No newline at end of file
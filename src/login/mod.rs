@@ -1,8 +1,15 @@
 pub use self::login_packet::*;
-pub use self::login_server::run_login_server;
-pub use self::account::{Account, AccountBox, AccountManager, LoginError};
+pub use self::login_server::{LoginCommand, run_login_server, send_resume_failed};
+pub use self::account::{Account, AccountBox, AccountManager, LoginError, SessionToken};
+pub use self::account::{validate_password, validate_username};
+pub use self::account_db::{AccountDb, AccountDbRef, AccountRecord};
+pub use self::password::PasswordHash;
+pub use self::progress::AccountProgress;
 
 mod login_packet;
 mod login_server;
 
-mod account;
\ No newline at end of file
+mod account;
+mod account_db;
+mod password;
+mod progress;
\ No newline at end of file
@@ -0,0 +1,93 @@
+use std::rand::{OsRng, Rng};
+
+use crypto::hmac::Hmac;
+use crypto::pbkdf2::pbkdf2;
+use crypto::sha2::Sha256;
+use crypto::util::fixed_time_eq;
+
+// PBKDF2 rounds for new hashes. Raising this makes older hashes get redone at their next login.
+static ITERATIONS: u32 = 20000;
+
+static SALT_LENGTH: usize = 16;
+static HASH_LENGTH: usize = 32;
+
+/// A salted PBKDF2-HMAC-SHA256 hash of a password. The password itself is never kept.
+#[derive(Clone, RustcEncodable, RustcDecodable)]
+pub struct PasswordHash {
+    salt: Vec<u8>,
+    iterations: u32,
+    hash: Vec<u8>,
+}
+
+impl PasswordHash {
+    /// Hashes a password with a new random salt. This is slow on purpose.
+    pub fn new(password: &str) -> PasswordHash {
+        let mut rng = OsRng::new().ok().expect("Failed to open OS random number generator");
+        let mut salt = vec![0u8; SALT_LENGTH];
+        rng.fill_bytes(salt.as_mut_slice());
+        
+        let hash = hash_password(password, salt.as_slice(), ITERATIONS);
+        
+        PasswordHash {
+            salt: salt,
+            iterations: ITERATIONS,
+            hash: hash,
+        }
+    }
+    
+    /// Returns true if the password is the one that was hashed. Takes the same time no matter
+    /// how much of the hash matches.
+    pub fn verify(&self, password: &str) -> bool {
+        let hash = hash_password(password, self.salt.as_slice(), self.iterations);
+        fixed_time_eq(hash.as_slice(), self.hash.as_slice())
+    }
+    
+    /// Returns true if the hash is weaker than new hashes and should be redone.
+    pub fn is_outdated(&self) -> bool {
+        self.iterations < ITERATIONS
+    }
+}
+
+fn hash_password(password: &str, salt: &[u8], iterations: u32) -> Vec<u8> {
+    let mut mac = Hmac::new(Sha256::new(), password.as_bytes());
+    let mut hash = vec![0u8; HASH_LENGTH];
+    pbkdf2(&mut mac, salt, iterations, hash.as_mut_slice());
+    hash
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ITERATIONS, PasswordHash, hash_password};
+    
+    #[test]
+    fn verifies_only_the_hashed_password() {
+        let hash = PasswordHash::new("hunter22");
+        assert!(hash.verify("hunter22"));
+        assert!(!hash.verify("hunter23"));
+        assert!(!hash.verify("Hunter22"));
+        assert!(!hash.verify(""));
+    }
+    
+    #[test]
+    fn salts_every_hash() {
+        let first = PasswordHash::new("hunter22");
+        let second = PasswordHash::new("hunter22");
+        assert!(first.salt != second.salt);
+        assert!(first.hash != second.hash);
+        assert!(first.verify("hunter22") && second.verify("hunter22"));
+    }
+    
+    #[test]
+    fn weaker_hashes_are_outdated() {
+        assert!(!PasswordHash::new("hunter22").is_outdated());
+        
+        let salt = vec![7u8; 16];
+        let old_hash = PasswordHash {
+            salt: salt.clone(),
+            iterations: ITERATIONS / 2,
+            hash: hash_password("hunter22", salt.as_slice(), ITERATIONS / 2),
+        };
+        assert!(old_hash.is_outdated());
+        assert!(old_hash.verify("hunter22"));
+    }
+}
@@ -0,0 +1,76 @@
+// Credits and experience for destroying a ship, per level of the destroyed ship
+static KILL_CREDITS_PER_LEVEL: u64 = 100;
+static KILL_EXPERIENCE_PER_LEVEL: u64 = 50;
+
+// Experience for each point of damage dealt
+static DAMAGE_EXPERIENCE: u64 = 2;
+
+// Experience needed to go from level 1 to 2. Each level after that needs this much more than the last.
+static LEVEL_EXPERIENCE_STEP: u64 = 200;
+
+/// Everything an account has earned and done over its lifetime.
+#[derive(Clone, RustcEncodable, RustcDecodable)]
+pub struct AccountProgress {
+    pub credits: u64,
+    pub experience: u64,
+    
+    // Lifetime stats
+    pub kills: u32,
+    pub deaths: u32,
+    pub damage_dealt: u64,
+    pub jumps: u32,
+}
+
+impl AccountProgress {
+    pub fn new() -> AccountProgress {
+        AccountProgress {
+            credits: 0,
+            experience: 0,
+            
+            kills: 0,
+            deaths: 0,
+            damage_dealt: 0,
+            jumps: 0,
+        }
+    }
+    
+    /// Level the account's experience puts it at. Accounts start at level 1.
+    pub fn get_level(&self) -> u32 {
+        let mut level = 1;
+        while self.experience >= experience_for_level(level + 1) {
+            level += 1;
+        }
+        level
+    }
+    
+    /// Experience still needed to reach the next level.
+    pub fn get_experience_to_next_level(&self) -> u64 {
+        experience_for_level(self.get_level() + 1) - self.experience
+    }
+    
+    /// Rewards destroying a ship of the given level.
+    pub fn add_kill(&mut self, ship_level: u8) {
+        self.kills += 1;
+        self.credits += KILL_CREDITS_PER_LEVEL*(ship_level as u64);
+        self.experience += KILL_EXPERIENCE_PER_LEVEL*(ship_level as u64);
+    }
+    
+    pub fn add_damage_dealt(&mut self, damage: u8) {
+        self.damage_dealt += damage as u64;
+        self.experience += DAMAGE_EXPERIENCE*(damage as u64);
+    }
+    
+    pub fn add_death(&mut self) {
+        self.deaths += 1;
+    }
+    
+    pub fn add_jump(&mut self) {
+        self.jumps += 1;
+    }
+}
+
+// Total experience needed to reach a level
+fn experience_for_level(level: u32) -> u64 {
+    let level = level as u64;
+    LEVEL_EXPERIENCE_STEP*(level - 1)*level/2
+}
@@ -9,10 +9,17 @@ use opengl_graphics::{Gl, Texture};
 use opengl_graphics::glyph_cache::GlyphCache;
 
 use gui::{TextBox, TextButton};
+use login::{LoginPacket, validate_password, validate_username};
 
 pub struct LoginScreen {
     done: bool,
-    login_info: Option<(String, String)>,
+    login_info: Option<LoginPacket>,
+    
+    // Whether the player is making a new account instead of logging in
+    registering: bool,
+    
+    // Message to show the player, like why the last login attempt failed
+    message: Option<String>,
 
     mouse_x: f64,
     mouse_y: f64,
@@ -20,10 +27,12 @@ pub struct LoginScreen {
     // Text boxes
     username_box: TextBox,
     password_box: TextBox,
+    confirm_password_box: TextBox, // Only used when registering
     
     // Buttons
     cancel_button: TextButton,
     login_button: TextButton,
+    mode_button: TextButton, // Switches between logging in and registering
 }
 
 impl LoginScreen {
@@ -32,18 +41,33 @@ impl LoginScreen {
             done: false,
             login_info: None,
             
+            registering: false,
+            
+            message: None,
+            
             mouse_x: 0.0,
             mouse_y: 0.0,
             
             username_box: TextBox::new("user".to_string(), 20, [600.0, 300.0], [300.0, 40.0]),
             password_box: TextBox::new("pass".to_string(), 20, [600.0, 370.0], [300.0, 40.0]),
+            confirm_password_box: TextBox::new(String::new(), 20, [600.0, 440.0], [300.0, 40.0]),
             
             cancel_button: TextButton::new("Cancel".to_string(), 20, [450.0, 500.0], [150.0, 40.0]),
             login_button: TextButton::new("Login".to_string(), 20, [610.0, 500.0], [150.0, 40.0]),
+            mode_button: TextButton::new("Register".to_string(), 20, [770.0, 500.0], [150.0, 40.0]),
         }
     }
 
-    pub fn run(mut self, window: &Rc<RefCell<Sdl2Window>>, gl: &mut Gl, glyph_cache: &mut GlyphCache, bg_texture: &Texture) -> Option<(String, String)> {
+    pub fn set_message(&mut self, message: String) {
+        self.message = Some(message);
+    }
+
+    /// Shows the login screen until the player logs in, registers or cancels. Returns the login or
+    /// register packet to send to the server.
+    pub fn run(&mut self, window: &Rc<RefCell<Sdl2Window>>, gl: &mut Gl, glyph_cache: &mut GlyphCache, bg_texture: &Texture) -> Option<LoginPacket> {
+        // Start fresh in case the login screen is being shown again
+        self.done = false;
+        
         // Main loop
         for e in Events::new(window.clone()) {
             use event;
@@ -66,7 +90,7 @@ impl LoginScreen {
             }
         }
         
-        self.login_info
+        self.login_info.take()
     }
 
     pub fn event<E: GenericEvent>(&mut self, e: &E) {
@@ -87,19 +111,60 @@ impl LoginScreen {
         // Handle text boxes
         self.username_box.event(e, [self.mouse_x, self.mouse_y]);
         self.password_box.event(e, [self.mouse_x, self.mouse_y]);
+        if self.registering {
+            self.confirm_password_box.event(e, [self.mouse_x, self.mouse_y]);
+        }
         
         // Handle buttons
         self.login_button.event(e, [self.mouse_x, self.mouse_y]);
         self.cancel_button.event(e, [self.mouse_x, self.mouse_y]);
+        self.mode_button.event(e, [self.mouse_x, self.mouse_y]);
         
         if self.cancel_button.get_clicked() {
             self.done = true;
             self.login_info = None;
         }
         
+        if self.mode_button.get_clicked() {
+            self.set_registering(!self.registering);
+        }
+        
         if self.login_button.get_clicked() {
-            self.done = true;
-            self.login_info = Some((self.username_box.text.clone(), self.password_box.text.clone()));
+            let username = self.username_box.text.clone();
+            let password = self.password_box.text.clone();
+            
+            if self.registering {
+                // Catch mistakes here instead of waiting on the server. The server checks again.
+                let valid =
+                    validate_username(username.as_slice())
+                        .and_then(|_| validate_password(username.as_slice(), password.as_slice()));
+                
+                if let Err(e) = valid {
+                    self.message = Some(format!("{}", e));
+                } else if password != self.confirm_password_box.text {
+                    self.message = Some("Passwords don't match".to_string());
+                } else {
+                    self.done = true;
+                    self.login_info = Some(LoginPacket::Register(username, password));
+                }
+            } else {
+                self.done = true;
+                self.login_info = Some(LoginPacket::Login(username, password));
+            }
+        }
+    }
+    
+    fn set_registering(&mut self, registering: bool) {
+        self.registering = registering;
+        self.message = None;
+        self.confirm_password_box.text = String::new();
+        
+        if registering {
+            self.login_button.text = "Register".to_string();
+            self.mode_button.text = "Back".to_string();
+        } else {
+            self.login_button.text = "Login".to_string();
+            self.mode_button.text = "Register".to_string();
         }
     }
 
@@ -147,12 +212,37 @@ impl LoginScreen {
             );
         }
         
+        if self.registering {
+            let context = context.trans(400.0, 470.0);
+            Text::colored([1.0; 4], 30).draw(
+                "Confirm",
+                glyph_cache,
+                &context.draw_state, context.transform,
+                gl,
+            );
+        }
+        
+        // Draw the message, if there is one
+        if let Some(ref message) = self.message {
+            let context = context.trans(400.0, 570.0);
+            Text::colored([1.0, 0.2, 0.2, 1.0], 15).draw(
+                message.as_slice(),
+                glyph_cache,
+                &context.draw_state, context.transform,
+                gl,
+            );
+        }
+        
         // Draw the text boxes
         self.username_box.draw(context, gl, glyph_cache);
         self.password_box.draw(context, gl, glyph_cache);
+        if self.registering {
+            self.confirm_password_box.draw(context, gl, glyph_cache);
+        }
         
         // Draw the buttons
         self.cancel_button.draw(context, gl, glyph_cache);
         self.login_button.draw(context, gl, glyph_cache);
+        self.mode_button.draw(context, gl, glyph_cache);
     }
 }
\ No newline at end of file
@@ -6,6 +6,8 @@ use std::rand;
 use std::rand::Rng;
 use std::marker::Reflect;
 
+use rustc_serialize::{Decodable, Decoder, Encodable, Encoder};
+
 use battle_state::BattleContext;
 use net::{InPacket, OutPacket};
 use ship::{ShipId, ShipRef, ShipState};
@@ -29,6 +31,8 @@ pub use self::target::{NetworkTarget, NetworkTargetData, Target, TargetMode, Tar
 pub use self::damage_visual::{DamageVisual, DamageVisualKind};
 pub use self::module_networked::{IModuleNetworked, ModuleBaseNetworked, ModuleNetworked, ModuleNetworkedBox};
 
+use self::module_networked::ModuleClass;
+
 pub mod engine;
 pub mod proj_weapon;
 pub mod shield;
@@ -194,6 +198,10 @@ pub struct ModuleStored<M: IModule> {
 }
 
 pub trait IModuleStored : Send {
+    fn get_type_id(&self) -> TypeId;
+    fn get_base(&self) -> &ModuleBaseStored;
+    fn get_module(&self) -> &IModule;
+    
     fn to_module(&self) -> ModuleBox;
 }
 
@@ -208,6 +216,18 @@ impl ModuleStoredBox {
 impl<M> IModuleStored for ModuleStored<M>
     where M: IModule+Reflect+Clone + 'static
 {   
+    fn get_type_id(&self) -> TypeId {
+        TypeId::of::<M>()
+    }
+    
+    fn get_base(&self) -> &ModuleBaseStored {
+        &self.base
+    }
+    
+    fn get_module(&self) -> &IModule {
+        &self.module
+    }
+    
     fn to_module(&self) -> ModuleBox {
         let base = self.base.to_module_base();
     
@@ -231,6 +251,86 @@ impl DerefMut for ModuleStoredBox {
     }
 }
 
+// Stored modules are written the same way as networked ones: the module's class, then its base,
+// then the module itself
+impl Decodable for ModuleStoredBox {
+    fn decode<D: Decoder>(d: &mut D) -> Result<ModuleStoredBox, D::Error> {
+        use self::module_networked::ModuleClass::*;
+        
+        let module_class: ModuleClass = try!(Decodable::decode(d));
+        let base: ModuleBaseStored = try!(Decodable::decode(d));
+        
+        match module_class {
+            ProjectileWeapon => Ok(ModuleStoredBox::new(ModuleStored {
+                base: base,
+                module: try!(<ProjectileWeaponModule as Decodable>::decode(d)),
+            })),
+            Shield => Ok(ModuleStoredBox::new(ModuleStored {
+                base: base,
+                module: try!(<ShieldModule as Decodable>::decode(d)),
+            })),
+            Engine => Ok(ModuleStoredBox::new(ModuleStored {
+                base: base,
+                module: try!(<EngineModule as Decodable>::decode(d)),
+            })),
+            Solar => Ok(ModuleStoredBox::new(ModuleStored {
+                base: base,
+                module: try!(<SolarModule as Decodable>::decode(d)),
+            })),
+            Command => Ok(ModuleStoredBox::new(ModuleStored {
+                base: base,
+                module: try!(<CommandModule as Decodable>::decode(d)),
+            })),
+            BeamWeapon => Ok(ModuleStoredBox::new(ModuleStored {
+                base: base,
+                module: try!(<BeamWeaponModule as Decodable>::decode(d)),
+            })),
+        }
+    }
+}
+
+impl Encodable for ModuleStoredBox {
+    fn encode<S: Encoder>(&self, s: &mut S) -> Result<(), S::Error> {
+        use std::mem;
+        use std::raw;
+        
+        use self::module_networked::ModuleClass::*;
+        
+        let module_class = ModuleClass::from_type_id(self.get_type_id());
+    
+        try!(module_class.encode(s));
+        try!(self.get_base().encode(s));
+        
+        match module_class {
+            ProjectileWeapon => unsafe {
+                let to: raw::TraitObject = mem::transmute(self.get_module());
+                try!(<ProjectileWeaponModule as Encodable>::encode(mem::transmute(to.data), s));
+            },
+            Shield => unsafe {
+                let to: raw::TraitObject = mem::transmute(self.get_module());
+                try!(<ShieldModule as Encodable>::encode(mem::transmute(to.data), s));
+            },
+            Engine => unsafe {
+                let to: raw::TraitObject = mem::transmute(self.get_module());
+                try!(<EngineModule as Encodable>::encode(mem::transmute(to.data), s));
+            },
+            Solar => unsafe {
+                let to: raw::TraitObject = mem::transmute(self.get_module());
+                try!(<SolarModule as Encodable>::encode(mem::transmute(to.data), s));
+            },
+            Command => unsafe {
+                let to: raw::TraitObject = mem::transmute(self.get_module());
+                try!(<CommandModule as Encodable>::encode(mem::transmute(to.data), s));
+            },
+            BeamWeapon => unsafe {
+                let to: raw::TraitObject = mem::transmute(self.get_module());
+                try!(<BeamWeaponModule as Encodable>::encode(mem::transmute(to.data), s));
+            },
+        }
+        Ok(())
+    }
+}
+
 ////////////////////////////////////////////////////////////////////////////////////////////////////
 
 // Some downcasting helper methods
@@ -361,22 +461,27 @@ impl ModuleBase {
         
         // Create damage visual at random location
         if self.hp < self.min_hp {
-            // Random number generater
-            let mut rng = rand::thread_rng();
-            
-            let x = rng.gen::<f64>() * ((self.width as f64) * 48.0);
-            let y = rng.gen::<f64>() * ((self.height as f64) * 48.0);
-
-            self.damage_visuals.push(DamageVisual {
-                x: x,
-                y: y,
-                kind: DamageVisualKind::Fire,
-            });
+            self.add_damage_visual(DamageVisualKind::Fire);
         }
         
         dealt_damage
     }
     
+    // Shows damage of some kind at a random spot on the module
+    pub fn add_damage_visual(&mut self, kind: DamageVisualKind) {
+        // Random number generater
+        let mut rng = rand::thread_rng();
+        
+        let x = rng.gen::<f64>() * ((self.width as f64) * 48.0);
+        let y = rng.gen::<f64>() * ((self.height as f64) * 48.0);
+
+        self.damage_visuals.push(DamageVisual {
+            x: x,
+            y: y,
+            kind: kind,
+        });
+    }
+    
     #[cfg(feature = "client")]
     pub fn add_damage_effects(&self, asset_store: &AssetStore, effects: &mut SimEffects, ship_id: ShipId) {
         use sim_visuals::SpriteVisual;
@@ -459,6 +564,7 @@ impl ModuleBase {
 
 ////////////////////////////////////////////////////////////////////////////////////////////////////
 
+#[derive(RustcEncodable, RustcDecodable)]
 pub struct ModuleBaseStored {
     // Module position/size stuff
     pub x: u8,
@@ -163,8 +163,10 @@ impl DerefMut for ModuleNetworkedBox {
 ////////////////////////////////////////////////////////////////////////////////////////////////////
 // Serialization
 
+// Which kind of module is in a box, written before the module itself. Saved ships refer to these
+// by position, so new kinds go at the end.
 #[derive(RustcEncodable, RustcDecodable)]
-enum ModuleClass {
+pub enum ModuleClass {
     ProjectileWeapon,
     Shield,
     Engine,
@@ -173,6 +175,20 @@ enum ModuleClass {
     BeamWeapon,
 }
 
+impl ModuleClass {
+    pub fn from_type_id(type_id: TypeId) -> ModuleClass {
+        use self::ModuleClass::*;
+        
+        if type_id == TypeId::of::<ProjectileWeaponModule>() { ProjectileWeapon }
+        else if type_id == TypeId::of::<ShieldModule>() { Shield }
+        else if type_id == TypeId::of::<EngineModule>() { Engine }
+        else if type_id == TypeId::of::<SolarModule>() { Solar }
+        else if type_id == TypeId::of::<CommandModule>() { Command }
+        else if type_id == TypeId::of::<BeamWeaponModule>() { BeamWeapon }
+        else { unreachable!() }
+    }
+}
+
 impl Decodable for ModuleNetworkedBox {
     fn decode<D: Decoder>(d: &mut D) -> Result<ModuleNetworkedBox, D::Error> {
         use self::ModuleClass::*;
@@ -216,16 +232,7 @@ impl Encodable for ModuleNetworkedBox {
         
         use self::ModuleClass::*;
         
-        let type_id = self.get_type_id();
-        
-        let module_class =
-            if type_id == TypeId::of::<ProjectileWeaponModule>() { ProjectileWeapon }
-            else if type_id == TypeId::of::<ShieldModule>() { Shield }
-            else if type_id == TypeId::of::<EngineModule>() { Engine }
-            else if type_id == TypeId::of::<SolarModule>() { Solar }
-            else if type_id == TypeId::of::<CommandModule>() { Command }
-            else if type_id == TypeId::of::<BeamWeaponModule>() { BeamWeapon }
-            else { unreachable!() };
+        let module_class = ModuleClass::from_type_id(self.get_type_id());
     
         try!(module_class.encode(s));
         try!(self.get_base().encode(s));
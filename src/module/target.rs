@@ -45,6 +45,15 @@ impl NetworkTarget {
         }
     }
     
+    // Checks that the target points at things that exist, so to_target won't panic. Anything from
+    // a client needs to be checked first.
+    pub fn is_valid(&self, context: &BattleContext) -> bool {
+        match context.ships.get(&self.ship) {
+            Some(ship) => self.data.is_valid(ship),
+            None => false,
+        }
+    }
+    
     pub fn to_target(&self, context: &BattleContext) -> Target {
         let ship = context.get_ship(self.ship);
     
@@ -77,6 +86,16 @@ impl NetworkTargetData {
         }
     }
     
+    pub fn is_valid(&self, ship: &ShipRef) -> bool {
+        match self {
+            &NetworkTargetData::TargetShip => true,
+            &NetworkTargetData::TargetModule(module_index) |
+            &NetworkTargetData::OwnModule(module_index) |
+            &NetworkTargetData::AnyModule(module_index) => (module_index as usize) < ship.borrow().modules.len(),
+            &NetworkTargetData::Beam(_, _) => true,
+        }
+    }
+    
     pub fn to_target_data(&self, ship: &ShipRef) -> TargetData {
         match self {
             &NetworkTargetData::TargetShip => TargetData::TargetShip,
@@ -1,14 +1,27 @@
 use std::collections::HashMap;
+use std::fmt;
+use std::fs::File;
 use std::io;
-use std::io::{Read, Write};
-use std::net::{TcpListener, TcpStream};
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::net::{Shutdown, TcpListener, TcpStream};
+use std::path::Path;
+use std::old_io::timer::Timer;
 use std::result::Result;
+use std::sync::{Arc, Mutex};
 use std::sync::mpsc::{channel, Receiver, Sender, TryRecvError};
 use std::thread::{Builder, Thread};
+use std::time::Duration;
+use time;
 
 use rustc_serialize::Encodable;
 use rustc_serialize::Decodable;
 
+use flate2::Compression;
+use flate2::read::ZlibDecoder;
+use flate2::write::ZlibEncoder;
+
+use net_sim::{NetworkConditions, simulate_link};
+
 use bincode::{EncoderWriter, EncodingError, DecoderReader, DecodingError, encode_into, decode_from, SizeLimit};
 
 ///////////////////////////////////////////////////////////////////////////////////////////////////
@@ -18,6 +31,111 @@ pub type ClientId = u32;
 
 pub type ServerSlotId = u32;
 
+// Bit set of optional protocol features
+pub type FeatureSet = u32;
+
+// Time between heartbeat pings from the server, in milliseconds
+static HEARTBEAT_INTERVAL: i64 = 1000;
+
+// Default time a connection can go quiet before it's considered dead, in milliseconds
+pub static DEFAULT_HEARTBEAT_TIMEOUT: i64 = 10000;
+
+// Biggest packet a client is allowed to send, in bytes. Clients only ever send small packets like
+// plans, so anything bigger is junk.
+pub static MAX_CLIENT_PACKET_SIZE: u64 = 4096;
+
+// Biggest packet the server is allowed to send, in bytes. This is as big as a frame can hold.
+pub static MAX_SERVER_PACKET_SIZE: u64 = 65535;
+
+// How long an incoming connection gets to finish the handshake before it's hung up on, in milliseconds
+static HANDSHAKE_TIMEOUT: i64 = 5000;
+
+// Most packets a client can send per second before it gets disconnected for flooding
+static MAX_PACKETS_PER_SECOND: u32 = 50;
+
+///////////////////////////////////////////////////////////////////////////////////////////////////
+// Handshake
+
+// Bump this whenever a change to the packets would break older clients or servers
+pub static PROTOCOL_VERSION: u32 = 12;
+
+// Optional protocol features. Each feature is one bit.
+pub static FEATURE_COMPRESSION: FeatureSet = 1; // Big frames can be sent compressed
+
+// Optional protocol features this build supports
+pub static SUPPORTED_FEATURES: FeatureSet = FEATURE_COMPRESSION;
+
+// First packet a client sends after connecting
+#[derive(RustcEncodable, RustcDecodable)]
+struct ClientHello {
+    version: u32,
+    features: FeatureSet,
+}
+
+// Server's answer to a ClientHello
+#[derive(RustcEncodable, RustcDecodable)]
+enum ServerHello {
+    Accepted(FeatureSet), // Features to use for this connection (both sides support them)
+    Rejected(String),     // Reason the client can't connect
+}
+
+pub enum ConnectError {
+    Io(io::Error),    // Couldn't talk to the server
+    Rejected(String), // Server refused the connection
+}
+
+impl fmt::Display for ConnectError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ConnectError::Io(ref e) => write!(f, "Failed to connect to server: {}", e),
+            ConnectError::Rejected(ref reason) => write!(f, "Server rejected connection: {}", reason),
+        }
+    }
+}
+
+// Runs the server side of the handshake. Returns the negotiated features if the client is compatible.
+fn server_handshake(stream: &mut TcpStream) -> io::Result<Option<FeatureSet>> {
+    let mut packet = try!(InPacket::try_new_from_reader(stream, MAX_CLIENT_PACKET_SIZE));
+    let hello: ClientHello = try!(packet.read().map_err(decoding_to_io_error));
+    
+    let (reply, features) =
+        if hello.version == PROTOCOL_VERSION {
+            let features = hello.features & SUPPORTED_FEATURES;
+            (ServerHello::Accepted(features), Some(features))
+        } else {
+            let reason = format!("Client protocol version is {}, server needs version {}. Please update your game.",
+                                 hello.version, PROTOCOL_VERSION);
+            (ServerHello::Rejected(reason), None)
+        };
+    
+    let mut packet = OutPacket::new();
+    try!(packet.write(&reply).map_err(encoding_to_io_error));
+    try!(write_packet(stream, &packet));
+    
+    Ok(features)
+}
+
+// Runs the client side of the handshake and returns the negotiated features.
+fn client_handshake(stream: &mut TcpStream) -> Result<FeatureSet, ConnectError> {
+    let mut packet = OutPacket::new();
+    packet.write(&ClientHello{version: PROTOCOL_VERSION, features: SUPPORTED_FEATURES})
+        .ok().expect("Failed to write ClientHello");
+    if let Err(e) = write_packet(stream, &packet) {
+        return Err(ConnectError::Io(e));
+    }
+    
+    let mut packet =
+        match InPacket::try_new_from_reader(stream, MAX_SERVER_PACKET_SIZE) {
+            Ok(packet) => packet,
+            Err(e) => { return Err(ConnectError::Io(e)); },
+        };
+    match packet.read() {
+        Ok(ServerHello::Accepted(features)) => Ok(features),
+        Ok(ServerHello::Rejected(reason)) => Err(ConnectError::Rejected(reason)),
+        Err(e) => Err(ConnectError::Io(decoding_to_io_error(e))),
+    }
+}
+
 ///////////////////////////////////////////////////////////////////////////////////////////////////
 // Server Slot
 
@@ -34,6 +152,20 @@ pub enum SlotOutMsg {
     BroadcastPacket(ServerSlotId, OutPacket),             // Send packet to all clients in slot (my_slot_id, packet)
     CreateSlot(ServerSlotId),                             // Tell the server to make a new ServerSlot (slot_id)
     TransferClient(ServerSlotId, ClientId, ServerSlotId), // Tell the server to transfer a client to a different slot
+    DisconnectClient(ServerSlotId, ClientId),             // Tell the server to kick a misbehaving client (my_slot_id, client_id)
+    
+    // Admin messages, which can reach clients in any slot
+    ListClients(Sender<Vec<ClientInfo>>), // Ask the server about all the connected clients (reply channel)
+    KickClient(ClientId),                 // Tell the server to kick a client no matter what slot it's in (client_id)
+    BroadcastAll(OutPacket),              // Send a packet to every connected client (packet)
+    Shutdown(Sender<()>),                 // Tell the server to disconnect everyone and stop taking new connections (done channel)
+}
+
+// What the server master task knows about a connected client, for admins
+pub struct ClientInfo {
+    pub client_id: ClientId,
+    pub slot_id: ServerSlotId,
+    pub latency: Option<u32>, // Round trip time in milliseconds
 }
 
 pub struct ServerSlot {
@@ -69,6 +201,11 @@ impl ServerSlot {
         self.receiver.try_recv()
     }
     
+    // For waiting on the slot together with other channels, like with select!
+    pub fn get_receiver(&self) -> &Receiver<SlotInMsg> {
+        &self.receiver
+    }
+    
     pub fn create_slot(&self) -> ServerSlot {
         self.sender.send(SlotOutMsg::CreateSlot(self.id));
         match self.create_slot.recv() {
@@ -82,6 +219,37 @@ impl ServerSlot {
         self.sender.send(SlotOutMsg::TransferClient(self.id, client_id, to_slot));
     }
     
+    // Drop a client's connection, like when it sends garbage. The slot still gets a Disconnected
+    // message for the client afterwards.
+    pub fn disconnect_client(&self, client_id: ClientId) {
+        self.sender.send(SlotOutMsg::DisconnectClient(self.id, client_id));
+    }
+    
+    /// Lists every client connected to the server, no matter what slot it's in.
+    pub fn list_clients(&self) -> Vec<ClientInfo> {
+        let (reply_t, reply_r) = channel();
+        self.sender.send(SlotOutMsg::ListClients(reply_t));
+        reply_r.recv().ok().expect("Failed to receive client list")
+    }
+    
+    /// Drops a client's connection no matter what slot it's in. Only for admins, slots should use
+    /// `disconnect_client` for their own clients.
+    pub fn kick_client(&self, client_id: ClientId) {
+        self.sender.send(SlotOutMsg::KickClient(client_id));
+    }
+    
+    /// Sends a packet to every client connected to the server.
+    pub fn broadcast_all(&self, packet: OutPacket) {
+        self.sender.send(SlotOutMsg::BroadcastAll(packet));
+    }
+    
+    /// Disconnects everyone and stops the server from taking new connections. Returns once it's done.
+    pub fn shutdown_server(&self) {
+        let (done_t, done_r) = channel();
+        self.sender.send(SlotOutMsg::Shutdown(done_t));
+        done_r.recv();
+    }
+    
     pub fn create_slot_and_transfer_clients(&self, clients: &Vec<ClientId>) -> ServerSlot {
         let new_slot = self.create_slot();
         
@@ -100,6 +268,21 @@ impl ServerSlot {
 ///////////////////////////////////////////////////////////////////////////////////////////////////
 // Server
 
+// A client's connection to the server, as handed to the server master task
+enum Connection {
+    // Client connected over the network. (stream, negotiated features)
+    Tcp(TcpStream, FeatureSet),
+    
+    // Client living in the same process as the server. (client ID channel, to-client packets, from-client packets, client's status)
+    Local(Sender<ClientId>, Sender<io::Result<InPacket>>, Receiver<InPacket>, Arc<Mutex<ConnectionStatus>>),
+}
+
+// Lets clients in the same process connect to a server without going through the network
+#[derive(Clone)]
+pub struct LocalConnector {
+    new_connection_t: Sender<Connection>,
+}
+
 pub struct Server {
     // Server slots. Maps slot ID to communication channels with slot
     slots: HashMap<ServerSlotId, (Sender<SlotInMsg>, Sender<ServerSlot>)>,
@@ -108,21 +291,44 @@ pub struct Server {
     slot_channel_t: Sender<SlotOutMsg>,
     slot_channel_r: Receiver<SlotOutMsg>,
     
+    // Channel for new client connections, from either the TCP listener or local connectors
+    new_connection_t: Sender<Connection>,
+    new_connection_r: Receiver<Connection>,
+    
     // ID to give to next slot
     next_slot_id: ServerSlotId,
+    
+    // How long a client can go without being heard from before it's disconnected, in milliseconds
+    heartbeat_timeout: i64,
+    
+    // Simulated network conditions to put every connection through, for testing
+    conditions: Option<NetworkConditions>,
+    
+    // Records all the traffic to a capture file, if capturing
+    recorder: Option<PacketRecorder>,
 }
 
 impl Server {
     pub fn new() -> Server {
         let (slot_channel_t, slot_channel_r) = channel();
+        let (new_connection_t, new_connection_r) = channel();
     
         Server {
             slots: HashMap::new(),
             slot_channel_t: slot_channel_t, slot_channel_r: slot_channel_r,
+            new_connection_t: new_connection_t, new_connection_r: new_connection_r,
             next_slot_id: 0,
+            heartbeat_timeout: DEFAULT_HEARTBEAT_TIMEOUT,
+            conditions: None,
+            recorder: None,
         }
     }
     
+    /// Creates a connector that in-process clients can use to connect to this server.
+    pub fn create_local_connector(&self) -> LocalConnector {
+        LocalConnector{new_connection_t: self.new_connection_t.clone()}
+    }
+    
     pub fn create_slot(&mut self) -> ServerSlot {
         let (slot_in_t, slot_in_r) = channel();
         let (create_slot_t, create_slot_r) = channel(); // Channel for sending newly created ServerSlots to the slot upon request
@@ -134,6 +340,7 @@ impl Server {
         ServerSlot::new(slot_id, self.slot_channel_t.clone(), slot_in_r, create_slot_r)
     }
     
+    /// Listens for TCP clients on the given address and runs the server.
     pub fn listen(&mut self, address: &str) {
         let listener = 
             match TcpListener::bind(address) {
@@ -141,189 +348,502 @@ impl Server {
                 Err(e) => panic!("Server failed to listen on address {}: {}", address, e),
             };
         
-        // Maps clients to their server slots
-        let mut client_slots: HashMap<ClientId, Sender<SlotInMsg>> = HashMap::new();
+        let new_connection_t = self.new_connection_t.clone();
+        Thread::spawn(move || {
+            client_acceptor(listener, new_connection_t);
+        });
         
-        // Maps clients to their out packet channels. Also store client's server slot ID so we can verify it when
-        // sending packets from server slots.
-        let mut client_outs: HashMap<ClientId, (ServerSlotId, Sender<OutPacket>)> = HashMap::new();
+        self.run();
+    }
+    
+    /// Runs the server. Only clients from local connectors can connect unless `listen` is used instead.
+    pub fn run(&mut self) {
+        // Maps client IDs to everything the master task knows about each client
+        let mut clients: HashMap<ClientId, ConnectedClient> = HashMap::new();
         
         // Client task to master: packet channel
-        let (packet_in_t, packet_in_r): (Sender<(ClientId, InPacket)>, Receiver<(ClientId, InPacket)>) = channel();
-        
-        // Server listener task to master: TcpStream channel
-        let (new_client_t, new_client_r): (Sender<TcpStream>, Receiver<TcpStream>) = channel();
+        let (packet_in_t, packet_in_r): (Sender<ClientInMsg>, Receiver<ClientInMsg>) = channel();
         
         // Next ID to give to each client
         let mut next_client_id = 0;
         
-        Thread::spawn(move || {
-            client_acceptor(listener, new_client_t);
-        });
+        // Set once a slot shuts the server down. New connections get dropped from then on.
+        let mut shut_down = false;
         
-        // Manage server slots
-        loop {
-            // Accept connections and process them, spawning a new tasks for each one
-            let mut accepted_connections = 0u32; // Counter for accepted connections - move on after a while if connections keep coming
-            loop {
-                match new_client_r.try_recv() {
-                    Err(_) => { break; },
-                    Ok(mut stream) => {
-                        let client_id = next_client_id;
-                        next_client_id += 1;
-                        
-                        // Send back the client ID
-                        if let Err(e) = write_u32(&mut stream, client_id) {
-                            panic!("Failed to send client ID to client: {}", e);
-                        }
-                        
-                        // Assign client to default slot
-                        let (ref default_slot, _) = self.slots[&0];
-                        client_slots.insert(client_id, default_slot.clone());
-                        
-                        // Create client packet output channel
-                        let (client_out_t, client_out_r) = channel();
-                        client_outs.insert(client_id, (0, client_out_t)); // Zero is the slot ID of the default slot
-                        
-                        // Clone packet in channel
-                        let packet_in_t = packet_in_t.clone();
-                        
-                        // Clone stream for output stream
-                        let out_stream = stream.try_clone().ok().expect("Failed to clone to-client stream");
-                    
-                        // Client input process
-                        Thread::spawn(move || {
-                            handle_client_in(client_id, stream, packet_in_t);
-                        });
-                        
-                        // Client output process
-                        Thread::spawn(move || {
-                            handle_client_out(out_stream, client_out_r);
-                        });
-                        
-                        // Tell the default channel that it's been joined
-                        default_slot.send(SlotInMsg::Joined(client_id));
-                        
-                        accepted_connections += 1;
-                    }
-                }
-                
-                if accepted_connections >= 5 {
-                    break;
-                }
-            }
+        // Wakes the master task up whenever it's time to send heartbeats
+        let mut heartbeat_timer = Timer::new().ok().expect("Failed to create heartbeat timer");
+        let heartbeat_r = heartbeat_timer.periodic(Duration::milliseconds(HEARTBEAT_INTERVAL));
         
-            // Check for new packets
-            let mut received_packets = 0u32; // Packet counter. Move on after a while if packets keep coming
-            loop {
-                match packet_in_r.try_recv() {
-                    Ok((client_id, packet)) => {
-                        received_packets += 1;
-                        
-                        // Send the received packet to the slot the client is in
-                        client_slots[&client_id].send(SlotInMsg::ReceivedPacket(client_id, packet));
-                    },
-                    Err(_) => { break; }
-                }
+        // Manage server slots. Sleeps until something happens.
+        loop {
+            let event = {
+                let new_connection_r = &self.new_connection_r;
+                let slot_channel_r = &self.slot_channel_r;
+                let packet_in_r = &packet_in_r;
+                let heartbeat_r = &heartbeat_r;
                 
-                if received_packets >= 10 {
-                    break;
+                select! {
+                    connection = new_connection_r.recv() => MasterEvent::NewConnection(connection.ok().expect("New connection channel closed")),
+                    msg = packet_in_r.recv() => MasterEvent::Client(msg.ok().expect("Client packet channel closed")),
+                    msg = slot_channel_r.recv() => MasterEvent::Slot(msg.ok().expect("Slot channel closed")),
+                    _ = heartbeat_r.recv() => MasterEvent::Heartbeat
                 }
-            }
+            };
             
-            // Check for messages from slots
-            let mut received_messages = 0u32; // Packet counter. Move on after a while if messages keep coming
-            loop {
-                match self.slot_channel_r.try_recv() {
-                    Ok(msg) => {
-                        received_messages += 1;
-                        match msg {
-                            SlotOutMsg::SendPacket(slot_id, client_id, packet) => match client_outs.get(&client_id) {
-                                Some(&(ref client_slot_id, ref c)) => {
-                                    c.send(packet);
-                                    /*if slot_id == *client_slot_id {
-                                        c.send(packet);
-                                    } else {
-                                        println!("Failed to send packet to client {} from server slot {} because the client's server slot is {}", client_id, slot_id, client_slot_id);
-                                    }*/
-                                },
-                                None => { println!("Failed to send packet to invalid client ID {}", client_id); }
-                            },
-                            SlotOutMsg::BroadcastPacket(slot_id, packet) => for &(ref client_slot_id, ref c) in client_outs.values() {
-                                if slot_id == *client_slot_id {
-                                    c.send(packet.clone());
+            let now = time::now().to_timespec();
+            
+            match event {
+                MasterEvent::NewConnection(_) if shut_down => {},
+                MasterEvent::NewConnection(connection) => {
+                    let client_id = next_client_id;
+                    next_client_id += 1;
+                    
+                    // Create client packet output channel
+                    let (client_out_t, client_out_r) = channel();
+                    
+                    // Send back the client ID and start the client's IO tasks
+                    if let Err(e) = start_client_io(client_id, connection, packet_in_t.clone(), client_out_r, self.conditions) {
+                        println!("Failed to send client ID to client: {}", e);
+                        continue;
+                    }
+                    
+                    // Assign client to default slot
+                    let (ref default_slot, _) = self.slots[&0];
+                    clients.insert(client_id, ConnectedClient::new(0, default_slot.clone(), client_out_t, now)); // Zero is the slot ID of the default slot
+                    
+                    // Tell the default channel that it's been joined
+                    default_slot.send(SlotInMsg::Joined(client_id));
+                    record(&self.recorder, client_id, 0, CaptureEvent::Joined);
+                },
+                MasterEvent::Client(ClientInMsg::Packet(client_id, packet)) => {
+                    // Send the received packet to the slot the client is in
+                    let flooding =
+                        match clients.get_mut(&client_id) {
+                            Some(client) => {
+                                client.last_heard = now;
+                                if client.count_packet(now) {
+                                    if self.recorder.is_some() {
+                                        record(&self.recorder, client_id, client.slot_id, CaptureEvent::FromClient(packet.buffer.get_ref().clone()));
+                                    }
+                                    client.slot.send(SlotInMsg::ReceivedPacket(client_id, packet));
+                                    false
+                                } else {
+                                    true
                                 }
                             },
-                            SlotOutMsg::CreateSlot(slot_id) =>  {
-                                let new_slot = self.create_slot();
-                                let (_, ref create_slot_t) = self.slots[&slot_id];
-                                create_slot_t.send(new_slot);
-                            },
-                            SlotOutMsg::TransferClient(slot_id, client_id, new_slot_id) => {
-                                match self.slots.get(&new_slot_id) {
-                                    Some(slot) => {
-                                        let &mut (ref mut client_slot_id, _) =
-                                            client_outs.get_mut(&client_id).expect("Failed to get client output stream");
-                                        if *client_slot_id == slot_id {
-                                            let &(ref slot_in_t, _) = slot;
-                                            *client_slot_id = new_slot_id; // set the client's new slot ID
-                                            client_slots.get_mut(&client_id)
-                                                .expect("Failed to get client slot")
-                                                .clone_from(slot_in_t);
+                            None => false,
+                        };
+                    
+                    if flooding {
+                        println!("Client {} sent too many packets", client_id);
+                        disconnect_client(&mut clients, client_id, &self.recorder);
+                    }
+                },
+                MasterEvent::Client(ClientInMsg::Pong(client_id, sequence)) => {
+                    if let Some(client) = clients.get_mut(&client_id) {
+                        client.on_pong(sequence, now);
+                    }
+                },
+                MasterEvent::Client(ClientInMsg::Disconnected(client_id)) => {
+                    disconnect_client(&mut clients, client_id, &self.recorder);
+                },
+                MasterEvent::Slot(msg) => match msg {
+                    SlotOutMsg::SendPacket(slot_id, client_id, packet) => match clients.get(&client_id) {
+                        Some(client) => {
+                            if self.recorder.is_some() {
+                                record(&self.recorder, client_id, client.slot_id, CaptureEvent::ToClient(packet.buffer.get_ref().clone()));
+                            }
+                            client.out.send(OutFrame::Packet(packet));
+                            /*if slot_id == client.slot_id {
+                                client.out.send(OutFrame::Packet(packet));
+                            } else {
+                                println!("Failed to send packet to client {} from server slot {} because the client's server slot is {}", client_id, slot_id, client.slot_id);
+                            }*/
+                        },
+                        None => { println!("Failed to send packet to invalid client ID {}", client_id); }
+                    },
+                    SlotOutMsg::BroadcastPacket(slot_id, packet) => for (client_id, client) in clients.iter() {
+                        if slot_id == client.slot_id {
+                            if self.recorder.is_some() {
+                                record(&self.recorder, *client_id, slot_id, CaptureEvent::ToClient(packet.buffer.get_ref().clone()));
+                            }
+                            client.out.send(OutFrame::Packet(packet.clone()));
+                        }
+                    },
+                    SlotOutMsg::CreateSlot(slot_id) =>  {
+                        let new_slot = self.create_slot();
+                        let (_, ref create_slot_t) = self.slots[&slot_id];
+                        create_slot_t.send(new_slot);
+                    },
+                    SlotOutMsg::TransferClient(slot_id, client_id, new_slot_id) => {
+                        match self.slots.get(&new_slot_id) {
+                            Some(slot) => {
+                                let &(ref slot_in_t, _) = slot;
+                                match clients.get_mut(&client_id) {
+                                    Some(client) => {
+                                        if client.slot_id == slot_id {
+                                            client.slot_id = new_slot_id; // set the client's new slot ID
+                                            client.slot.clone_from(slot_in_t);
                                             slot_in_t.send(SlotInMsg::Joined(client_id));
+                                            record(&self.recorder, client_id, new_slot_id, CaptureEvent::Joined);
                                         }
                                     },
-                                    None => panic!("Failed to transfer client {} to non-existant slot {}", client_id, slot_id)
+                                    None => {
+                                        // The client disconnected while it was being transferred. The
+                                        // new slot might be getting the client's account handed to it,
+                                        // so it needs to know to clean up after the client as well.
+                                        slot_in_t.send(SlotInMsg::Disconnected(client_id));
+                                    },
                                 }
                             },
+                            None => panic!("Failed to transfer client {} to non-existant slot {}", client_id, slot_id)
                         }
                     },
-                    Err(_) => { break; }
-                }
-                
-                if received_messages >= 10 {
-                    break;
-                }
+                    SlotOutMsg::DisconnectClient(slot_id, client_id) => {
+                        // Only the client's own slot gets to kick it
+                        let in_slot = clients.get(&client_id).map(|client| client.slot_id == slot_id).unwrap_or(false);
+                        if in_slot {
+                            disconnect_client(&mut clients, client_id, &self.recorder);
+                        }
+                    },
+                    SlotOutMsg::ListClients(reply_t) => {
+                        let client_list =
+                            clients.iter()
+                                .map(|(client_id, client)| ClientInfo {
+                                    client_id: *client_id,
+                                    slot_id: client.slot_id,
+                                    latency: client.latency,
+                                })
+                                .collect();
+                        reply_t.send(client_list);
+                    },
+                    SlotOutMsg::KickClient(client_id) => {
+                        println!("Kicking client {}", client_id);
+                        disconnect_client(&mut clients, client_id, &self.recorder);
+                    },
+                    SlotOutMsg::BroadcastAll(packet) => for (client_id, client) in clients.iter() {
+                        if self.recorder.is_some() {
+                            record(&self.recorder, *client_id, client.slot_id, CaptureEvent::ToClient(packet.buffer.get_ref().clone()));
+                        }
+                        client.out.send(OutFrame::Packet(packet.clone()));
+                    },
+                    SlotOutMsg::Shutdown(done_t) => {
+                        println!("Server shutting down");
+                        let client_ids: Vec<ClientId> = clients.keys().map(|client_id| *client_id).collect();
+                        for client_id in client_ids.into_iter() {
+                            disconnect_client(&mut clients, client_id, &self.recorder);
+                        }
+                        shut_down = true;
+                        done_t.send(());
+                    },
+                },
+                MasterEvent::Heartbeat => {
+                    // Ping everyone and drop clients that stopped answering
+                    let timed_out: Vec<ClientId> =
+                        clients.iter()
+                            .filter(|&(_, client)| (now - client.last_heard).num_milliseconds() > self.heartbeat_timeout)
+                            .map(|(client_id, _)| *client_id)
+                            .collect();
+                    for client_id in timed_out.into_iter() {
+                        println!("Client {} timed out", client_id);
+                        disconnect_client(&mut clients, client_id, &self.recorder);
+                    }
+                    
+                    for client in clients.values_mut() {
+                        client.send_ping(now);
+                    }
+                },
+            }
+        }
+    }
+    
+    /// Makes every connection from now on go through simulated network conditions. Local
+    /// connections get them both ways, network connections only on the way to the client.
+    pub fn set_network_conditions(&mut self, conditions: NetworkConditions) {
+        self.conditions = Some(conditions);
+    }
+    
+    /// Records all the traffic from now on into a capture file, so it can be replayed later.
+    pub fn set_capture_file(&mut self, path: &Path) -> io::Result<()> {
+        let file = try!(File::create(path));
+        self.recorder = Some(PacketRecorder::new(file));
+        Ok(())
+    }
+    
+    /// Sets how long a client can go without being heard from before it gets disconnected.
+    pub fn set_heartbeat_timeout(&mut self, timeout_ms: i64) {
+        self.heartbeat_timeout = timeout_ms;
+    }
+}
+
+// Things that wake up the server master task
+enum MasterEvent {
+    NewConnection(Connection),
+    Client(ClientInMsg),
+    Slot(SlotOutMsg),
+    Heartbeat, // Time to ping everyone
+}
+
+// Everything the server master task keeps track of for a connected client
+struct ConnectedClient {
+    // The server slot the client is in
+    slot_id: ServerSlotId,
+    slot: Sender<SlotInMsg>,
+    
+    // Channel to the client's output task
+    out: Sender<OutFrame>,
+    
+    // Last time anything was heard from the client
+    last_heard: time::Timespec,
+    
+    // Sequence number for the next ping, and the ping still waiting on an answer
+    next_ping: u32,
+    pending_ping: Option<(u32, time::Timespec)>,
+    
+    // Last measured round trip time in milliseconds
+    latency: Option<u32>,
+    
+    // Packets received since rate_window_start, for rate limiting
+    packets_in_window: u32,
+    rate_window_start: time::Timespec,
+}
+
+impl ConnectedClient {
+    fn new(slot_id: ServerSlotId, slot: Sender<SlotInMsg>, out: Sender<OutFrame>, now: time::Timespec) -> ConnectedClient {
+        ConnectedClient {
+            slot_id: slot_id,
+            slot: slot,
+            out: out,
+            last_heard: now,
+            next_ping: 0,
+            pending_ping: None,
+            latency: None,
+            packets_in_window: 0,
+            rate_window_start: now,
+        }
+    }
+    
+    // Counts a packet from the client against its rate limit. Returns false if the client is over the limit.
+    fn count_packet(&mut self, now: time::Timespec) -> bool {
+        if (now - self.rate_window_start).num_milliseconds() >= 1000 {
+            self.rate_window_start = now;
+            self.packets_in_window = 0;
+        }
+        
+        self.packets_in_window += 1;
+        self.packets_in_window <= MAX_PACKETS_PER_SECOND
+    }
+    
+    fn send_ping(&mut self, now: time::Timespec) {
+        // Pings carry the last measured latency so the client can show it
+        self.out.send(OutFrame::Ping(self.next_ping, self.latency));
+        
+        // Only time the newest ping. Answers to older pings still count as hearing from the client.
+        self.pending_ping = Some((self.next_ping, now));
+        self.next_ping += 1;
+    }
+    
+    fn on_pong(&mut self, sequence: u32, now: time::Timespec) {
+        self.last_heard = now;
+        
+        if let Some((pending_sequence, sent_time)) = self.pending_ping {
+            if sequence == pending_sequence {
+                self.latency = Some((now - sent_time).num_milliseconds() as u32);
+                self.pending_ping = None;
             }
         }
     }
 }
 
-fn client_acceptor(listener: TcpListener, new_client_t: Sender<TcpStream>) {
+// Forgets about a client and tells its slot it's gone. Dropping the client's out channel stops its
+// output task, which also closes the connection if it's still open.
+fn disconnect_client(clients: &mut HashMap<ClientId, ConnectedClient>, client_id: ClientId, recorder: &Option<PacketRecorder>) {
+    if let Some(client) = clients.remove(&client_id) {
+        println!("Client {} disconnected", client_id);
+        record(recorder, client_id, client.slot_id, CaptureEvent::Disconnected);
+        
+        // Tell the slot the client was in so it can clean up after it
+        client.slot.send(SlotInMsg::Disconnected(client_id));
+    }
+}
+
+fn client_acceptor(listener: TcpListener, new_connection_t: Sender<Connection>) {
     for stream in listener.incoming() {
         match stream {
             Err(e) => { println!("Incoming connection failed: {}", e); },
-            Ok(stream) => {
-                new_client_t.send(stream);
+            Ok(mut stream) => {
+                let new_connection_t = new_connection_t.clone();
+                
+                // Hang up on connections that go quiet during the handshake so they don't tie up a task forever
+                let watchdog_stream =
+                    match stream.try_clone() {
+                        Ok(watchdog_stream) => watchdog_stream,
+                        Err(e) => {
+                            println!("Failed to clone incoming connection: {}", e);
+                            continue;
+                        },
+                    };
+                let (handshake_done_t, handshake_done_r) = channel::<()>();
+                Thread::spawn(move || {
+                    let mut watchdog_stream = watchdog_stream;
+                    let mut timer = Timer::new().ok().expect("Failed to create handshake timer");
+                    let deadline_r = timer.oneshot(Duration::milliseconds(HANDSHAKE_TIMEOUT));
+                    select! {
+                        _ = deadline_r.recv() => {
+                            println!("Incoming connection timed out during handshake");
+                            watchdog_stream.shutdown(Shutdown::Both);
+                        },
+                        _ = handshake_done_r.recv() => {}
+                    }
+                });
+                
+                // Handshake in its own task so a slow client can't hold up other connections
+                Thread::spawn(move || {
+                    let result = server_handshake(&mut stream);
+                    handshake_done_t.send(());
+                    match result {
+                        Ok(Some(features)) => { new_connection_t.send(Connection::Tcp(stream, features)); },
+                        Ok(None) => { println!("Rejected client with incompatible protocol version"); },
+                        Err(e) => { println!("Handshake with incoming connection failed: {}", e); },
+                    }
+                });
             }
         }
     }
 }
 
-fn handle_client_in(client_id: ClientId, mut stream: TcpStream, packet_in_t: Sender<(ClientId, InPacket)>) {
+// Sends the client its ID and spawns the tasks that move packets between the client and the master task
+fn start_client_io(client_id: ClientId, connection: Connection, packet_in_t: Sender<ClientInMsg>, client_out_r: Receiver<OutFrame>,
+                   conditions: Option<NetworkConditions>) -> io::Result<()> {
+    let client_out_r =
+        match conditions {
+            Some(conditions) => simulate_link(conditions, client_out_r, frame_size),
+            None => client_out_r,
+        };
+    
+    match connection {
+        Connection::Tcp(mut stream, features) => {
+            try!(write_u32(&mut stream, client_id));
+            
+            // Clone stream for output stream
+            let out_stream = try!(stream.try_clone());
+        
+            // Client input process
+            Thread::spawn(move || {
+                handle_client_in(client_id, stream, packet_in_t);
+            });
+            
+            // Client output process
+            Thread::spawn(move || {
+                handle_client_out(out_stream, client_out_r, features);
+            });
+        },
+        Connection::Local(id_t, to_client_t, from_client_r, status) => {
+            use std::io::{Error, ErrorKind};
+            
+            if let Err(_) = id_t.send(client_id) {
+                return Err(Error::new(ErrorKind::Other, "Local client went away", None));
+            }
+            
+            let from_client_r =
+                match conditions {
+                    Some(conditions) => simulate_link(conditions, from_client_r, |packet: &InPacket| packet.len()),
+                    None => from_client_r,
+                };
+            
+            // Client input process
+            let local_packet_in_t = packet_in_t.clone();
+            Thread::spawn(move || {
+                handle_local_client_in(client_id, from_client_r, local_packet_in_t);
+            });
+            
+            // Client output process
+            Thread::spawn(move || {
+                handle_local_client_out(client_id, to_client_t, client_out_r, packet_in_t, status);
+            });
+        },
+    }
+    
+    Ok(())
+}
+
+// Messages from client input tasks to the server master task
+enum ClientInMsg {
+    Packet(ClientId, InPacket), // Received a packet from the client (client_id, packet)
+    Pong(ClientId, u32),        // Client answered a ping (client_id, sequence)
+    Disconnected(ClientId),     // The client's connection died (client_id)
+}
+
+fn handle_client_in(client_id: ClientId, mut stream: TcpStream, packet_in_t: Sender<ClientInMsg>) {
     loop {
-        packet_in_t.send((client_id, InPacket::new_from_reader(&mut stream)));
+        match read_frame(&mut stream, MAX_CLIENT_PACKET_SIZE) {
+            Ok(InFrame::Packet(packet)) => { packet_in_t.send(ClientInMsg::Packet(client_id, packet)); },
+            Ok(InFrame::Pong(sequence)) => { packet_in_t.send(ClientInMsg::Pong(client_id, sequence)); },
+            Ok(InFrame::Ping(_, _)) => {}, // Only the server sends pings
+            Err(e) => {
+                println!("Lost connection to client {}: {}", client_id, e);
+                break;
+            },
+        }
     }
+    
+    // Make sure the output task's writes fail too
+    stream.shutdown(Shutdown::Both);
+    
+    packet_in_t.send(ClientInMsg::Disconnected(client_id));
 }
 
-fn handle_client_out(mut stream: TcpStream, out_r: Receiver<OutPacket>) {
+fn handle_client_out(mut stream: TcpStream, out_r: Receiver<OutFrame>, features: FeatureSet) {
     loop {
-        // Receive a packet to send
-        let packet = 
+        // Receive a frame to send. The channel closes when the client is disconnected.
+        let frame = 
             match out_r.recv() {
-                Ok(packet) => packet,
-                _ => panic!("Failed to receive out packet over channel."),
+                Ok(frame) => frame,
+                Err(_) => { break; },
             };
         
-        // Get the packet's data
-        let data = packet.buffer.get_ref();
-        
-        // Write the packet size, then the actual packet data
-        if let Err(e) = write_u16(&mut stream, data.len() as u16) {
-            panic!("Failed to write packet length: {}", e);
+        if let Err(e) = write_frame(&mut stream, &frame, features) {
+            println!("Failed to write frame: {}", e);
+            break;
         }
-        if let Err(e) = stream.write(data) {
-            panic!("Failed to write packet data: {}", e);
+    }
+    
+    // Wake up the input task so it reports the disconnect
+    stream.shutdown(Shutdown::Both);
+}
+
+fn handle_local_client_in(client_id: ClientId, from_client_r: Receiver<InPacket>, packet_in_t: Sender<ClientInMsg>) {
+    // The channel closes when the local client is dropped
+    for packet in from_client_r.iter() {
+        // Hold local clients to the same rules as remote ones
+        if packet.len() as u64 > MAX_CLIENT_PACKET_SIZE {
+            println!("Client {} sent a {} byte packet, which is over the limit", client_id, packet.len());
+            break;
+        }
+        
+        packet_in_t.send(ClientInMsg::Packet(client_id, packet));
+    }
+    
+    packet_in_t.send(ClientInMsg::Disconnected(client_id));
+}
+
+fn handle_local_client_out(client_id: ClientId, to_client_t: Sender<io::Result<InPacket>>, out_r: Receiver<OutFrame>,
+                           packet_in_t: Sender<ClientInMsg>, status: Arc<Mutex<ConnectionStatus>>) {
+    // Stops when the client is disconnected, which closes the out channel
+    for frame in out_r.iter() {
+        match frame {
+            OutFrame::Packet(packet) => {
+                if let Err(_) = to_client_t.send(Ok(InPacket::from_out_packet(packet))) {
+                    // Client is gone. Its input task will report the disconnect.
+                    break;
+                }
+            },
+            OutFrame::Ping(sequence, latency) => {
+                // Local clients never see frames, so answer for them here
+                status.lock().unwrap().on_heard(latency);
+                packet_in_t.send(ClientInMsg::Pong(client_id, sequence));
+            },
+            OutFrame::Pong(_) => {},
         }
     }
 }
@@ -331,58 +851,216 @@ fn handle_client_out(mut stream: TcpStream, out_r: Receiver<OutPacket>) {
 ///////////////////////////////////////////////////////////////////////////////////////////////////
 // Client
 
+// Where a client's packets to the server go
+enum ClientStream {
+    Tcp(Sender<OutFrame>), // To the client's output task
+    Local(Sender<InPacket>),
+}
+
+// Health of a client's connection, shared between the Client and the tasks doing its IO
+struct ConnectionStatus {
+    last_heard: time::Timespec, // Last time anything arrived from the server
+    latency: Option<u32>,       // Round trip time in milliseconds, as measured by the server
+}
+
+impl ConnectionStatus {
+    fn new() -> ConnectionStatus {
+        ConnectionStatus {
+            last_heard: time::now().to_timespec(),
+            latency: None,
+        }
+    }
+    
+    fn on_heard(&mut self, latency: Option<u32>) {
+        self.last_heard = time::now().to_timespec();
+        if latency.is_some() {
+            self.latency = latency;
+        }
+    }
+}
+
+// Where a client connected to, so it can connect there again
+enum ClientAddress {
+    Remote(String),
+    Local(LocalConnector),
+}
+
 pub struct Client {
     id: ClientId,
-    stream: TcpStream,
+    address: ClientAddress,
+    conditions: Option<NetworkConditions>,
+    features: FeatureSet,
+    stream: ClientStream,
     packet_receiver: Receiver<io::Result<InPacket>>,
+    status: Arc<Mutex<ConnectionStatus>>,
+    
+    // How long the server can go quiet before the connection is considered dead, in milliseconds
+    timeout: i64,
 }
 
 impl Client {
-    pub fn new(host: &str) -> Client {
+    pub fn new(host: &str) -> Result<Client, ConnectError> {
+        Client::connect(host, None)
+    }
+    
+    /// Connects to a server with everything sent to it going through simulated network conditions.
+    pub fn new_simulated(host: &str, conditions: NetworkConditions) -> Result<Client, ConnectError> {
+        Client::connect(host, Some(conditions))
+    }
+    
+    fn connect(host: &str, conditions: Option<NetworkConditions>) -> Result<Client, ConnectError> {
         let mut stream = 
             match TcpStream::connect(host) {
                 Ok(stream) => stream,
-                Err(e) => panic!("Failed to connect to server: {}", e),
+                Err(e) => { return Err(ConnectError::Io(e)); },
             };
+        
+        let features = try!(client_handshake(&mut stream));
 
         let id = 
             match read_u32(&mut stream) {
                 Ok(id) => id,
-                Err(e) => panic!("Couldn't connect to server because client ID failed to receive: {}", e),
+                Err(e) => { return Err(ConnectError::Io(e)); },
             };
         
+        let status = Arc::new(Mutex::new(ConnectionStatus::new()));
         let (packet_sender, packet_receiver) = channel();
+        let (frame_sender, frame_receiver) = channel();
+        let frame_receiver =
+            match conditions {
+                Some(conditions) => simulate_link(conditions, frame_receiver, frame_size),
+                None => frame_receiver,
+            };
         
         let mut thread_stream = stream.try_clone().ok().expect("Failed to clone client TcpStream");
+        let thread_status = status.clone();
+        let pong_sender = frame_sender.clone();
         Builder::new().name("client_packet_receiver".to_string()).spawn(move || {
             loop {
-                let packet = InPacket::try_new_from_reader(&mut thread_stream);
-                packet_sender.send(packet);
+                match read_frame(&mut thread_stream, MAX_SERVER_PACKET_SIZE) {
+                    Ok(InFrame::Packet(packet)) => {
+                        thread_status.lock().unwrap().on_heard(None);
+                        if let Err(_) = packet_sender.send(Ok(packet)) {
+                            break; // Client was dropped
+                        }
+                    },
+                    Ok(InFrame::Ping(sequence, latency)) => {
+                        thread_status.lock().unwrap().on_heard(latency);
+                        pong_sender.send(OutFrame::Pong(sequence));
+                    },
+                    Ok(InFrame::Pong(_)) => {}, // Only the server sends pings
+                    Err(e) => {
+                        packet_sender.send(Err(e));
+                        break;
+                    },
+                }
+            }
+        });
+        
+        // All writes go through one task so pongs and packets don't get mixed together
+        Builder::new().name("client_packet_sender".to_string()).spawn(move || {
+            for frame in frame_receiver.iter() {
+                if let Err(e) = write_frame(&mut stream, &frame, features) {
+                    println!("Failed to send frame to server: {}", e);
+                    break;
+                }
             }
+            
+            stream.shutdown(Shutdown::Both);
         });
     
-        Client{id: id, stream: stream, packet_receiver: packet_receiver}
+        Ok(Client {
+            id: id,
+            address: ClientAddress::Remote(host.to_string()),
+            conditions: conditions,
+            features: features,
+            stream: ClientStream::Tcp(frame_sender),
+            packet_receiver: packet_receiver,
+            status: status,
+            timeout: DEFAULT_HEARTBEAT_TIMEOUT,
+        })
     }
     
-    pub fn send(&mut self, packet: &OutPacket) {
-        let data = &packet.buffer.get_ref();
-        if let Err(e) = write_u16(&mut self.stream, data.len() as u16) {
-            panic!("Failed to send packet size to server: {}", e);
+    /// Connects to a server in the same process.
+    pub fn new_local(connector: &LocalConnector) -> Client {
+        let (id_t, id_r) = channel();
+        let (to_client_t, to_client_r) = channel();
+        let (from_client_t, from_client_r) = channel();
+        let status = Arc::new(Mutex::new(ConnectionStatus::new()));
+        
+        if let Err(_) = connector.new_connection_t.send(Connection::Local(id_t, to_client_t, from_client_r, status.clone())) {
+            panic!("Failed to connect to local server: Server is gone");
         }
-        match self.stream.write(&(*data)[..]) {
-            Ok(bytes_written) => {
-                if data.len() != bytes_written {
-                    panic!("Failed to send packet data to server: Tried to write {} bytes, only {} bytes written", data.len(), bytes_written);
+        
+        let id =
+            match id_r.recv() {
+                Ok(id) => id,
+                Err(_) => panic!("Couldn't connect to local server because client ID failed to receive"),
+            };
+        
+        // Local clients are always the same build as the server, so everything is supported
+        Client {
+            id: id,
+            address: ClientAddress::Local(connector.clone()),
+            conditions: None, // Local servers simulate network conditions themselves
+            features: SUPPORTED_FEATURES,
+            stream: ClientStream::Local(from_client_t),
+            packet_receiver: to_client_r,
+            status: status,
+            timeout: DEFAULT_HEARTBEAT_TIMEOUT,
+        }
+    }
+    
+    /// Makes a new connection to the same server this client connected to.
+    pub fn reconnect(&self) -> Result<Client, ConnectError> {
+        match self.address {
+            ClientAddress::Remote(ref host) => Client::connect(host.as_slice(), self.conditions),
+            ClientAddress::Local(ref connector) => Ok(Client::new_local(connector)),
+        }
+    }
+    
+    pub fn send(&mut self, packet: &OutPacket) {
+        match self.stream {
+            ClientStream::Tcp(ref sender) => {
+                // A closed connection shows up as an error on the receiving end, so it's handled there
+                if let Err(_) = sender.send(OutFrame::Packet(packet.clone())) {
+                    println!("Failed to send packet to server: Connection is closed");
+                }
+            },
+            ClientStream::Local(ref sender) => {
+                if let Err(_) = sender.send(InPacket::from_out_packet(packet.clone())) {
+                    println!("Failed to send packet to local server: Server is gone");
                 }
             },
-            Err(e) => { panic!("Failed to send packet data to server: {}", e); },
         }
     }
     
-    pub fn receive(&mut self) -> InPacket {
-        self.packet_receiver.recv()
-            .ok().expect("Client packet sending channel closed")
-            .ok().expect("Failed to receive packet from server")
+    /// Waits for the next packet from the server. Fails if the connection is lost.
+    pub fn receive(&mut self) -> io::Result<InPacket> {
+        use std::io::{Error, ErrorKind};
+        
+        let mut timer = Timer::new().ok().expect("Failed to create client timeout timer");
+        let packet_receiver = &self.packet_receiver;
+        loop {
+            // Wake up when the connection would time out. Pings can come in while waiting, so
+            // check again then instead of giving up right away.
+            let last_heard = self.status.lock().unwrap().last_heard;
+            let time_left = self.timeout - (time::now().to_timespec() - last_heard).num_milliseconds();
+            if time_left < 0 {
+                return Err(Error::new(ErrorKind::ConnectionAborted, "Server stopped responding", None));
+            }
+            let timeout_receiver = timer.oneshot(Duration::milliseconds(time_left + 1));
+            
+            select! {
+                packet = packet_receiver.recv() => {
+                    return match packet {
+                        Ok(packet) => packet,
+                        Err(_) => Err(Error::new(ErrorKind::Other, "Client packet sending channel closed", None)),
+                    };
+                },
+                _ = timeout_receiver.recv() => {}
+            }
+        }
     }
     
     pub fn try_receive(&mut self) -> io::Result<InPacket> {
@@ -391,16 +1069,40 @@ impl Client {
     
         match self.packet_receiver.try_recv() {
             Ok(packet) => packet,
-            Err(e) if e == TryRecvError::Empty =>
-                Err(Error::new(ErrorKind::TimedOut, "No packet ready yet", None)),
+            Err(e) if e == TryRecvError::Empty => {
+                if self.is_timed_out() {
+                    Err(Error::new(ErrorKind::ConnectionAborted, "Server stopped responding", None))
+                } else {
+                    Err(Error::new(ErrorKind::TimedOut, "No packet ready yet", None))
+                }
+            },
             Err(_) =>
                 Err(Error::new(ErrorKind::Other, "Client packet sending channel closed", None)),
         }
     }
     
+    /// Sets how long the server can go quiet before the connection is considered dead.
+    pub fn set_timeout(&mut self, timeout_ms: i64) {
+        self.timeout = timeout_ms;
+    }
+    
+    pub fn is_timed_out(&self) -> bool {
+        let last_heard = self.status.lock().unwrap().last_heard;
+        (time::now().to_timespec() - last_heard).num_milliseconds() > self.timeout
+    }
+    
+    /// Round trip time to the server in milliseconds, if it's been measured yet.
+    pub fn get_latency(&self) -> Option<u32> {
+        self.status.lock().unwrap().latency
+    }
+    
     pub fn get_id(&self) -> ClientId {
         self.id
     }
+    
+    pub fn get_features(&self) -> FeatureSet {
+        self.features
+    }
 }
 
 ///////////////////////////////////////////////////////////////////////////////////////////////////
@@ -416,6 +1118,14 @@ impl OutPacket {
         OutPacket{buffer: io::Cursor::new(vec!())}
     }
     
+    // Makes a packet out of data that was already written, like packets from a capture file
+    pub fn from_data(data: Vec<u8>) -> OutPacket {
+        let len = data.len();
+        let mut buffer = io::Cursor::new(data);
+        buffer.set_position(len as u64);
+        OutPacket{buffer: buffer}
+    }
+    
     pub fn len(&self) -> usize {
         self.buffer.get_ref().len()
     }
@@ -436,39 +1146,29 @@ impl InPacket {
         InPacket{buffer: io::Cursor::new(data)}
     }
     
-    pub fn new_from_reader<T: Read>(reader: &mut T) -> InPacket {
-        // Get next packet size
-        let packet_size =
-            match read_u16(reader) {
-                Err(e) => panic!("Failed to receive packet size: {}", e),
-                Ok(packet_size) => packet_size
-            };
-        let packet_size = packet_size as u64;
-        
-        // Get data
-        let mut data = vec!();
-        match reader.take(packet_size).read_to_end(&mut data) {
-            Err(e) => { panic!("Failed to receive data: {}", e); },
-            Ok(bytes_read) =>
-                if bytes_read as u64 != packet_size {
-                    panic!("Expected {} bytes, got {} bytes", packet_size, bytes_read);
-                },
-        }
-        
-        // Build packet
-        InPacket::new(data)
+    // Turns a packet meant for sending into a packet for reading, for when the packet doesn't go
+    // over the network.
+    pub fn from_out_packet(packet: OutPacket) -> InPacket {
+        InPacket::new(packet.buffer.into_inner())
     }
     
-    pub fn try_new_from_reader<T: Read>(reader: &mut T) -> io::Result<InPacket> {
+    // Reads a size prefixed packet. Packets bigger than max_size are refused before any of their
+    // data is read.
+    pub fn try_new_from_reader<T: Read>(reader: &mut T, max_size: u64) -> io::Result<InPacket> {
+        use std::io::{Error, ErrorKind};
+    
         // Get next packet size
         let packet_size = try!(read_u16(reader));
         let packet_size = packet_size as u64;
+        if packet_size > max_size {
+            return Err(Error::new(ErrorKind::InvalidInput, "Packet is too big", Some(format!("{} bytes, limit is {}", packet_size, max_size))));
+        }
     
         // Get data
         let mut data = vec!();
         let bytes_read = try!(reader.take(packet_size).read_to_end(&mut data));
         if bytes_read as u64 != packet_size {
-            panic!("Expected {} bytes, got {} bytes", packet_size, bytes_read);
+            return Err(Error::new(ErrorKind::Other, "Connection closed in the middle of a packet", None));
         }
         
         // Build packet
@@ -479,12 +1179,247 @@ impl InPacket {
         self.buffer.get_ref().len()
     }
     
+    // Go back to the start of the packet so it can be read again
+    pub fn rewind(&mut self) {
+        self.buffer.set_position(0);
+    }
+    
     pub fn read<T: Decodable>(&mut self) -> Result<T, DecodingError> {
-        decode_from(&mut self.buffer, SizeLimit::Infinite)
+        // Nothing in a packet can be bigger than the packet itself
+        let size_limit = self.len() as u64;
+        decode_from(&mut self.buffer, SizeLimit::Bounded(size_limit))
     }
 }
 
 ////////////////////////////////////////////////////////////////////////////////////////////////////
+// Capture
+//
+// A capture file is a list of bincode encoded CaptureRecords, one for everything that happened to
+// a client while the server was recording.
+
+#[derive(Clone, Debug, PartialEq, RustcEncodable, RustcDecodable)]
+pub enum CaptureEvent {
+    Joined,              // Client joined the slot
+    Disconnected,        // Client disconnected while in the slot
+    FromClient(Vec<u8>), // Packet the client sent to the slot (packet data)
+    ToClient(Vec<u8>),   // Packet sent to the client from the slot (packet data)
+}
+
+#[derive(Clone, RustcEncodable, RustcDecodable)]
+pub struct CaptureRecord {
+    pub time: i64, // Milliseconds since recording started
+    pub client_id: ClientId,
+    pub slot_id: ServerSlotId,
+    pub event: CaptureEvent,
+}
+
+// Writes capture records to a file from its own task so recording doesn't slow down the server
+struct PacketRecorder {
+    sender: Sender<CaptureRecord>,
+    start_time: time::Timespec,
+}
+
+impl PacketRecorder {
+    fn new(file: File) -> PacketRecorder {
+        let (sender, receiver) = channel();
+        
+        Builder::new().name("packet_recorder".to_string()).spawn(move || {
+            let mut writer = BufWriter::new(file);
+            for record in receiver.iter() {
+                let record: CaptureRecord = record;
+                if let Err(e) = encode_into(&record, &mut writer, SizeLimit::Infinite) {
+                    println!("Failed to write capture record: {}", e);
+                    break;
+                }
+                
+                // Flush every record so a crash doesn't lose the end of the capture
+                if let Err(e) = writer.flush() {
+                    println!("Failed to write capture record: {}", e);
+                    break;
+                }
+            }
+        });
+        
+        PacketRecorder {
+            sender: sender,
+            start_time: time::now().to_timespec(),
+        }
+    }
+}
+
+fn record(recorder: &Option<PacketRecorder>, client_id: ClientId, slot_id: ServerSlotId, event: CaptureEvent) {
+    if let Some(ref recorder) = *recorder {
+        recorder.sender.send(CaptureRecord {
+            time: (time::now().to_timespec() - recorder.start_time).num_milliseconds(),
+            client_id: client_id,
+            slot_id: slot_id,
+            event: event,
+        });
+    }
+}
+
+/// Reads all the records from a capture file. A record cut off at the end of the file, like from a
+/// crash, is left out.
+pub fn read_capture(path: &Path) -> io::Result<Vec<CaptureRecord>> {
+    let mut reader = BufReader::new(try!(File::open(path)));
+    
+    let mut records = vec!();
+    while let Ok(record) = decode_from(&mut reader, SizeLimit::Infinite) {
+        records.push(record);
+    }
+    
+    Ok(records)
+}
+
+////////////////////////////////////////////////////////////////////////////////////////////////////
+// Frames
+//
+// After the handshake, everything sent over a connection is a frame: the frame kind, the u16 size
+// of the data, then the data. If compression was negotiated, frames with more data than
+// COMPRESSION_THRESHOLD are compressed and have the FRAME_COMPRESSED bit set in their kind.
+
+const FRAME_PACKET: u8 = 0;
+const FRAME_PING: u8 = 1;
+const FRAME_PONG: u8 = 2;
+
+const FRAME_COMPRESSED: u8 = 0x80;
+
+// Frames with less data than this aren't worth compressing, in bytes
+static COMPRESSION_THRESHOLD: usize = 256;
+
+// Frames going out over a connection
+enum OutFrame {
+    Packet(OutPacket),
+    Ping(u32, Option<u32>), // Heartbeat from the server (sequence, client's last measured round trip time in ms)
+    Pong(u32),              // Client's answer to a ping (sequence)
+}
+
+// Frames coming in over a connection
+enum InFrame {
+    Packet(InPacket),
+    Ping(u32, Option<u32>),
+    Pong(u32),
+}
+
+fn write_frame<T: Write>(writer: &mut T, frame: &OutFrame, features: FeatureSet) -> io::Result<()> {
+    match *frame {
+        OutFrame::Packet(ref packet) => {
+            let data = packet.buffer.get_ref();
+            
+            if features & FEATURE_COMPRESSION != 0 && data.len() > COMPRESSION_THRESHOLD {
+                let compressed = try!(compress(data));
+                
+                // Some data doesn't get any smaller
+                if compressed.len() < data.len() {
+                    return write_frame_data(writer, FRAME_PACKET | FRAME_COMPRESSED, &compressed);
+                }
+            }
+            
+            write_frame_data(writer, FRAME_PACKET, data)
+        },
+        OutFrame::Ping(sequence, latency) => {
+            let mut packet = OutPacket::new();
+            try!(packet.write(&(sequence, latency)).map_err(encoding_to_io_error));
+            write_frame_data(writer, FRAME_PING, packet.buffer.get_ref())
+        },
+        OutFrame::Pong(sequence) => {
+            let mut packet = OutPacket::new();
+            try!(packet.write(&sequence).map_err(encoding_to_io_error));
+            write_frame_data(writer, FRAME_PONG, packet.buffer.get_ref())
+        },
+    }
+}
+
+// Roughly how many bytes a frame takes up on the wire
+fn frame_size(frame: &OutFrame) -> usize {
+    match *frame {
+        OutFrame::Packet(ref packet) => packet.len() + 3,
+        OutFrame::Ping(_, _) | OutFrame::Pong(_) => 12,
+    }
+}
+
+fn write_frame_data<T: Write>(writer: &mut T, kind: u8, data: &[u8]) -> io::Result<()> {
+    use std::io::{Error, ErrorKind};
+    
+    if data.len() as u64 > MAX_SERVER_PACKET_SIZE {
+        return Err(Error::new(ErrorKind::InvalidInput, "Frame is too big", Some(format!("{} bytes", data.len()))));
+    }
+    
+    try!(writer.write_all(&[kind]));
+    try!(write_u16(writer, data.len() as u16));
+    try!(writer.write_all(data));
+    Ok(())
+}
+
+fn read_frame<T: Read>(reader: &mut T, max_size: u64) -> io::Result<InFrame> {
+    use std::io::{Error, ErrorKind};
+    
+    let mut kind: [u8; 1] = [0];
+    if try!(reader.read(&mut kind)) != 1 {
+        return Err(Error::new(ErrorKind::Other, "Connection closed", None));
+    }
+    
+    let compressed = kind[0] & FRAME_COMPRESSED != 0;
+    let kind = kind[0] & !FRAME_COMPRESSED;
+    
+    let mut packet = try!(InPacket::try_new_from_reader(reader, max_size));
+    if compressed {
+        // The size limit counts for the decompressed data too
+        packet = InPacket::new(try!(decompress(packet.buffer.get_ref(), max_size)));
+    }
+    
+    match kind {
+        FRAME_PACKET => Ok(InFrame::Packet(packet)),
+        FRAME_PING => {
+            let (sequence, latency): (u32, Option<u32>) = try!(packet.read().map_err(decoding_to_io_error));
+            Ok(InFrame::Ping(sequence, latency))
+        },
+        FRAME_PONG => {
+            let sequence: u32 = try!(packet.read().map_err(decoding_to_io_error));
+            Ok(InFrame::Pong(sequence))
+        },
+        kind => Err(Error::new(ErrorKind::InvalidInput, "Unknown frame kind", Some(format!("{}", kind)))),
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////////////////////////
+
+// Writes the packet size, then the actual packet data
+fn write_packet<T: Write>(writer: &mut T, packet: &OutPacket) -> io::Result<()> {
+    let data = packet.buffer.get_ref();
+    try!(write_u16(writer, data.len() as u16));
+    try!(writer.write_all(data));
+    Ok(())
+}
+
+fn compress(data: &[u8]) -> io::Result<Vec<u8>> {
+    let mut encoder = ZlibEncoder::new(vec!(), Compression::Default);
+    try!(encoder.write_all(data));
+    encoder.finish()
+}
+
+// Fails if the decompressed data would be bigger than max_size
+fn decompress(data: &[u8], max_size: u64) -> io::Result<Vec<u8>> {
+    use std::io::{Error, ErrorKind};
+    
+    let mut decompressed = vec!();
+    try!(ZlibDecoder::new(data).take(max_size + 1).read_to_end(&mut decompressed));
+    if decompressed.len() as u64 > max_size {
+        return Err(Error::new(ErrorKind::InvalidInput, "Decompressed packet is too big", None));
+    }
+    
+    Ok(decompressed)
+}
+
+fn decoding_to_io_error(e: DecodingError) -> io::Error {
+    use std::io::{Error, ErrorKind};
+    Error::new(ErrorKind::InvalidInput, "Failed to decode packet", Some(format!("{}", e)))
+}
+
+fn encoding_to_io_error(e: EncodingError) -> io::Error {
+    use std::io::{Error, ErrorKind};
+    Error::new(ErrorKind::InvalidInput, "Failed to encode packet", Some(format!("{}", e)))
+}
 
 fn read_u16<T: Read>(reader: &mut T) -> io::Result<u16> {
     use std::io::{Error, ErrorKind};
@@ -563,3 +1498,101 @@ fn write_u32<T: Write>(writer: &mut T, data: u32) -> io::Result<usize> {
         },
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::io::ErrorKind;
+    
+    use super::{FEATURE_COMPRESSION, InFrame, InPacket, MAX_CLIENT_PACKET_SIZE, MAX_SERVER_PACKET_SIZE, OutFrame, OutPacket,
+                read_frame, write_frame, write_frame_data};
+    
+    // Frame holding a packet of size copies of byte, as it would go over the wire
+    fn packet_frame(size: usize, byte: u8, features: u32) -> Vec<u8> {
+        let mut packet = OutPacket::new();
+        for _ in 0 .. size {
+            packet.write(&byte).unwrap();
+        }
+        
+        let mut data = vec!();
+        write_frame(&mut data, &OutFrame::Packet(packet), features).unwrap();
+        data
+    }
+    
+    // Reads a frame that has to be a packet and returns how big it is
+    fn read_packet_size(data: &[u8], max_size: u64) -> Result<usize, ErrorKind> {
+        let mut reader = data;
+        match read_frame(&mut reader, max_size) {
+            Ok(InFrame::Packet(packet)) => Ok(packet.len()),
+            Ok(_) => panic!("Read a frame that isn't a packet"),
+            Err(e) => Err(e.kind()),
+        }
+    }
+    
+    #[test]
+    fn packets_survive_framing() {
+        let data = packet_frame(100, 7, 0);
+        assert_eq!(data.len(), 103);
+        assert_eq!(read_packet_size(data.as_slice(), MAX_CLIENT_PACKET_SIZE), Ok(100));
+    }
+    
+    #[test]
+    fn refuses_packets_over_the_limit() {
+        let limit = MAX_CLIENT_PACKET_SIZE as usize;
+        assert_eq!(read_packet_size(packet_frame(limit, 7, 0).as_slice(), MAX_CLIENT_PACKET_SIZE), Ok(limit));
+        assert_eq!(read_packet_size(packet_frame(limit + 1, 7, 0).as_slice(), MAX_CLIENT_PACKET_SIZE), Err(ErrorKind::InvalidInput));
+    }
+    
+    #[test]
+    fn refuses_cut_off_packets() {
+        let data = packet_frame(100, 7, 0);
+        assert!(read_packet_size(&data[.. 50], MAX_CLIENT_PACKET_SIZE).is_err());
+        assert!(read_packet_size(&data[.. 2], MAX_CLIENT_PACKET_SIZE).is_err());
+        assert!(read_packet_size(&[], MAX_CLIENT_PACKET_SIZE).is_err());
+    }
+    
+    #[test]
+    fn refuses_unknown_frame_kinds() {
+        let mut data = packet_frame(10, 7, 0);
+        data[0] = 9;
+        assert_eq!(read_packet_size(data.as_slice(), MAX_CLIENT_PACKET_SIZE), Err(ErrorKind::InvalidInput));
+    }
+    
+    #[test]
+    fn refuses_to_send_frames_too_big_for_the_size_field() {
+        let mut data = vec!();
+        let too_big = vec![0u8; MAX_SERVER_PACKET_SIZE as usize + 1];
+        assert!(write_frame_data(&mut data, 0, too_big.as_slice()).is_err());
+        assert!(data.is_empty());
+    }
+    
+    #[test]
+    fn limit_counts_decompressed_size() {
+        // Zeros compress down to almost nothing
+        let data = packet_frame(10000, 0, FEATURE_COMPRESSION);
+        assert!(data.len() < MAX_CLIENT_PACKET_SIZE as usize);
+        
+        assert_eq!(read_packet_size(data.as_slice(), MAX_SERVER_PACKET_SIZE), Ok(10000));
+        assert_eq!(read_packet_size(data.as_slice(), MAX_CLIENT_PACKET_SIZE), Err(ErrorKind::InvalidInput));
+    }
+    
+    #[test]
+    fn pongs_survive_framing() {
+        let mut data = vec!();
+        write_frame(&mut data, &OutFrame::Pong(6), 0).unwrap();
+        match read_frame(&mut data.as_slice(), MAX_CLIENT_PACKET_SIZE) {
+            Ok(InFrame::Pong(sequence)) => { assert_eq!(sequence, 6); },
+            _ => panic!("Failed to read pong"),
+        }
+    }
+    
+    #[test]
+    fn packet_contents_cant_claim_more_than_the_packet() {
+        // A vector that says it's much longer than the packet it's in
+        let mut packet = OutPacket::new();
+        packet.write(&1000000u64).unwrap();
+        let mut packet = InPacket::from_out_packet(packet);
+        
+        let result: Result<Vec<u8>, _> = packet.read();
+        assert!(result.is_err());
+    }
+}
@@ -0,0 +1,136 @@
+use std::cmp;
+use std::rand;
+use std::sync::mpsc::{channel, Receiver};
+use std::thread::{self, Builder};
+use std::time::Duration;
+use time;
+
+// Bad network conditions to put a connection through, for testing on one machine
+#[derive(Clone, Copy, Debug)]
+pub struct NetworkConditions {
+    pub latency: i64,           // Time added to every send, in milliseconds
+    pub jitter: i64,            // Most time randomly added to or taken from the latency, in milliseconds
+    pub bandwidth: u32,         // Bytes per second the link can carry, or 0 for no limit
+    pub disconnect_chance: f64, // Chance of the connection dropping each time something is sent
+}
+
+impl NetworkConditions {
+    pub fn new() -> NetworkConditions {
+        NetworkConditions {
+            latency: 0,
+            jitter: 0,
+            bandwidth: 0,
+            disconnect_chance: 0.0,
+        }
+    }
+    
+    /// Parses settings like "latency=200,jitter=50,bandwidth=8000,disconnect=0.001". Settings
+    /// that are left out stay at their defaults.
+    pub fn parse(settings: &str) -> Result<NetworkConditions, String> {
+        let mut conditions = NetworkConditions::new();
+        
+        for setting in settings.split(',').filter(|s| !s.is_empty()) {
+            let (name, value) =
+                match setting.find('=') {
+                    Some(i) => (&setting[..i], &setting[i+1..]),
+                    None => { return Err(format!("Setting '{}' has no value", setting)); },
+                };
+            
+            match name {
+                "latency" => { conditions.latency = try!(parse_value(name, value)); },
+                "jitter" => { conditions.jitter = try!(parse_value(name, value)); },
+                "bandwidth" => { conditions.bandwidth = try!(parse_value(name, value)); },
+                "disconnect" => { conditions.disconnect_chance = try!(parse_value(name, value)); },
+                _ => { return Err(format!("Unknown setting '{}'", name)); },
+            }
+        }
+        
+        Ok(conditions)
+    }
+    
+    /// Looks for "--netsim <settings>" in the command line arguments.
+    pub fn from_args(args: &[String]) -> Option<NetworkConditions> {
+        let position = args.iter().position(|arg| arg.as_slice() == "--netsim");
+        position.map(|i| {
+            let settings = args.get(i + 1).map(|s| s.as_slice()).unwrap_or("");
+            match NetworkConditions::parse(settings) {
+                Ok(conditions) => conditions,
+                Err(e) => panic!("Invalid --netsim settings: {}", e),
+            }
+        })
+    }
+}
+
+fn parse_value<T: ::std::str::FromStr>(name: &str, value: &str) -> Result<T, String> {
+    value.parse().map_err(|_| format!("Invalid value '{}' for setting '{}'", value, name))
+}
+
+/// Puts a link through the network conditions. Everything sent into the returned receiver comes
+/// out the other end late, in order, and no faster than the bandwidth allows. If the simulated
+/// connection drops, the returned receiver closes just like a real connection's channel would.
+pub fn simulate_link<T: Send + 'static, F>(conditions: NetworkConditions, input: Receiver<T>, size_of: F) -> Receiver<T>
+    where F: Fn(&T) -> usize + Send + 'static
+{
+    let (stamped_t, stamped_r) = channel();
+    let (output_t, output_r) = channel();
+    
+    // Note when everything was sent, so waiting on one thing doesn't hold up the next
+    Builder::new().name("net_sim_intake".to_string()).spawn(move || {
+        for item in input.iter() {
+            if let Err(_) = stamped_t.send((now_ms(), item)) {
+                break;
+            }
+        }
+    });
+    
+    Builder::new().name("net_sim".to_string()).spawn(move || {
+        // When the link finishes sending what it's already got, in milliseconds since the epoch
+        let mut link_free_at = 0i64;
+        
+        // When the last thing was delivered. Nothing gets delivered before it, just like TCP.
+        let mut last_delivery = 0i64;
+        
+        for (sent_at, item) in stamped_r.iter() {
+            if conditions.disconnect_chance > 0.0 && rand::random::<f64>() < conditions.disconnect_chance {
+                println!("Simulated connection drop");
+                break;
+            }
+            
+            // Time spent squeezing the data through the link
+            let send_start = cmp::max(sent_at, link_free_at);
+            let send_time =
+                if conditions.bandwidth > 0 {
+                    (size_of(&item) as i64)*1000/(conditions.bandwidth as i64)
+                } else {
+                    0
+                };
+            link_free_at = send_start + send_time;
+            
+            // Time spent in flight
+            let jitter =
+                if conditions.jitter > 0 {
+                    (rand::random::<u32>() as i64) % (conditions.jitter*2 + 1) - conditions.jitter
+                } else {
+                    0
+                };
+            let delivery = cmp::max(last_delivery, link_free_at + cmp::max(0, conditions.latency + jitter));
+            last_delivery = delivery;
+            
+            let wait = delivery - now_ms();
+            if wait > 0 {
+                thread::sleep(Duration::milliseconds(wait));
+            }
+            
+            if let Err(_) = output_t.send(item) {
+                break; // The other end is gone
+            }
+        }
+    });
+    
+    output_r
+}
+
+fn now_ms() -> i64 {
+    let now = time::now().to_timespec();
+    now.sec*1000 + (now.nsec as i64)/1000000
+}
\ No newline at end of file
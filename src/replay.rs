@@ -0,0 +1,202 @@
+use std::collections::HashMap;
+use std::sync::mpsc::channel;
+use std::thread::{self, Builder};
+use std::time::Duration;
+use time;
+
+use ai::AI_SHIP_NAMES;
+use battle_state::{BattleContext, ClientPacketId};
+use login::{Account, AccountProgress, PasswordHash};
+use net::{CaptureEvent, CaptureRecord, Client, ClientId, InPacket, LocalConnector, OutPacket, Server, ServerSlotId, SlotInMsg};
+use sector_data::SectorId;
+use sector_state::SectorState;
+use ship::{ShipNetworked, ShipStored};
+
+// How long to keep a replayed sector running after the last record, in milliseconds
+static REPLAY_LINGER: i64 = 5000;
+
+/// Starts a fake server that plays back everything a captured client was sent, at the same pace it
+/// was sent. Connect a client to the returned connector to watch the capture from that client's
+/// point of view. Whatever the client sends is ignored.
+pub fn replay_to_client(records: Vec<CaptureRecord>, client_id: ClientId) -> LocalConnector {
+    let mut server = Server::new();
+    let connector = server.create_local_connector();
+    let slot = server.create_slot();
+    
+    Builder::new().name("replay_server_master".to_string()).spawn(move || {
+        server.run();
+    });
+    
+    Builder::new().name("replay_to_client".to_string()).spawn(move || {
+        // Wait for the client to show up
+        let replay_client_id;
+        loop {
+            if let SlotInMsg::Joined(client_id) = slot.receive() {
+                replay_client_id = client_id;
+                break;
+            }
+        }
+        
+        let packets: Vec<(i64, Vec<u8>)> =
+            records.into_iter()
+                .filter(|r| r.client_id == client_id)
+                .filter_map(|r| match r.event {
+                    CaptureEvent::ToClient(data) => Some((r.time, data)),
+                    _ => None,
+                })
+                .collect();
+        
+        println!("Replaying {} packets to client {}", packets.len(), client_id);
+        
+        let clock = ReplayClock::new(packets.first().map(|&(time, _)| time).unwrap_or(0));
+        for (time, data) in packets.into_iter() {
+            clock.wait_until(time);
+            slot.send(replay_client_id, OutPacket::from_data(data));
+        }
+        
+        println!("Replay finished");
+    });
+    
+    connector
+}
+
+/// Feeds the clients' side of a captured sector slot into a fresh SectorState. Clients are
+/// recreated from the join packets they were sent, then send the same packets at the same times as
+/// in the capture. AI decisions and ship generation are random, so those can still differ from the
+/// original run.
+pub fn replay_into_sector(records: Vec<CaptureRecord>, slot_id: ServerSlotId, create_ai: bool) {
+    let mut server = Server::new();
+    let connector = server.create_local_connector();
+    let lobby_slot = server.create_slot(); // Replay clients land here before being moved into the sector
+    let star_map_slot_id = server.create_slot().get_id(); // Jumping clients get sent here and go nowhere
+    let sector_slot = server.create_slot();
+    let sector_slot_id = sector_slot.get_id();
+    
+    // Nothing comes back out of the sector, but the channels need to stay open for it
+    let (jump_sender, _jump_receiver) = channel();
+    let (to_sector_sender, to_sector_receiver) = channel();
+    let (ack_sender, ack_receiver) = channel();
+    let (_resume_sender, resume_receiver) = channel();
+    let (resumed_sender, _resumed_receiver) = channel();
+    let (logout_sender, _logout_receiver) = channel();
+    let (_command_sender, command_receiver) = channel();
+    
+    Builder::new().name("replay_server_master".to_string()).spawn(move || {
+        server.run();
+    });
+    
+    Builder::new()
+        .name("replay_sector_thread".to_string())
+        .stack_size(8388608)
+        .spawn(move || {
+            let mut sector_state = SectorState::new(sector_slot, star_map_slot_id, BattleContext::new(vec!()), true);
+            // Same AI ships the sectors used to always start with
+            let ai_ships =
+                if create_ai {
+                    AI_SHIP_NAMES.iter().map(|name| (name.to_string(), 2)).collect()
+                } else {
+                    vec!()
+                };
+            sector_state.run(jump_sender, to_sector_receiver, ack_sender, resume_receiver, resumed_sender, logout_sender, command_receiver, ai_ships);
+        });
+    
+    let records: Vec<CaptureRecord> = records.into_iter().filter(|r| r.slot_id == slot_id).collect();
+    println!("Replaying {} records into sector", records.len());
+    
+    // Replay clients, by their client ID in the capture
+    let mut clients: HashMap<ClientId, Client> = HashMap::new();
+    
+    let clock = ReplayClock::new(records.first().map(|r| r.time).unwrap_or(0));
+    for (i, record) in records.iter().enumerate() {
+        clock.wait_until(record.time);
+        
+        match record.event {
+            CaptureEvent::Joined => {
+                let ship = match find_joined_ship(&records[i..], record.client_id) {
+                    Some(ship) => ship,
+                    None => {
+                        println!("Skipping client {}, it was never sent its ship", record.client_id);
+                        continue;
+                    },
+                };
+                
+                let client = Client::new_local(&connector);
+                let replay_client_id = client.get_id();
+                
+                let account = Box::new(Account {
+                    username: ship.name.clone(),
+                    password: PasswordHash::new(""),
+                    ship: Some(ship),
+                    client_id: Some(replay_client_id),
+                    sector: SectorId(0),
+                    session_token: None,
+                    progress: AccountProgress::new(),
+                });
+                
+                // Same handoff the star map does
+                to_sector_sender.send(account);
+                ack_receiver.recv();
+                lobby_slot.transfer_client(replay_client_id, sector_slot_id);
+                
+                clients.insert(record.client_id, client);
+            },
+            CaptureEvent::FromClient(ref data) => {
+                if let Some(client) = clients.get_mut(&record.client_id) {
+                    client.send(&OutPacket::from_data(data.clone()));
+                }
+            },
+            CaptureEvent::Disconnected => {
+                // Dropping the client disconnects it
+                clients.remove(&record.client_id);
+            },
+            CaptureEvent::ToClient(_) => {},
+        }
+    }
+    
+    // Give the sector time to finish the turn the last packets were for
+    thread::sleep(Duration::milliseconds(REPLAY_LINGER));
+    
+    println!("Replay finished");
+}
+
+// Finds the ship a client was given in the first join packet sent to it
+fn find_joined_ship(records: &[CaptureRecord], client_id: ClientId) -> Option<ShipStored> {
+    for record in records.iter().filter(|r| r.client_id == client_id) {
+        if let CaptureEvent::ToClient(ref data) = record.event {
+            let mut packet = InPacket::new(data.clone());
+            if let Ok(ClientPacketId::JoinSector) = packet.read() {
+                let ship: ShipNetworked = match packet.read() {
+                    Ok(ship) => ship,
+                    Err(_) => { return None; },
+                };
+                let (ship, _) = ship.to_ship();
+                return Some(ShipStored::from_ship(ship));
+            }
+        }
+    }
+    
+    None
+}
+
+// Keeps replayed events at the same pace as the capture
+struct ReplayClock {
+    start_time: time::Timespec, // When the replay started
+    first_time: i64,            // Capture time of the first event
+}
+
+impl ReplayClock {
+    fn new(first_time: i64) -> ReplayClock {
+        ReplayClock {
+            start_time: time::now().to_timespec(),
+            first_time: first_time,
+        }
+    }
+    
+    fn wait_until(&self, capture_time: i64) {
+        let elapsed = (time::now().to_timespec() - self.start_time).num_milliseconds();
+        let wait = (capture_time - self.first_time) - elapsed;
+        if wait > 0 {
+            thread::sleep(Duration::milliseconds(wait));
+        }
+    }
+}
\ No newline at end of file
@@ -1,10 +1,81 @@
+use std::fmt;
+
 use vec::Vec2f;
 
 #[derive(Clone, Copy, PartialEq, Eq, Hash, RustcEncodable, RustcDecodable)]
 pub struct SectorId(pub u32);
 
-#[derive(Clone, Copy, RustcEncodable, RustcDecodable)]
+#[derive(Clone, RustcEncodable, RustcDecodable)]
 pub struct SectorData {
     pub id: SectorId,
+    pub name: String,
     pub map_position: Vec2f,
-}
\ No newline at end of file
+    pub environment: SectorEnvironment,
+    
+    // Sectors that can be jumped to from this one
+    pub neighbours: Vec<SectorId>,
+}
+
+// What space is like in a sector, which changes how fights there go
+#[derive(Clone, Copy, Debug, PartialEq, RustcEncodable, RustcDecodable)]
+pub enum SectorEnvironment {
+    Normal,        // Nothing special
+    Nebula,        // Shields recharge at half speed
+    AsteroidField, // Ships randomly get hit by asteroids
+    StarCorona,    // Every few turns, the star sets ships' modules on fire
+    IonStorm,      // Ships' powered modules randomly get knocked out
+}
+
+impl SectorEnvironment {
+    /// What the environment does, for players.
+    pub fn get_description(&self) -> &'static str {
+        match *self {
+            SectorEnvironment::Normal => "Nothing unusual",
+            SectorEnvironment::Nebula => "Shields recharge at half speed",
+            SectorEnvironment::AsteroidField => "Asteroids randomly hit ships",
+            SectorEnvironment::StarCorona => "The star sets modules on fire every few turns",
+            SectorEnvironment::IonStorm => "Ion bursts randomly knock out powered modules",
+        }
+    }
+}
+
+impl fmt::Display for SectorEnvironment {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            SectorEnvironment::Normal => write!(f, "normal space"),
+            SectorEnvironment::Nebula => write!(f, "nebula"),
+            SectorEnvironment::AsteroidField => write!(f, "asteroid field"),
+            SectorEnvironment::StarCorona => write!(f, "star corona"),
+            SectorEnvironment::IonStorm => write!(f, "ion storm"),
+        }
+    }
+}
+
+// Who's in a sector right now, so players can tell how busy and dangerous it is
+#[derive(Clone, RustcEncodable, RustcDecodable)]
+pub struct SectorStatus {
+    pub id: SectorId,
+    pub players: u32,
+    pub ai_ships: u32,
+    pub average_level: f32, // Of all the ships in the sector, 0 if there are none
+}
+
+// Why the server wouldn't let a ship jump
+#[derive(Clone, Copy, Debug, PartialEq, RustcEncodable, RustcDecodable)]
+pub enum JumpError {
+    NoSuchSector,    // There's no sector with that ID
+    AlreadyThere,    // The ship is already in that sector
+    NotReachable,    // There's no jump lane to that sector from here
+    NoWorkingEngine, // The ship has no powered, working engine
+}
+
+impl fmt::Display for JumpError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            JumpError::NoSuchSector => write!(f, "That sector doesn't exist"),
+            JumpError::AlreadyThere => write!(f, "You're already in that sector"),
+            JumpError::NotReachable => write!(f, "There's no jump lane to that sector from here"),
+            JumpError::NoWorkingEngine => write!(f, "Your ship needs a working, powered engine to jump"),
+        }
+    }
+}
@@ -2,17 +2,71 @@ use std::rc::Rc;
 use std::cell::RefCell;
 use std::collections::{HashMap, HashSet};
 use std::ops::{Deref, DerefMut};
+use std::old_io::timer::Timer;
 use std::sync::mpsc::{Sender, Receiver};
+use std::time::Duration;
 use time;
 
 use ai::run_ai;
 use battle_state::{BattleContext, ClientPacketId, ServerPacketId};
-use login::AccountBox;
-use module::Module;
+use environment::roll_environment_events;
+use login::{AccountBox, AccountDbRef, SessionToken};
+use module::{Module, ModulePlans};
 use net::{ClientId, ServerSlot, ServerSlotId, SlotInMsg, InPacket, OutPacket};
 use ship::{Ship, ShipId, ShipRef, ShipStored, ShipNetworked, as_networked_ships};
+use sector_data::{JumpError, SectorData, SectorId, SectorStatus};
 use sim::SimEvents;
 
+// Time between turns, in milliseconds
+static TURN_LENGTH: i64 = 3500;
+
+// How long after a turn's results go out a client joining can still start in the simulation phase, in
+// milliseconds. Clients send their plans 2500ms into the simulation, so any later and they'd miss the turn.
+static SIM_JOIN_WINDOW: i64 = 1000;
+
+// How long a disconnected player's ship stays in the sector waiting for them to come back, in milliseconds
+static RESUME_TIMEOUT: i64 = 60000;
+
+// Time between saves of the players' accounts, in milliseconds
+static SAVE_INTERVAL: i64 = 60000;
+
+// First ID to give to AI ships. Player ships use their client IDs, which stay well below this.
+static FIRST_AI_SHIP_ID: ShipId = 100000000;
+
+// Admin commands for a sector. Each one carries a channel for the answer.
+pub enum SectorCommand {
+    ListShips(Sender<Vec<ShipInfo>>),    // Describe every ship in the sector
+    EndTurn(Sender<()>),                 // Simulate the turn right now instead of waiting for the timer
+    SpawnAi(String, u8, Sender<ShipId>), // Add an AI ship with the given name and level
+    LogOutAll(Sender<()>),               // Send every account back to the login server and kick its client
+}
+
+// What a sector tells admins about one of its ships
+pub struct ShipInfo {
+    pub id: ShipId,
+    pub name: String,
+    pub level: u8,
+    pub hp: u8,
+    pub client_id: Option<ClientId>, // None for AI ships
+}
+
+// What a sector says when asked to let a client resume its session
+pub struct ResumeResult {
+    pub session_token: SessionToken,
+    pub client_id: ClientId,
+    pub sector_id: SectorId,
+    pub resumed: bool, // False if the session's account isn't in the sector
+}
+
+// Things that wake up a sector
+enum SectorEvent {
+    TurnEnd,                        // Time to simulate the next turn
+    Slot(SlotInMsg),
+    Account(AccountBox),            // Account arrived from the star map
+    Resume(SessionToken, ClientId), // Client wants its disconnected ship back (session_token, new client_id)
+    Command(SectorCommand),         // Admin wants something done
+}
+
 pub struct SectorState {
     slot: ServerSlot,
     star_map_slot_id: ServerSlotId,
@@ -21,7 +75,6 @@ pub struct SectorState {
     context: BattleContext,
     
     turn_start_time: time::Timespec,
-    sent_results: bool,
     
     received_plans: HashSet<ClientId>,
     clients_waiting: HashSet<ClientId>,
@@ -30,6 +83,10 @@ pub struct SectorState {
     // All the clients' accounts
     accounts: HashMap<ClientId, AccountBox>,
     
+    // Clients that lost their connection, and when. Their ships are flown by the AI until they
+    // resume their session or run out of time.
+    disconnected_clients: HashMap<ClientId, time::Timespec>,
+    
     // Ships to add after simulation
     ships_to_add: Vec<ShipRef>,
     
@@ -38,6 +95,21 @@ pub struct SectorState {
     
     turn_number: u32,
     
+    // ID to give to the next AI ship
+    next_ai_ship_id: ShipId,
+    
+    // Where the players' accounts get saved every so often, if anywhere
+    account_db: Option<AccountDbRef>,
+    last_save_time: time::Timespec,
+    
+    // This sector's ID and every sector on the star map, for checking jumps. Without a star map,
+    // no jump goes anywhere.
+    sector_id: SectorId,
+    star_map: HashMap<SectorId, SectorData>,
+    
+    // Where to tell the star map who's in the sector after every turn, if anywhere
+    status_sender: Option<Sender<SectorStatus>>,
+    
     debug: bool,
 }
 
@@ -48,101 +120,343 @@ impl SectorState {
             star_map_slot_id: star_map_slot_id,
             context: context,
             turn_start_time: time::now().to_timespec(),
-            sent_results: false,
             received_plans: HashSet::new(),
             clients_waiting: HashSet::new(),
             clients_active: HashSet::new(),
             accounts: HashMap::new(),
+            disconnected_clients: HashMap::new(),
             ships_to_add: vec!(),
             ships_to_remove: vec!(),
             turn_number: 0,
+            next_ai_ship_id: FIRST_AI_SHIP_ID,
+            account_db: None,
+            last_save_time: time::now().to_timespec(),
+            sector_id: SectorId(0),
+            star_map: HashMap::new(),
+            status_sender: None,
             debug: debug,
         }
     }
     
-    pub fn run(&mut self, to_map_sender: Sender<AccountBox>, from_map_receiver: Receiver<AccountBox>, ack: Sender<()>, create_ai: bool) {
-        if create_ai {
-            // TODO: come up with better way to generate AI ship IDs
-            let ai_ship = Ship::generate((100000000) as ShipId, "n00bslayer808".to_string(), 2);
-            self.context.add_ship(Rc::new(RefCell::new(ai_ship)));
-            
-            let ai_ship = Ship::generate((100000001) as ShipId, "thing1".to_string(), 2);
-            self.context.add_ship(Rc::new(RefCell::new(ai_ship)));
-            
-            let ai_ship = Ship::generate((100000002) as ShipId, "thing2".to_string(), 2);
-            self.context.add_ship(Rc::new(RefCell::new(ai_ship)));
-            
-            let ai_ship = Ship::generate((100000003) as ShipId, "daisy_girl".to_string(), 2);
-            self.context.add_ship(Rc::new(RefCell::new(ai_ship)));
+    /// Makes the sector save its players' accounts to the database every so often.
+    pub fn set_account_db(&mut self, account_db: AccountDbRef) {
+        self.account_db = Some(account_db);
+    }
+    
+    /// Tells the sector which sector it is and what the star map looks like, so it can tell which
+    /// jumps its ships are allowed to make and what its environment is.
+    pub fn set_star_map(&mut self, sector_id: SectorId, sectors: Vec<SectorData>) {
+        self.sector_id = sector_id;
+        self.star_map = sectors.into_iter().map(|sector| (sector.id, sector)).collect();
+        
+        if let Some(sector) = self.star_map.get(&sector_id) {
+            self.context.environment = sector.environment;
         }
+    }
+    
+    /// Makes the sector report who's in it through the given channel after every turn.
+    pub fn set_status_sender(&mut self, status_sender: Sender<SectorStatus>) {
+        self.status_sender = Some(status_sender);
+    }
+    
+    pub fn run(&mut self, to_map_sender: Sender<AccountBox>, from_map_receiver: Receiver<AccountBox>, ack: Sender<()>,
+               resume_receiver: Receiver<(SessionToken, ClientId)>, resumed_sender: Sender<ResumeResult>,
+               logout_sender: Sender<AccountBox>, command_receiver: Receiver<SectorCommand>, ai_ships: Vec<(String, u8)>) {
+        // Start off with the sector's AI ships (name, level)
+        for (name, level) in ai_ships.into_iter() {
+            self.add_ai_ship(name, level);
+        }
+    
+        // Wakes the sector up when it's time to simulate the next turn
+        let mut turn_timer = Timer::new().ok().expect("Failed to create turn timer");
+        let mut turn_end = turn_timer.oneshot(Duration::milliseconds(TURN_LENGTH));
     
         loop {
-            ///////////////////////////////////////////////////////////
-            // Check if it's time to simulate next turn
-            
-            // Get the current time from our turn timer
-            let turn_time = time::now().to_timespec() - self.turn_start_time;
-            
-            if turn_time.num_milliseconds() > 3500 {
-                self.simulate_next_turn(&to_map_sender);
+            // Sleep until something happens
+            let event = {
+                let slot_receiver = self.slot.get_receiver();
+                let turn_end = &turn_end;
+                let from_map_receiver = &from_map_receiver;
+                let resume_receiver = &resume_receiver;
+                let command_receiver = &command_receiver;
                 
-                // Reset the turn stuff
-                self.turn_start_time = time::now().to_timespec();
-                self.sent_results = false;
-            }
-        
-            ///////////////////////////////////////////////////////////
-            // Receiver ServerSlot messages
-            if let Ok(msg) = self.slot.try_receive() {
-                match msg {
+                select! {
+                    _ = turn_end.recv() => SectorEvent::TurnEnd,
+                    command = command_receiver.recv() => SectorEvent::Command(command.ok().expect("Sector command channel closed")),
+                    msg = slot_receiver.recv() => SectorEvent::Slot(msg.ok().expect("Sector slot closed")),
+                    account = from_map_receiver.recv() => SectorEvent::Account(account.ok().expect("Star map channel closed")),
+                    resume = resume_receiver.recv() => {
+                        let (session_token, client_id) = resume.ok().expect("Resume channel closed");
+                        SectorEvent::Resume(session_token, client_id)
+                    }
+                }
+            };
+            
+            match event {
+                SectorEvent::TurnEnd => {
+                    self.end_turn(&to_map_sender, &logout_sender);
+                    turn_end = turn_timer.oneshot(Duration::milliseconds(TURN_LENGTH));
+                },
+                SectorEvent::Slot(msg) => match msg {
                     SlotInMsg::Joined(client_id) => {
                         println!("Client {} joined battle {}", client_id, self.slot.get_id());
                     },
                     SlotInMsg::ReceivedPacket(client_id, mut packet) => { self.handle_packet(client_id, &mut packet); },
-                    _ => {}
-                }
+                    SlotInMsg::Disconnected(client_id) => { self.handle_disconnect(client_id); },
+                },
+                SectorEvent::Account(account) => {
+                    self.add_client(account);
+                    ack.send(());
+                },
+                SectorEvent::Resume(session_token, client_id) => {
+                    // Let a reconnected client take back its ship
+                    let resumed = self.resume_client(session_token, client_id);
+                    resumed_sender.send(ResumeResult {
+                        session_token: session_token,
+                        client_id: client_id,
+                        sector_id: self.sector_id,
+                        resumed: resumed,
+                    });
+                },
+                SectorEvent::Command(command) => match command {
+                    SectorCommand::ListShips(reply) => {
+                        let ships =
+                            self.context.ships_list.iter()
+                                .map(|ship| {
+                                    let ship = ship.borrow();
+                                    ShipInfo {
+                                        id: ship.id,
+                                        name: ship.name.clone(),
+                                        level: ship.level,
+                                        hp: ship.state.get_hp(),
+                                        client_id: ship.client_id,
+                                    }
+                                })
+                                .collect();
+                        reply.send(ships);
+                    },
+                    SectorCommand::EndTurn(done) => {
+                        println!("Admin ended turn {} in battle {}", self.turn_number, self.slot.get_id());
+                        self.end_turn(&to_map_sender, &logout_sender);
+                        turn_end = turn_timer.oneshot(Duration::milliseconds(TURN_LENGTH));
+                        done.send(());
+                    },
+                    SectorCommand::SpawnAi(name, level, reply) => {
+                        let ship = self.add_ai_ship(name, level);
+                        self.ships_to_add.push(ship.clone());
+                        reply.send(ship.borrow().id);
+                    },
+                    SectorCommand::LogOutAll(done) => {
+                        let client_ids: Vec<ClientId> = self.accounts.keys().map(|client_id| *client_id).collect();
+                        for client_id in client_ids.into_iter() {
+                            let connected = !self.disconnected_clients.contains_key(&client_id);
+                            self.log_out_client(client_id, &logout_sender);
+                            if connected {
+                                self.slot.disconnect_client(client_id);
+                            }
+                        }
+                        done.send(());
+                    },
+                },
             }
-            
-            ///////////////////////////////////////////////////////////
-            // Receive new clients
-            if let Ok(mut account) = from_map_receiver.try_recv() {
-                if self.debug {
-                    println!("Receiving account");
+        }
+    }
+    
+    // Simulates the next turn and gets everything ready for the one after it
+    fn end_turn(&mut self, to_map_sender: &Sender<AccountBox>, logout_sender: &Sender<AccountBox>) {
+        self.simulate_next_turn(to_map_sender);
+        
+        // Reset the turn stuff
+        self.turn_start_time = time::now().to_timespec();
+        
+        // Log out disconnected clients that didn't come back in time
+        let now = time::now().to_timespec();
+        let expired_clients: Vec<ClientId> =
+            self.disconnected_clients.iter()
+                .filter(|&(_, disconnect_time)| (now - *disconnect_time).num_milliseconds() > RESUME_TIMEOUT)
+                .map(|(client_id, _)| *client_id)
+                .collect();
+        for client_id in expired_clients.into_iter() {
+            self.log_out_client(client_id, logout_sender);
+        }
+        
+        if (now - self.last_save_time).num_milliseconds() > SAVE_INTERVAL {
+            self.save_accounts();
+            self.last_save_time = now;
+        }
+    }
+    
+    // Saves every player's account with their ship as it is right now, so a crash doesn't lose much
+    fn save_accounts(&self) {
+        let account_db =
+            match self.account_db {
+                Some(ref account_db) => account_db,
+                None => { return; },
+            };
+        
+        let mut account_db = account_db.lock().unwrap();
+        for (client_id, account) in self.accounts.iter() {
+            if let Some(ship) = self.context.ships_client_id.get(client_id) {
+                if let Err(e) = account_db.save_in_sector(account, ship.borrow().deref()) {
+                    println!("Failed to save account {}: {}", account.username, e);
                 }
-                let client_id = account.client_id.expect("This must have a client ID");
-                
-                // Add the client to the waiting list
-                self.clients_waiting.insert(client_id);
-                
-                // Get the ship out of storage
-                let ship_stored = account.ship.take().expect("This account must have a ship");
-                let ship = ship_stored.to_ship(Some(client_id));
-                
-                // Add the player's account
-                self.accounts.insert(client_id, account);
-                
-                // Send initial join packet
-                let mut packet = OutPacket::new();
-                packet.write(&ShipNetworked::from_ship(&ship));
-                packet.write(&self.sent_results); // Whether or not to start at simulation instead of planning phase
-                packet.write(&as_networked_ships(&self.context.ships_list)).unwrap();
-                self.slot.send(client_id, packet);
-                
-                // Add the player's ship
-                let ship = Rc::new(RefCell::new(ship));
-                self.context.add_ship(ship.clone());
-                self.ships_to_add.push(ship);
-                
-                ack.send(());
             }
         }
     }
     
+    // Adds a ship flown by the AI
+    fn add_ai_ship(&mut self, name: String, level: u8) -> ShipRef {
+        let ship_id = self.next_ai_ship_id;
+        self.next_ai_ship_id += 1;
+        
+        let ship = Rc::new(RefCell::new(Ship::generate(ship_id, name, level)));
+        self.context.add_ship(ship.clone());
+        ship
+    }
+    
+    // Adds a client that just arrived from the star map
+    fn add_client(&mut self, mut account: AccountBox) {
+        if self.debug {
+            println!("Receiving account");
+        }
+        let client_id = account.client_id.expect("This must have a client ID");
+        
+        // Add the client to the waiting list
+        self.clients_waiting.insert(client_id);
+        
+        // Get the ship out of storage
+        let ship_stored = account.ship.take().expect("This account must have a ship");
+        let ship = ship_stored.to_ship(Some(client_id));
+        
+        // Add the player's account
+        self.accounts.insert(client_id, account);
+        
+        // Send initial join packet
+        self.send_join_packet(client_id, &ship);
+        
+        // Add the player's ship
+        let ship = Rc::new(RefCell::new(ship));
+        self.context.add_ship(ship.clone());
+        self.ships_to_add.push(ship);
+    }
+    
+    // Whether clients are still early enough into showing the last turn's results for a joining client to
+    // start there and get its plans in for the next turn
+    fn is_simulating(&self) -> bool {
+        self.turn_number > 0 && (time::now().to_timespec() - self.turn_start_time).num_milliseconds() < SIM_JOIN_WINDOW
+    }
+    
+    fn send_join_packet(&self, client_id: ClientId, ship: &Ship) {
+        let other_ships: Vec<ShipRef> = self.context.ships_list.iter().filter(|s| s.borrow().id != ship.id).map(|s| s.clone()).collect();
+    
+        let mut packet = OutPacket::new();
+        packet.write(&ClientPacketId::JoinSector).ok().expect("Failed to write join packet ID");
+        packet.write(&ShipNetworked::from_ship(ship));
+        packet.write(&self.is_simulating()); // Whether or not to start at simulation instead of planning phase
+        packet.write(&as_networked_ships(&other_ships)).unwrap();
+        packet.write(&self.context.environment).ok().expect("Failed to write sector environment");
+        self.slot.send(client_id, packet);
+        
+        self.send_progress(client_id);
+    }
+    
+    fn handle_disconnect(&mut self, client_id: ClientId) {
+        println!("Client {} disconnected from battle {}", client_id, self.slot.get_id());
+        
+        self.received_plans.remove(&client_id);
+        self.clients_waiting.remove(&client_id);
+        self.clients_active.remove(&client_id);
+        
+        if !self.accounts.contains_key(&client_id) {
+            return; // The account already left for the star map
+        }
+        
+        // Keep the ship around for a while in case the client comes back. It shouldn't jump
+        // anywhere without its player.
+        self.context.get_ship_by_client_id(client_id).borrow_mut().target_sector = None;
+        self.disconnected_clients.insert(client_id, time::now().to_timespec());
+    }
+    
+    // Gives a disconnected client's ship and account to the client resuming its session. Returns
+    // false if the session's account isn't waiting here.
+    fn resume_client(&mut self, session_token: SessionToken, client_id: ClientId) -> bool {
+        let old_client_id =
+            match self.accounts.iter().find(|&(_, account)| account.session_token == Some(session_token)) {
+                Some((old_client_id, _)) => *old_client_id,
+                None => { return false; },
+            };
+        
+        if self.disconnected_clients.remove(&old_client_id).is_none() {
+            // The server hasn't noticed the old connection is dead yet. Only the player has the
+            // token, so drop the old connection and let the new one have the ship.
+            println!("Kicking client {} so client {} can resume its session", old_client_id, client_id);
+            self.slot.kick_client(old_client_id);
+            self.received_plans.remove(&old_client_id);
+            self.clients_waiting.remove(&old_client_id);
+            self.clients_active.remove(&old_client_id);
+        }
+        
+        println!("Client {} resumed client {}'s session in battle {}", client_id, old_client_id, self.slot.get_id());
+        
+        let mut account = self.accounts.remove(&old_client_id).expect("Account must exist here");
+        account.client_id = Some(client_id);
+        self.accounts.insert(client_id, account);
+        self.context.change_client_id(old_client_id, client_id);
+        
+        // Start the client over like it just joined
+        self.clients_waiting.insert(client_id);
+        let ship = self.context.get_ship_by_client_id(client_id).clone();
+        self.send_join_packet(client_id, ship.borrow().deref());
+        
+        true
+    }
+    
+    // Takes a client's ship out of the sector and returns its account to the login server
+    fn log_out_client(&mut self, client_id: ClientId, logout_sender: &Sender<AccountBox>) {
+        use std::rc::try_unwrap;
+        
+        println!("Logging out client {} from battle {}", client_id, self.slot.get_id());
+        
+        self.disconnected_clients.remove(&client_id);
+        self.received_plans.remove(&client_id);
+        self.clients_waiting.remove(&client_id);
+        self.clients_active.remove(&client_id);
+        
+        let mut account =
+            match self.accounts.remove(&client_id) {
+                Some(account) => account,
+                None => { return; },
+            };
+        
+        let ship =
+            match self.context.ships_client_id.get(&client_id) {
+                Some(ship) => ship.clone(),
+                None => panic!("Disconnected client {} has an account but no ship", client_id),
+            };
+        let ship_id = ship.borrow().id;
+        
+        // Take the ship out of the sector
+        self.ships_to_add.retain(|s| s.borrow().id != ship_id);
+        self.ships_to_remove.push(ship_id);
+        self.context.remove_ship(ship_id);
+        
+        let mut ship = try_unwrap(ship).ok().expect("Failed to unwrap disconnected ship").into_inner();
+        
+        // Same as when jumping, the plans made during the last simulation are thrown away.
+        ship.state.plan_power = ship.state.power;
+        ship.target_sector = None;
+        
+        // Save the ship back into the account and return it to the login server
+        account.ship = Some(ShipStored::from_ship(ship));
+        logout_sender.send(account);
+    }
+    
     fn handle_packet(&mut self, client_id: ClientId, packet: &mut InPacket) {
         let id: ServerPacketId = match packet.read() {
             Ok(id) => id,
             Err(e) => {
                 println!("Received invalid packet from client {}: {}", client_id, e);
+                self.slot.disconnect_client(client_id);
                 return;
             }
         };
@@ -153,10 +467,14 @@ impl SectorState {
                     println!("Handling plans packet");
                 }
             
-                self.received_plans.insert(client_id);
-                
                 // Handle the plans
-                self.handle_plans_packet(client_id, packet);
+                if let Err(e) = self.handle_plans_packet(client_id, packet) {
+                    println!("Received bad plans from client {}: {}", client_id, e);
+                    self.slot.disconnect_client(client_id);
+                    return;
+                }
+                
+                self.received_plans.insert(client_id);
                 
                 println!("Received plans packet from {} for turn {}", client_id, self.turn_number);
  
@@ -167,12 +485,34 @@ impl SectorState {
         }
     }
     
-    fn handle_plans_packet(&mut self, client_id: ClientId, packet: &mut InPacket) {
-        let mut ship = self.context.get_ship_by_client_id(client_id).borrow_mut();
-    
-        ship.target_sector = packet.read().ok().expect("Failed to read player's target sector");
-        let plans = packet.read().ok().expect("Failed to read player's plans");
-        ship.set_module_plans(&self.context, &plans);
+    // Reads a client's plans and gives them to its ship. Nothing from the packet is used unless all
+    // of it checks out.
+    fn handle_plans_packet(&mut self, client_id: ClientId, packet: &mut InPacket) -> Result<(), String> {
+        let ship =
+            match self.context.ships_client_id.get(&client_id) {
+                Some(ship) => ship.clone(),
+                None => { return Err(format!("client has no ship in this sector")); },
+            };
+        
+        let target_sector: Option<SectorId> = try!(packet.read().map_err(|e| format!("failed to read target sector: {}", e)));
+        let plans: Vec<ModulePlans> = try!(packet.read().map_err(|e| format!("failed to read plans: {}", e)));
+        
+        if plans.len() != ship.borrow().modules.len() {
+            return Err(format!("got plans for {} modules, ship has {}", plans.len(), ship.borrow().modules.len()));
+        }
+        
+        for module_plans in plans.iter() {
+            if let Some(ref target) = module_plans.plan_target {
+                if !target.is_valid(&self.context) {
+                    return Err(format!("plans target something that doesn't exist"));
+                }
+            }
+        }
+        
+        ship.borrow_mut().target_sector = target_sector;
+        ship.borrow().set_module_plans(&self.context, &plans);
+        
+        Ok(())
     }
     
     fn simulate_next_turn(&mut self, to_map_sender: &Sender<AccountBox>) {
@@ -183,32 +523,52 @@ impl SectorState {
         // Send new ships to added/removed before simulation
         self.send_new_ships();
     
-        // Run AI on ships with no client
+        // Run AI on ships with no client, or whose client is disconnected
         for ship in self.context.ships_list.iter() {
             let ship_id = ship.borrow().id;
             let enemies = &self.context.ships_list.iter().filter(|s| s.borrow().id != ship_id).map(|s| s.clone()).collect();
             
             let mut ship = ship.borrow_mut();
-            if ship.client_id.is_none() {
+            let has_player =
+                match ship.client_id {
+                    Some(client_id) => !self.disconnected_clients.contains_key(&client_id),
+                    None => false,
+                };
+            if !has_player {
                 // Run AI
                 run_ai(ship.deref_mut(), enemies);
             }
         }
         
-        // Let the ships that want to jump jump, if they can
+        // Apply all the plans
+        self.context.apply_module_plans();
+        
+        // Let the ships that want to jump jump, if they can. This comes after the plans so the
+        // engines the player powered this turn count.
         for ship in self.context.ships_list.iter() {
             let mut ship = ship.borrow_mut();
-            if ship.target_sector.is_some() {
-                ship.jumping = true;
+            if let Some(target_sector) = ship.target_sector {
+                match check_jump(&self.star_map, self.sector_id, ship.deref(), target_sector) {
+                    Ok(()) => {
+                        ship.jumping = true;
+                    },
+                    Err(e) => {
+                        ship.target_sector = None;
+                        if let Some(client_id) = ship.client_id {
+                            self.send_jump_failed(client_id, e);
+                        }
+                    },
+                }
             }
         }
-        
-        // Apply all the plans
-        self.context.apply_module_plans();
     
         // Do server-side precalculations
         self.context.server_preprocess();
         
+        // Decide what the environment does this turn. It goes out with the results and gets applied
+        // during the simulation.
+        self.context.environment_events = roll_environment_events(self.context.environment, &self.context, self.turn_number);
+        
         // Send the results packet
         let mut results_packet = self.build_results_packet();
         self.slot.broadcast(results_packet);
@@ -216,6 +576,9 @@ impl SectorState {
         // Run the simulation
         self.do_simulation();
         
+        // Reward players for what their ships did
+        self.update_progress();
+        
         // Finish the results packet with ships to add and remove
         let mut new_ships = vec!();
         let mut dead_ships = vec!();
@@ -277,13 +640,17 @@ impl SectorState {
                 
                 let mut account = self.accounts.remove(&client_id).expect("Client's account must exist here.");
                 account.ship = Some(ship_stored);
+                account.progress.add_jump();
                 
-                self.slot.transfer_client(client_id, self.star_map_slot_id);
-                
+                // The account goes first so it's there by the time the star map hears about the client
                 to_map_sender.send(account);
+                
+                self.slot.transfer_client(client_id, self.star_map_slot_id);
             }
         }
         
+        self.report_status();
+        
         // Reset everything for the next turn
         self.received_plans.clear();
         self.turn_number += 1;
@@ -292,10 +659,105 @@ impl SectorState {
         self.clients_active = self.clients_active.union(&self.clients_waiting).map(|&x| x).collect();
     }
     
+    // Credits players with the damage they dealt, the ships they destroyed and the times they were
+    // destroyed during the last simulation, and tells them about it
+    fn update_progress(&mut self) {
+        let mut changed_clients = HashSet::new();
+        
+        for ship in self.context.ships_list.iter() {
+            let ship = ship.borrow();
+            
+            for &(attacker, damage) in ship.damage_log.iter() {
+                let attacker_client_id = attacker.and_then(|a| self.context.ships.get(&a)).and_then(|s| s.borrow().client_id);
+                if let Some(client_id) = attacker_client_id {
+                    if let Some(account) = self.accounts.get_mut(&client_id) {
+                        account.progress.add_damage_dealt(damage);
+                        changed_clients.insert(client_id);
+                    }
+                }
+            }
+            
+            if ship.state.get_hp() > 0 {
+                continue;
+            }
+            
+            // The kill goes to whoever dealt the last hit, unless the environment did
+            if let Some(&(Some(killer), _)) = ship.damage_log.last() {
+                let killer_client_id = self.context.ships.get(&killer).and_then(|s| s.borrow().client_id);
+                if let Some(client_id) = killer_client_id {
+                    if let Some(account) = self.accounts.get_mut(&client_id) {
+                        account.progress.add_kill(ship.level);
+                        changed_clients.insert(client_id);
+                    }
+                }
+            }
+            
+            if let Some(client_id) = ship.client_id {
+                if let Some(account) = self.accounts.get_mut(&client_id) {
+                    account.progress.add_death();
+                    changed_clients.insert(client_id);
+                }
+            }
+        }
+        
+        for client_id in changed_clients.into_iter() {
+            self.send_progress(client_id);
+        }
+    }
+    
+    // Tells the star map how many players and AI ships are in the sector, and how strong they are
+    fn report_status(&self) {
+        if let Some(ref status_sender) = self.status_sender {
+            let mut players = 0;
+            let mut ai_ships = 0;
+            let mut total_level = 0;
+            for ship in self.context.ships_list.iter() {
+                let ship = ship.borrow();
+                
+                // Ships of disconnected clients are flown by the AI until their players come back
+                let has_player =
+                    match ship.client_id {
+                        Some(client_id) => !self.disconnected_clients.contains_key(&client_id),
+                        None => false,
+                    };
+                if has_player {
+                    players += 1;
+                } else {
+                    ai_ships += 1;
+                }
+                total_level += ship.level as u32;
+            }
+            
+            let ship_count = players + ai_ships;
+            status_sender.send(SectorStatus {
+                id: self.sector_id,
+                players: players,
+                ai_ships: ai_ships,
+                average_level: if ship_count > 0 { (total_level as f32)/(ship_count as f32) } else { 0.0 },
+            });
+        }
+    }
+    
+    fn send_jump_failed(&self, client_id: ClientId, error: JumpError) {
+        let mut packet = OutPacket::new();
+        packet.write(&ClientPacketId::JumpFailed).ok().expect("Failed to write jump failed packet ID");
+        packet.write(&error).ok().expect("Failed to write jump error");
+        self.slot.send(client_id, packet);
+    }
+    
+    fn send_progress(&self, client_id: ClientId) {
+        if let Some(account) = self.accounts.get(&client_id) {
+            let mut packet = OutPacket::new();
+            packet.write(&ClientPacketId::Progress).ok().expect("Failed to write progress packet ID");
+            packet.write(&account.progress).ok().expect("Failed to write account progress");
+            self.slot.send(client_id, packet);
+        }
+    }
+    
     fn do_simulation(&mut self) {
         let mut sim_events = SimEvents::new();
     
-        // Pre simulation
+        // Pre simulation, which is also when the environment's events get lined up
         self.context.before_simulation(&mut sim_events);
         
         // Simulation!!!
@@ -330,6 +792,7 @@ impl SectorState {
         }
     
         let mut ships_packet = OutPacket::new();
+        ships_packet.write(&ClientPacketId::NewShips).ok().expect("Failed to write new ships packet ID");
         ships_packet.write(&as_networked_ships(&self.ships_to_add));
         ships_packet.write(&self.ships_to_remove);
         self.slot.broadcast(ships_packet);
@@ -338,3 +801,73 @@ impl SectorState {
         self.ships_to_remove.clear();
     }
 }
+
+// Checks whether a ship in one sector can jump to the target sector
+fn check_jump(star_map: &HashMap<SectorId, SectorData>, sector_id: SectorId, ship: &Ship, target_sector: SectorId) -> Result<(), JumpError> {
+    if !star_map.contains_key(&target_sector) {
+        return Err(JumpError::NoSuchSector);
+    }
+    
+    if target_sector == sector_id {
+        return Err(JumpError::AlreadyThere);
+    }
+    
+    let reachable = star_map.get(&sector_id).map_or(false, |here| here.neighbours.contains(&target_sector));
+    if !reachable {
+        return Err(JumpError::NotReachable);
+    }
+    
+    if ship.state.thrust == 0 {
+        return Err(JumpError::NoWorkingEngine);
+    }
+    
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    
+    use sector_data::{JumpError, SectorData, SectorEnvironment, SectorId};
+    use ship::Ship;
+    use vec::Vec2;
+    use super::check_jump;
+    
+    // Three sectors in a row: 0 - 1 - 2
+    fn star_map_in_a_row() -> HashMap<SectorId, SectorData> {
+        let mut star_map = HashMap::new();
+        for &(id, ref neighbours) in [(0, vec![1]), (1, vec![0, 2]), (2, vec![1])].iter() {
+            star_map.insert(SectorId(id), SectorData {
+                id: SectorId(id),
+                name: format!("Sector {}", id),
+                map_position: Vec2 { x: id as f64*100.0, y: 0.0 },
+                environment: SectorEnvironment::Normal,
+                neighbours: neighbours.iter().map(|&id| SectorId(id)).collect(),
+            });
+        }
+        star_map
+    }
+    
+    #[test]
+    fn checks_where_ships_can_jump() {
+        let star_map = star_map_in_a_row();
+        let mut ship = Ship::new(1, "test".to_string(), 1);
+        ship.state.thrust = 1;
+        
+        assert_eq!(check_jump(&star_map, SectorId(0), &ship, SectorId(1)), Ok(()));
+        assert_eq!(check_jump(&star_map, SectorId(1), &ship, SectorId(0)), Ok(()));
+        assert_eq!(check_jump(&star_map, SectorId(1), &ship, SectorId(2)), Ok(()));
+        
+        assert_eq!(check_jump(&star_map, SectorId(0), &ship, SectorId(7)), Err(JumpError::NoSuchSector));
+        assert_eq!(check_jump(&star_map, SectorId(1), &ship, SectorId(1)), Err(JumpError::AlreadyThere));
+        assert_eq!(check_jump(&star_map, SectorId(0), &ship, SectorId(2)), Err(JumpError::NotReachable));
+    }
+    
+    #[test]
+    fn ships_without_thrust_cant_jump() {
+        let mut ship = Ship::new(1, "test".to_string(), 1);
+        ship.state.thrust = 0;
+        
+        assert_eq!(check_jump(&star_map_in_a_row(), SectorId(0), &ship, SectorId(1)), Err(JumpError::NoWorkingEngine));
+    }
+}
@@ -5,6 +5,7 @@
 #![feature(core)]
 #![feature(os)]
 #![feature(io)]
+#![feature(fs)]
 #![feature(old_io)]
 #![feature(alloc)]
 #![feature(thread_sleep)]
@@ -12,21 +13,35 @@
 #![feature(std_misc)]
 
 extern crate bincode;
+extern crate crypto;
+extern crate flate2;
 extern crate time;
 extern crate rustc_serialize;
 
+use std::os;
+use std::path::Path;
 use std::thread::Thread;
 use std::sync::mpsc::channel;
 
+use galaxy::Galaxy;
+use galaxy_gen::{DEFAULT_GALAXY_SECTORS, generate_galaxy};
+use login::{AccountDb, AccountManager};
 use net::Server;
+use net_sim::NetworkConditions;
 use star_map_server::StarMapServer;
 
+mod admin;
 mod ai;
 mod battle_state;
 mod battle_type;
+mod environment;
+mod galaxy;
+mod galaxy_gen;
 mod login;
 mod module;
 mod net;
+mod net_sim;
+mod replay;
 mod sector_data;
 mod sector_state;
 mod ship;
@@ -37,21 +52,109 @@ mod vec;
 mod star_map_server;
 
 fn main() {
+    let args = os::args();
+    
+    // Replay a captured sector instead of serving with: --replay-sector <capture file> <slot ID> [--ai]
+    if let Some(i) = args.iter().position(|arg| arg.as_slice() == "--replay-sector") {
+        let path = args.get(i + 1).expect("Missing capture file for --replay-sector");
+        let slot_id = args.get(i + 2).and_then(|s| s.parse().ok()).expect("Missing slot ID for --replay-sector");
+        let create_ai = args.iter().any(|arg| arg.as_slice() == "--ai");
+        
+        let records = net::read_capture(&Path::new(path)).ok().expect("Failed to read capture file");
+        replay::replay_into_sector(records, slot_id, create_ai);
+        return;
+    }
+    
     // Start a local server
     let mut server = Server::new();
+    
+    // Record all the traffic if asked to with --capture <capture file>
+    if let Some(i) = args.iter().position(|arg| arg.as_slice() == "--capture") {
+        let path = args.get(i + 1).expect("Missing capture file for --capture");
+        server.set_capture_file(&Path::new(path)).ok().expect("Failed to create capture file");
+    }
+    
+    // Simulate a bad connection to every client if asked to with --netsim
+    if let Some(conditions) = NetworkConditions::from_args(args.as_slice()) {
+        println!("Simulating network conditions: {:?}", conditions);
+        server.set_network_conditions(conditions);
+    }
+    
+    // Drop clients that go quiet for longer than --heartbeat-timeout <milliseconds>
+    if let Some(i) = args.iter().position(|arg| arg.as_slice() == "--heartbeat-timeout") {
+        let timeout = args.get(i + 1).and_then(|s| s.parse().ok()).expect("Missing milliseconds for --heartbeat-timeout");
+        server.set_heartbeat_timeout(timeout);
+    }
+    
+    // Keep accounts in the file given with --accounts <file>, or accounts.db
+    let accounts_path =
+        match args.iter().position(|arg| arg.as_slice() == "--accounts") {
+            Some(i) => args.get(i + 1).expect("Missing file for --accounts").clone(),
+            None => "accounts.db".to_string(),
+        };
+    let account_db = AccountDb::open_shared(&Path::new(accounts_path.as_slice())).ok().expect("Failed to open account database");
+    let mut account_manager = AccountManager::with_db(account_db.clone());
+    
+    // Generate the sectors if asked to with --galaxy-seed <seed> [--galaxy-sectors <count>]. Otherwise load
+    // them from the file given with --galaxy <file>, or the default galaxy
+    let galaxy =
+        if let Some(i) = args.iter().position(|arg| arg.as_slice() == "--galaxy-seed") {
+            let seed = args.get(i + 1).and_then(|s| s.parse().ok()).expect("Missing seed for --galaxy-seed");
+            let sector_count =
+                match args.iter().position(|arg| arg.as_slice() == "--galaxy-sectors") {
+                    Some(i) => args.get(i + 1).and_then(|s| s.parse().ok()).expect("Missing sector count for --galaxy-sectors"),
+                    None => DEFAULT_GALAXY_SECTORS,
+                };
+            println!("Generating a galaxy of {} sectors with seed {}", sector_count, seed);
+            generate_galaxy(seed, sector_count)
+        } else {
+            let galaxy_path =
+                match args.iter().position(|arg| arg.as_slice() == "--galaxy") {
+                    Some(i) => args.get(i + 1).expect("Missing file for --galaxy").clone(),
+                    None => galaxy::DEFAULT_GALAXY_PATH.to_string(),
+                };
+            match Galaxy::load(&Path::new(galaxy_path.as_slice())) {
+                Ok(galaxy) => galaxy,
+                Err(e) => panic!("{}", e),
+            }
+        };
+    account_manager.set_start_sector(galaxy.get_start_sector());
+    
     let login_slot = server.create_slot();
     let star_map_slot = server.create_slot();
     let star_map_slot_id = star_map_slot.get_id();
     let (star_map_account_sender, star_map_account_receiver) = channel();
+    let (resume_sender, resume_receiver) = channel();
+    let (logout_sender, logout_receiver) = channel();
+    let (login_command_sender, login_command_receiver) = channel();
+    let (star_map_command_sender, star_map_command_receiver) = channel();
+    
+    // Let admins manage the server if asked to with --admin-password <password> [--admin-address <address>]
+    if let Some(i) = args.iter().position(|arg| arg.as_slice() == "--admin-password") {
+        let password = args.get(i + 1).expect("Missing password for --admin-password").clone();
+        let address =
+            match args.iter().position(|arg| arg.as_slice() == "--admin-address") {
+                Some(i) => args.get(i + 1).expect("Missing address for --admin-address").clone(),
+                None => admin::DEFAULT_ADMIN_ADDRESS.to_string(),
+            };
+        let admin_slot = server.create_slot();
+        let login_command_sender = login_command_sender.clone();
+        let star_map_command_sender = star_map_command_sender.clone();
+        
+        Thread::spawn(move || {
+            admin::run_admin_console(address.as_slice(), password, admin_slot, login_command_sender, star_map_command_sender);
+        });
+    }
     
     Thread::spawn(move || {
         server.listen("0.0.0.0:30000");
     });
     
     Thread::spawn(move || {
-        login::run_login_server(login_slot, star_map_slot_id, star_map_account_sender);
+        login::run_login_server(account_manager, login_slot, star_map_slot_id, star_map_account_sender, resume_sender, logout_receiver, login_command_receiver);
     });
     
-    let mut star_map_server = StarMapServer::new(star_map_slot);
-    star_map_server.run(star_map_account_receiver);
+    // Runs until an admin shuts the server down
+    let mut star_map_server = StarMapServer::new(star_map_slot, logout_sender, Some(account_db), galaxy);
+    star_map_server.run(star_map_account_receiver, resume_receiver, star_map_command_receiver);
 }
@@ -3,6 +3,8 @@ use std::cell::RefCell;
 use std::cmp;
 use std::marker::Reflect;
 
+use rustc_serialize::{Decodable, Decoder, Encodable, Encoder};
+
 use battle_state::BattleContext;
 use module;
 use module::{
@@ -96,7 +98,8 @@ impl ShipState {
         self.shields = 0;
     }
     
-    pub fn deal_damage(&mut self, modules: &Vec<ModuleRef>, module: &mut ModuleBox, damage: u8) {
+    // Returns how much damage the shields or the modules took
+    pub fn deal_damage(&mut self, modules: &Vec<ModuleRef>, module: &mut ModuleBox, damage: u8) -> u8 {
         // Can't deal more damage than there is HP
         let damage = cmp::min(self.hp, damage);
         
@@ -104,7 +107,9 @@ impl ShipState {
         let was_active = module.get_base().is_active();
         
         if self.shields > 0 {
-            self.shields -= cmp::min(self.shields, damage);
+            let damage = cmp::min(self.shields, damage);
+            self.shields -= damage;
+            damage
         } else {
             // Get the amount of damage dealt to the module
             let damage = module.get_base_mut().deal_damage(damage);
@@ -124,6 +129,8 @@ impl ShipState {
                     self.deactivate_module(module.get_base_mut());
                 }
             }
+            
+            damage
         }
     }
     
@@ -199,6 +206,10 @@ pub struct Ship {
     
     // Whether or not the ship successfully jumped
     pub jumping: bool,
+    
+    // Damage taken during the last simulation, in order, with the ID of the ship that dealt it if
+    // a ship did
+    pub damage_log: Vec<(Option<ShipId>, u8)>,
 }
 
 impl Ship {
@@ -217,6 +228,8 @@ impl Ship {
             
             target_sector: None,
             jumping: false,
+            
+            damage_log: vec!(),
         }
     }
     
@@ -341,8 +354,8 @@ impl Ship {
         }
     }
     
-    pub fn deal_damage(&mut self, module: &mut ModuleBox, damage: u8) {
-        self.state.deal_damage(&self.modules, module, damage);
+    pub fn deal_damage(&mut self, module: &mut ModuleBox, damage: u8) -> u8 {
+        self.state.deal_damage(&self.modules, module, damage)
     }
     
     pub fn server_preprocess(&mut self) {
@@ -405,6 +418,24 @@ impl Ship {
         }
     }
     
+    /// Turns off a powered module, giving its power back. Does nothing if the module isn't powered.
+    pub fn depower_module(&mut self, index: usize) {
+        let module =
+            match self.modules.get(index) {
+                Some(module) => module.clone(),
+                None => { return; },
+            };
+        let mut module = module.borrow_mut();
+        
+        if module.get_base().powered {
+            module.get_base_mut().powered = false;
+            module.get_base_mut().plan_powered = false;
+            self.state.power += module.get_base().get_power();
+            self.state.plan_power += module.get_base().get_power();
+            module.on_deactivated(&mut self.state, &self.modules);
+        }
+    }
+    
     pub fn get_module_plans(&self) -> Vec<module::ModulePlans> {
         self.modules.iter().map(|m| m.borrow().get_base().get_plans()).collect()
     }
@@ -555,6 +586,10 @@ impl Ship {
 
 ////////////////////////////////////////////////////////////////////////////////////////////////////
 
+// Version of the format stored ships are saved in. Bump this whenever ShipStored or anything saved
+// inside it changes, and teach ShipStored's decode to read the old version.
+static SHIP_STORED_VERSION: u32 = 1;
+
 pub struct ShipStored {
     pub id: ShipId,
     pub name: String,
@@ -603,6 +638,20 @@ impl ShipStored {
         }
     }
     
+    /// Same as `from_ship`, but copies a ship that's still in use.
+    pub fn from_ship_ref(ship: &Ship) -> ShipStored {
+        ShipStored {
+            id: ship.id,
+            name: ship.name.clone(),
+            state: ship.state,
+            modules: ship.modules.iter().map(|m| m.borrow().to_module_stored()).collect(),
+            width: ship.width,
+            height: ship.height,
+            level: ship.level,
+            target_sector: ship.target_sector,
+        }
+    }
+    
     pub fn to_ship(self, client_id: Option<ClientId>) -> Ship {
         Ship {
             id: self.id,
@@ -615,7 +664,45 @@ impl ShipStored {
             level: self.level,
             target_sector: self.target_sector,
             jumping: false,
+            damage_log: vec!(),
+        }
+    }
+}
+
+// Stored ships are saved to disk, so they start with the format version they were saved in
+impl Decodable for ShipStored {
+    fn decode<D: Decoder>(d: &mut D) -> Result<ShipStored, D::Error> {
+        let version: u32 = try!(Decodable::decode(d));
+        if version != SHIP_STORED_VERSION {
+            return Err(d.error(format!("Unknown stored ship version {}", version).as_slice()));
         }
+        
+        Ok(ShipStored {
+            id: try!(Decodable::decode(d)),
+            name: try!(Decodable::decode(d)),
+            state: try!(Decodable::decode(d)),
+            modules: try!(Decodable::decode(d)),
+            width: try!(Decodable::decode(d)),
+            height: try!(Decodable::decode(d)),
+            level: try!(Decodable::decode(d)),
+            target_sector: try!(Decodable::decode(d)),
+        })
+    }
+}
+
+impl Encodable for ShipStored {
+    fn encode<S: Encoder>(&self, s: &mut S) -> Result<(), S::Error> {
+        try!(SHIP_STORED_VERSION.encode(s));
+        
+        try!(self.id.encode(s));
+        try!(self.name.encode(s));
+        try!(self.state.encode(s));
+        try!(self.modules.encode(s));
+        try!(self.width.encode(s));
+        try!(self.height.encode(s));
+        try!(self.level.encode(s));
+        try!(self.target_sector.encode(s));
+        Ok(())
     }
 }
 
@@ -678,6 +765,7 @@ impl ShipNetworked {
             level: self.level,
             target_sector: self.target_sector,
             jumping: self.jumping,
+            damage_log: vec!(),
         }, module_targets)
     }
 }
@@ -16,12 +16,14 @@ use ship::ShipId;
 
 ////////////////////////////////////////////////////////////////////////////////////////////////////
 
+// Something that happens at a tick of the simulation. It gets the module that caused it, if a
+// module did.
 pub trait SimEvent {
-    fn apply(&mut self, &mut ModuleBox);
+    fn apply(&mut self, Option<&mut ModuleBox>);
 }
 
 pub struct SimEvents<'a> {
-    events: Vec<Vec<(ModuleRef, Box<SimEvent+'a>)>>,
+    events: Vec<Vec<(Option<ModuleRef>, Box<SimEvent+'a>)>>,
 }
 
 impl<'a> SimEvents<'a> {
@@ -39,10 +41,18 @@ impl<'a> SimEvents<'a> {
         let tick = tick as usize;
         while self.events[tick].len() > 0 {
             let (module, mut event) = self.events[tick].pop().unwrap();
-            event.apply(module.borrow_mut().deref_mut());
+            match module {
+                Some(module) => event.apply(Some(module.borrow_mut().deref_mut())),
+                None => event.apply(None),
+            }
         }
     }
     
+    /// Adds an event no module caused, like something the sector's environment does.
+    pub fn add(&mut self, tick: u32, event: Box<SimEvent+'a>) {
+        self.events[tick as usize].push((None, event));
+    }
+    
     pub fn create_adder<'b>(&'b mut self, module: ModuleRef) -> SimEventAdder<'a, 'b> {
         SimEventAdder {
             sim_events: self,
@@ -58,7 +68,7 @@ pub struct SimEventAdder<'a: 'b, 'b> {
 
 impl<'a, 'b> SimEventAdder<'a, 'b> {
     pub fn add(&mut self, tick: u32, event: Box<SimEvent+'a>) {
-        self.sim_events.events[tick as usize].push((self.module.clone(), event));
+        self.sim_events.events[tick as usize].push((Some(self.module.clone()), event));
     }
 }
 
@@ -1,18 +1,20 @@
 use std::ops::DerefMut;
 
-use module::{ModuleBox, ModuleRef};
-use ship::ShipRef;
+use module::{DamageVisualKind, ModuleBox, ModuleRef};
+use ship::{ShipId, ShipRef};
 use sim::SimEvent;
 
 pub struct DamageEvent {
+    attacker: Option<ShipId>, // Ship that dealt the damage, if a ship did
     ship: ShipRef,
     module: ModuleRef,
     damage: u8,
 }
 
 impl DamageEvent {
-    pub fn new(ship: ShipRef, module: ModuleRef, damage: u8) -> DamageEvent {
+    pub fn new(attacker: Option<ShipId>, ship: ShipRef, module: ModuleRef, damage: u8) -> DamageEvent {
         DamageEvent {
+            attacker: attacker,
             ship: ship,
             module: module,
             damage: damage,
@@ -21,8 +23,45 @@ impl DamageEvent {
 }
 
 impl SimEvent for DamageEvent {
-    fn apply(&mut self, module: &mut ModuleBox) {
+    fn apply(&mut self, _: Option<&mut ModuleBox>) {
         let mut ship = self.ship.borrow_mut();
-        ship.deal_damage(self.module.borrow_mut().deref_mut(), self.damage);
+        let damage = ship.deal_damage(self.module.borrow_mut().deref_mut(), self.damage);
+        if damage > 0 {
+            ship.damage_log.push((self.attacker, damage));
+        }
+    }
+}
+
+// Fire damage from the sector's environment. Modules it gets through to catch fire.
+pub struct FireEvent {
+    ship: ShipRef,
+    module: ModuleRef,
+    damage: u8,
+}
+
+impl FireEvent {
+    pub fn new(ship: ShipRef, module: ModuleRef, damage: u8) -> FireEvent {
+        FireEvent {
+            ship: ship,
+            module: module,
+            damage: damage,
+        }
+    }
+}
+
+impl SimEvent for FireEvent {
+    fn apply(&mut self, _: Option<&mut ModuleBox>) {
+        let mut ship = self.ship.borrow_mut();
+        let mut module = self.module.borrow_mut();
+        
+        // Shields stop the fire from reaching the module
+        let hp = module.get_base().get_hp();
+        let damage = ship.deal_damage(module.deref_mut(), self.damage);
+        if damage > 0 {
+            ship.damage_log.push((None, damage));
+        }
+        if module.get_base().get_hp() < hp {
+            module.get_base_mut().add_damage_visual(DamageVisualKind::Fire);
+        }
     }
 }
\ No newline at end of file
@@ -3,6 +3,7 @@ use std::rand;
 use std::rc::Rc;
 use std::ops::{Deref, DerefMut};
 use std::path::Path;
+use time;
 
 use event::{Events, GenericEvent, RenderArgs};
 use graphics::{Context, Rectangle};
@@ -13,10 +14,11 @@ use opengl_graphics::glyph_cache::GlyphCache;
 use asset_store::AssetStore;
 use battle_state::BattleContext;
 use gui::TextButton;
+use login::AccountProgress;
 use module;
 use module::{IModule, ModuleBox, ModuleRef};
 use net::ClientId;
-use sector_data::SectorData;
+use sector_data::{SectorData, SectorEnvironment, SectorStatus};
 use ship::{Ship, ShipId, ShipRef, ShipState};
 use sim::SimEffects;
 use star_map_gui::{StarMapAction, StarMapGui};
@@ -28,6 +30,9 @@ static SHIP_OFFSET_Y: f64 = 170.0;
 static ENEMY_OFFSET_X: f64 = 80.0;
 static ENEMY_OFFSET_Y: f64 = 50.0;
 
+// How long messages from the server stay on screen, in milliseconds
+static SERVER_MESSAGE_TIME: i64 = 10000;
+
 pub struct ModuleIcons {
     pub power_on_texture: Texture,
     pub power_off_texture: Texture,
@@ -76,6 +81,18 @@ pub struct SpaceGui {
     
     // Logout button
     logout_button: TextButton,
+    
+    // Round trip time to the server in milliseconds, for the ping indicator
+    latency: Option<u32>,
+    
+    // Last message from the server and when it arrived
+    server_message: Option<(String, time::Timespec)>,
+    
+    // The player's credits, experience and stats, once the server has sent them
+    progress: Option<AccountProgress>,
+    
+    // What space is like in the player's sector
+    environment: SectorEnvironment,
 
     // targets
     target_icons: Vec<TargetIcon>,
@@ -135,11 +152,40 @@ impl SpaceGui {
             show_star_map: false,
             
             logout_button: TextButton::new("logout".to_string(), 20, [550.0, 100.0], [120.0, 40.0]),
+            
+            latency: None,
+            server_message: None,
+            progress: None,
+            environment: context.environment,
 
             target_icons: target_icons,
         }
     }
     
+    pub fn set_sectors(&mut self, sectors: Vec<SectorData>) {
+        self.star_map_gui.set_sectors(sectors);
+    }
+    
+    pub fn set_sector_statuses(&mut self, statuses: Vec<SectorStatus>) {
+        self.star_map_gui.set_statuses(statuses);
+    }
+    
+    pub fn set_pilot(&mut self, pilot: String) {
+        self.star_map_gui.set_pilot(pilot);
+    }
+    
+    pub fn set_latency(&mut self, latency: Option<u32>) {
+        self.latency = latency;
+    }
+    
+    pub fn show_server_message(&mut self, message: String) {
+        self.server_message = Some((message, time::now().to_timespec()));
+    }
+    
+    pub fn set_progress(&mut self, progress: AccountProgress) {
+        self.progress = Some(progress);
+    }
+    
     pub fn event<E: GenericEvent>(&mut self, e: &E, client_ship: &ShipRef) {
         use event::*;
         
@@ -225,6 +271,7 @@ impl SpaceGui {
     
     fn draw_screen(&mut self, context: &Context, gl: &mut Gl, glyph_cache: &mut GlyphCache, asset_store: &AssetStore, sim_effects: &mut SimEffects, client_ship: &mut Ship, time: f64, dt: f64) {
         use graphics::*;
+        use graphics::text::Text;
         
         // Draw the space background
         self.space_bg.update(dt);
@@ -387,6 +434,65 @@ impl SpaceGui {
         
         self.star_map_button.draw(context, gl, glyph_cache);
         self.logout_button.draw(context, gl, glyph_cache);
+        
+        // Draw the ping indicator. Green is good, yellow is meh, red is bad.
+        if let Some(latency) = self.latency {
+            let color =
+                if latency < 100 { [0.0, 1.0, 0.0, 1.0] }
+                else if latency < 250 { [1.0, 1.0, 0.0, 1.0] }
+                else { [1.0, 0.0, 0.0, 1.0] };
+        
+            let context = context.trans(550.0, 160.0);
+            Text::colored(color, 10).draw(
+                format!("ping {} ms", latency).as_slice(),
+                glyph_cache,
+                &context.draw_state, context.transform,
+                gl,
+            );
+        }
+        
+        // Draw the player's progress under the ping indicator
+        if let Some(ref progress) = self.progress {
+            let lines = [
+                format!("level {} ({} xp to next)", progress.get_level(), progress.get_experience_to_next_level()),
+                format!("credits {}", progress.credits),
+                format!("kills {} deaths {} jumps {}", progress.kills, progress.deaths, progress.jumps),
+            ];
+            
+            for (i, line) in lines.iter().enumerate() {
+                let context = context.trans(550.0, 180.0 + (i as f64)*15.0);
+                Text::colored([1.0; 4], 10).draw(
+                    line.as_slice(),
+                    glyph_cache,
+                    &context.draw_state, context.transform,
+                    gl,
+                );
+            }
+        }
+        
+        // Warn the player about the sector's environment under their progress
+        if self.environment != SectorEnvironment::Normal {
+            let context = context.trans(550.0, 230.0);
+            Text::colored([1.0, 0.6, 0.2, 1.0], 10).draw(
+                format!("{}: {}", self.environment, self.environment.get_description()).as_slice(),
+                glyph_cache,
+                &context.draw_state, context.transform,
+                gl,
+            );
+        }
+        
+        // Draw the last message from the server until it gets old
+        if let Some((ref message, received_time)) = self.server_message {
+            if (time::now().to_timespec() - received_time).num_milliseconds() < SERVER_MESSAGE_TIME {
+                let context = context.trans(550.0, 30.0);
+                Text::colored([1.0, 1.0, 0.0, 1.0], 16).draw(
+                    message.as_slice(),
+                    glyph_cache,
+                    &context.draw_state, context.transform,
+                    gl,
+                );
+            }
+        }
 
         // Draw target icons
         for (i, icon) in self.target_icons.iter().enumerate() {
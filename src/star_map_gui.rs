@@ -1,4 +1,5 @@
 use std::cell::RefCell;
+use std::collections::HashMap;
 
 use sdl2_window::Sdl2Window;
 use event::{Events, GenericEvent};
@@ -8,7 +9,7 @@ use opengl_graphics::{Gl, Texture};
 use opengl_graphics::glyph_cache::GlyphCache;
 
 use gui::TextButton;
-use sector_data::{SectorData, SectorId};
+use sector_data::{SectorData, SectorEnvironment, SectorId, SectorStatus};
 use vec::Vec2;
 
 pub enum StarMapAction {
@@ -18,11 +19,20 @@ pub enum StarMapAction {
 
 pub struct StarMapGui {
     sectors: Vec<SectorData>,
+    
+    // Who's in each sector, as of the server's last update
+    statuses: HashMap<SectorId, SectorStatus>,
 
     action: Option<StarMapAction>,
     
     selected_sector: Option<SectorId>,
     
+    // Sector under the mouse, which gets its status shown
+    hovered_sector: Option<SectorId>,
+    
+    // Who the player is, shown next to the title
+    pilot: String,
+    
     // Buttons
     close_button: TextButton,
     jump_button: TextButton,
@@ -32,19 +42,37 @@ impl StarMapGui {
     pub fn new(sectors: Vec<SectorData>) -> StarMapGui {
         StarMapGui {
             sectors: sectors,
+            statuses: HashMap::new(),
         
             action: None,
             
             selected_sector: None,
+            hovered_sector: None,
+            
+            pilot: String::new(),
             
             close_button: TextButton::new("Close".to_string(), 20, [450.0, 400.0], [150.0, 40.0]),
             jump_button: TextButton::new("Jump".to_string(), 20, [610.0, 400.0], [150.0, 40.0]),
         }
     }
 
+    pub fn set_sectors(&mut self, sectors: Vec<SectorData>) {
+        self.sectors = sectors;
+    }
+    
+    pub fn set_statuses(&mut self, statuses: Vec<SectorStatus>) {
+        self.statuses = statuses.into_iter().map(|status| (status.id, status)).collect();
+    }
+    
+    pub fn set_pilot(&mut self, pilot: String) {
+        self.pilot = pilot;
+    }
+
     pub fn event<E: GenericEvent>(&mut self, e: &E, mouse_pos: [f64; 2]) -> Option<StarMapAction> {
         use event::*;
         
+        self.hovered_sector = self.sector_at(mouse_pos);
+        
         e.press(|button| {
             match button {
                 Button::Mouse(button) => {
@@ -76,6 +104,13 @@ impl StarMapGui {
     }
 
     fn on_mouse_left_pressed(&mut self, mouse_pos: [f64; 2], button: mouse::MouseButton) {
+        if let Some(sector_id) = self.sector_at(mouse_pos) {
+            self.selected_sector = Some(sector_id);
+        }
+    }
+    
+    // Sector whose circle is under the mouse, if any
+    fn sector_at(&self, mouse_pos: [f64; 2]) -> Option<SectorId> {
         let mouse_pos = Vec2 { x: mouse_pos[0] - 5.0, y: mouse_pos[1] - 25.0 };
     
         for sector in &self.sectors {
@@ -83,9 +118,11 @@ impl StarMapGui {
             let map_pos = sector.map_position;
         
             if (map_pos - mouse_pos).length() <= radius {
-                self.selected_sector = Some(sector.id);
+                return Some(sector.id);
             }
         }
+        
+        None
     }
 
     pub fn draw(&mut self, context: &Context, gl: &mut Gl, glyph_cache: &mut GlyphCache) {
@@ -103,6 +140,22 @@ impl StarMapGui {
             Rectangle::new([0.0, 0.0, 0.0, 1.0])
                 .draw([0.0, 0.0, 800.0 - 10.0, 400.0 - 30.0], &context.draw_state, context.transform, gl);
             
+            // Draw the jump lanes under the sectors, each one once
+            for sector in &self.sectors {
+                for neighbour_id in sector.neighbours.iter().filter(|id| id.0 > sector.id.0) {
+                    if let Some(neighbour) = self.sectors.iter().find(|s| s.id == *neighbour_id) {
+                        let ref start = sector.map_position;
+                        let ref end = neighbour.map_position;
+                        Line::new([0.4, 0.4, 0.6, 1.0], 1.0)
+                            .draw(
+                                [start.x, start.y, end.x, end.y],
+                                &context.draw_state, context.transform,
+                                gl
+                            );
+                    }
+                }
+            }
+            
             for sector in &self.sectors {
                 let radius = 10.0;
                 let ref map_pos = sector.map_position;
@@ -119,6 +172,54 @@ impl StarMapGui {
                         &context.draw_state, context.transform,
                         gl
                     );
+                
+                // Label the sector with its name
+                let label_context = context.trans(map_pos.x - radius, map_pos.y + radius + 5.0);
+                Text::colored([1.0; 4], 12).draw(
+                    sector.name.as_slice(),
+                    glyph_cache,
+                    &label_context.draw_state, label_context.transform,
+                    gl,
+                );
+                
+                // And with what's special about it, if anything
+                if sector.environment != SectorEnvironment::Normal {
+                    let label_context = label_context.trans(0.0, 12.0);
+                    Text::colored([1.0, 0.6, 0.2, 1.0], 10).draw(
+                        format!("{}", sector.environment).as_slice(),
+                        glyph_cache,
+                        &label_context.draw_state, label_context.transform,
+                        gl,
+                    );
+                }
+            }
+            
+            // Show who's in the sector under the mouse, on top of everything else
+            if let Some(sector) = self.hovered_sector.and_then(|id| self.sectors.iter().find(|s| s.id == id)) {
+                let lines =
+                    match self.statuses.get(&sector.id) {
+                        Some(status) => vec![
+                            format!("players: {}", status.players),
+                            format!("AI ships: {}", status.ai_ships),
+                            format!("average level: {:.1}", status.average_level),
+                        ],
+                        None => vec!["no news yet".to_string()],
+                    };
+                
+                let ref map_pos = sector.map_position;
+                let box_context = context.trans(map_pos.x + 15.0, map_pos.y - 10.0);
+                Rectangle::new([0.1, 0.1, 0.2, 0.9])
+                    .draw([0.0, 0.0, 130.0, 8.0 + (lines.len() as f64)*14.0], &box_context.draw_state, box_context.transform, gl);
+                
+                for (i, line) in lines.iter().enumerate() {
+                    let line_context = box_context.trans(5.0, 14.0 + (i as f64)*14.0);
+                    Text::colored([1.0; 4], 11).draw(
+                        line.as_slice(),
+                        glyph_cache,
+                        &line_context.draw_state, line_context.transform,
+                        gl,
+                    );
+                }
             }
         }
         
@@ -130,6 +231,14 @@ impl StarMapGui {
                 &context.draw_state, context.transform,
                 gl,
             );
+            
+            let context = context.trans(100.0, 0.0);
+            Text::colored([0.8, 0.8, 1.0, 1.0], 12).draw(
+                self.pilot.as_slice(),
+                glyph_cache,
+                &context.draw_state, context.transform,
+                gl,
+            );
         }
         
         // Draw the buttons
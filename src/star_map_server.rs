@@ -1,141 +1,416 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::old_io::timer::Timer;
 use std::sync::mpsc::{channel, Sender, Receiver};
 use std::thread::Builder;
+use std::time::Duration;
 
-use battle_state::BattleContext;
-use login::AccountBox;
+use battle_state::{BattleContext, ClientPacketId};
+use galaxy::Galaxy;
+use login::{AccountBox, AccountDbRef, SessionToken, send_resume_failed};
 use net::{
+    ClientId,
     OutPacket,
     ServerSlot,
     ServerSlotId,
     SlotInMsg,
 };
-use sector_data::{SectorData, SectorId};
-use sector_state::SectorState;
-use vec::Vec2;
+use sector_data::{SectorData, SectorId, SectorStatus};
+use sector_state::{ResumeResult, SectorCommand, SectorState};
+
+// Time between sending every client who's in each sector, in milliseconds
+static STATUS_INTERVAL: i64 = 5000;
 
 pub struct Sector {
     pub slot_id: ServerSlotId,
     pub to_sector: Sender<AccountBox>,
-    pub from_sector: Receiver<AccountBox>,
     pub ack: Receiver<()>,
+    pub resume: Sender<(SessionToken, ClientId)>,
+    pub commands: Sender<SectorCommand>,
     pub data: SectorData,
 }
 
+// Admin commands for the star map
+pub enum StarMapCommand {
+    ListSectors(Sender<Vec<(SectorId, ServerSlotId)>>), // List every sector and its slot
+    Sector(SectorId, SectorCommand),                     // Pass a command on to a sector. Dropped if there's no such sector.
+    LogOutAll(Sender<()>),                               // Send every account back to the login server, for shutting down
+    Shutdown,                                            // Stop running
+}
+
+// Things that wake up the star map server
+enum StarMapEvent {
+    Slot(SlotInMsg),
+    LoggedIn(AccountBox),           // Account arrived from the login server
+    Resume(SessionToken, ClientId), // Client wants back into its session (session_token, new client_id)
+    Resumed(ResumeResult),          // Sector answered whether the client got its session back
+    Jumped(AccountBox),             // Account left its sector for another one
+    Status(SectorStatus),           // Sector reported who's in it
+    SendStatuses,                   // Time to tell clients who's in each sector
+    Command(StarMapCommand),        // Admin wants something done
+}
+
 pub struct StarMapServer {
     slot: ServerSlot,
     sectors: HashMap<SectorId, Sector>,
+    
+    // Every sector sends the accounts of jumping ships through here
+    jump_receiver: Receiver<AccountBox>,
+    
+    // Every sector answers resume requests through here
+    resumed_receiver: Receiver<ResumeResult>,
+    
+    // Every sector reports who's in it through here after each turn, and the latest reports
+    status_receiver: Receiver<SectorStatus>,
+    statuses: HashMap<SectorId, SectorStatus>,
+    
+    // Accounts of disconnected clients go back to the login server through here
+    logout_sender: Sender<AccountBox>,
+    
+    // Clients that disconnected while their accounts were waiting to be handled
+    disconnected_clients: HashSet<ClientId>,
+    
+    // Which sector each session's account was last sent to
+    sessions: HashMap<SessionToken, SectorId>,
+    
+    // Set once the server starts shutting down. Accounts arriving after that get logged out.
+    shutting_down: bool,
+    
+    // Where accounts get saved when they jump, if anywhere
+    account_db: Option<AccountDbRef>,
+    
+    // Where accounts whose sector doesn't exist anymore get sent
+    start_sector: SectorId,
 }
 
 impl StarMapServer {
-    pub fn new(slot: ServerSlot) -> StarMapServer {
+    /// Creates the star map with one sector for each sector in the galaxy, each running on its own
+    /// thread.
+    pub fn new(slot: ServerSlot, logout_sender: Sender<AccountBox>, account_db: Option<AccountDbRef>, galaxy: Galaxy) -> StarMapServer {
         let slot_id = slot.get_id();
     
         let mut sectors = HashMap::new();
+        let (jump_sender, jump_receiver) = channel();
+        let (status_sender, status_receiver) = channel();
+        let (resumed_sender, resumed_receiver) = channel();
         
-        // Sector 0
-        let (to_sector_sender, to_sector_receiver) = channel();
-        let (from_sector_sender, from_sector_receiver) = channel();
-        let (ack_sender, ack_receiver) = channel();
-        let sector_slot = slot.create_slot();
-        let sector_id = SectorId(0);
-        sectors.insert(sector_id, Sector {
-            slot_id: sector_slot.get_id(),
-            to_sector: to_sector_sender,
-            from_sector: from_sector_receiver,
-            ack: ack_receiver,
-            data: SectorData {
-                id: sector_id,
-                map_position: Vec2 { x: 50.0, y: 50.0 },
-            },
-        });
-        
-        Builder::new()
-            .name(format!("sector_{}_thread", 0))
-            .stack_size(8388608)
-            .spawn(move || {
-                let mut sector_state = SectorState::new(sector_slot, slot_id, BattleContext::new(vec!()), false);
-                sector_state.run(from_sector_sender, to_sector_receiver, ack_sender, false);
-            });
+        // Every sector gets the whole map so it can check its ships' jumps
+        let star_map: Vec<SectorData> = galaxy.sectors.iter().map(|sector_def| sector_def.to_sector_data()).collect();
         
-        // Sector 1
-        let (to_sector_sender, to_sector_receiver) = channel();
-        let (from_sector_sender, from_sector_receiver) = channel();
-        let (ack_sender, ack_receiver) = channel();
-        let sector_slot = slot.create_slot();
-        let sector_id = SectorId(1);
-        sectors.insert(sector_id, Sector {
-            slot_id: sector_slot.get_id(),
-            to_sector: to_sector_sender,
-            from_sector: from_sector_receiver,
-            ack: ack_receiver,
-            data: SectorData {
-                id: sector_id,
-                map_position: Vec2 { x: 100.0, y: 100.0 },
-            },
-        });
-        
-        Builder::new()
-            .name(format!("sector_{}_thread", 1))
-            .stack_size(8388608)
-            .spawn(move || {
-                let mut sector_state = SectorState::new(sector_slot, slot_id, BattleContext::new(vec!()), false);
-                sector_state.run(from_sector_sender, to_sector_receiver, ack_sender, true);
+        for sector_def in galaxy.sectors.iter() {
+            let (to_sector_sender, to_sector_receiver) = channel();
+            let sector_jump_sender = jump_sender.clone();
+            let (ack_sender, ack_receiver) = channel();
+            let (resume_sender, resume_receiver) = channel();
+            let sector_resumed_sender = resumed_sender.clone();
+            let sector_logout_sender = logout_sender.clone();
+            let (command_sender, command_receiver) = channel();
+            let sector_account_db = account_db.clone();
+            let sector_slot = slot.create_slot();
+            let sector_id = sector_def.get_id();
+            let ai_ships: Vec<(String, u8)> = sector_def.ai_ships.iter().map(|ai| (ai.name.clone(), ai.level)).collect();
+            let sector_star_map = star_map.clone();
+            let sector_status_sender = status_sender.clone();
+            
+            sectors.insert(sector_id, Sector {
+                slot_id: sector_slot.get_id(),
+                to_sector: to_sector_sender,
+                ack: ack_receiver,
+                resume: resume_sender,
+                commands: command_sender,
+                data: sector_def.to_sector_data(),
             });
+            
+            Builder::new()
+                .name(format!("sector_{}_thread", sector_id.0))
+                .stack_size(8388608)
+                .spawn(move || {
+                    let mut sector_state = SectorState::new(sector_slot, slot_id, BattleContext::new(vec!()), false);
+                    if let Some(account_db) = sector_account_db {
+                        sector_state.set_account_db(account_db);
+                    }
+                    sector_state.set_star_map(sector_id, sector_star_map);
+                    sector_state.set_status_sender(sector_status_sender);
+                    sector_state.run(sector_jump_sender, to_sector_receiver, ack_sender, resume_receiver, sector_resumed_sender,
+                                     sector_logout_sender, command_receiver, ai_ships);
+                });
+            
+            println!("Started sector {} '{}'", sector_id.0, sector_def.name);
+        }
         
         StarMapServer {
             slot: slot,
             sectors: sectors,
+            jump_receiver: jump_receiver,
+            resumed_receiver: resumed_receiver,
+            status_receiver: status_receiver,
+            statuses: HashMap::new(),
+            logout_sender: logout_sender,
+            disconnected_clients: HashSet::new(),
+            sessions: HashMap::new(),
+            shutting_down: false,
+            account_db: account_db,
+            start_sector: galaxy.get_start_sector(),
         }
     }
     
-    pub fn run(&mut self, account_receiver: Receiver<AccountBox>) {
+    /// Runs the star map until an admin shuts it down.
+    pub fn run(&mut self, account_receiver: Receiver<AccountBox>, resume_receiver: Receiver<(SessionToken, ClientId)>,
+               command_receiver: Receiver<StarMapCommand>) {
+        let mut status_timer = Timer::new().ok().expect("Failed to create sector status timer");
+        let status_ticks = status_timer.periodic(Duration::milliseconds(STATUS_INTERVAL));
+        
         loop {
-            if let Ok(slot_msg) = self.slot.try_receive() {
-                match slot_msg {
-                    SlotInMsg::Joined(client_id) => {
-                        println!("Client {} joined the star map", client_id);
-                    },
-                    SlotInMsg::ReceivedPacket(client_id, mut packet) => {
+            // Sleep until something happens
+            let event = {
+                let slot_receiver = self.slot.get_receiver();
+                let jump_receiver = &self.jump_receiver;
+                let resumed_receiver = &self.resumed_receiver;
+                let status_receiver = &self.status_receiver;
+                let status_ticks = &status_ticks;
+                let account_receiver = &account_receiver;
+                let resume_receiver = &resume_receiver;
+                let command_receiver = &command_receiver;
+                
+                select! {
+                    slot_msg = slot_receiver.recv() => StarMapEvent::Slot(slot_msg.ok().expect("Star map slot closed")),
+                    account = account_receiver.recv() => StarMapEvent::LoggedIn(account.ok().expect("Login channel closed")),
+                    resume = resume_receiver.recv() => {
+                        let (session_token, client_id) = resume.ok().expect("Resume channel closed");
+                        StarMapEvent::Resume(session_token, client_id)
                     },
-                    _ => {},
+                    result = resumed_receiver.recv() => StarMapEvent::Resumed(result.ok().expect("Resume result channel closed")),
+                    account = jump_receiver.recv() => StarMapEvent::Jumped(account.ok().expect("Jump channel closed")),
+                    status = status_receiver.recv() => StarMapEvent::Status(status.ok().expect("Sector status channel closed")),
+                    _ = status_ticks.recv() => StarMapEvent::SendStatuses,
+                    command = command_receiver.recv() => StarMapEvent::Command(command.ok().expect("Star map command channel closed"))
                 }
-            }
-            
-            if let Ok(account) = account_receiver.try_recv() {
-                let client_id = account.client_id.expect("This needs to have a client ID");
-            
-                let sector_data: Vec<SectorData> = self.sectors.iter().map(|(_, s)| s.data.clone()).collect();
+            };
             
-                let mut sectors_packet = OutPacket::new();
-                sectors_packet.write(&sector_data).ok().expect("Failed to write SectorData");
-                self.slot.send(client_id, sectors_packet);
-            
-                let ref sector = self.sectors[&account.sector];
-                
-                sector.to_sector.send(account);
-                sector.ack.recv();
-                self.slot.transfer_client(client_id, sector.slot_id);
+            match event {
+                StarMapEvent::Slot(slot_msg) => self.handle_slot_msg(slot_msg, &account_receiver, &resume_receiver),
+                StarMapEvent::LoggedIn(account) => self.handle_login(account),
+                StarMapEvent::Resume(session_token, client_id) => self.handle_resume(session_token, client_id),
+                StarMapEvent::Resumed(result) => self.handle_resumed(result),
+                StarMapEvent::Jumped(account) => self.handle_jump(account),
+                StarMapEvent::Status(status) => {
+                    self.statuses.insert(status.id, status);
+                },
+                StarMapEvent::SendStatuses => {
+                    self.slot.broadcast_all(self.build_status_packet());
+                },
+                StarMapEvent::Command(StarMapCommand::ListSectors(reply)) => {
+                    reply.send(self.sectors.iter().map(|(sector_id, sector)| (*sector_id, sector.slot_id)).collect());
+                },
+                StarMapEvent::Command(StarMapCommand::Sector(sector_id, command)) => {
+                    if let Some(sector) = self.sectors.get(&sector_id) {
+                        sector.commands.send(command);
+                    }
+                },
+                StarMapEvent::Command(StarMapCommand::LogOutAll(done)) => {
+                    self.log_out_all(&account_receiver);
+                    done.send(());
+                },
+                StarMapEvent::Command(StarMapCommand::Shutdown) => {
+                    println!("Star map shutting down");
+                    return;
+                },
             }
-            
-            // Send any jumping ships to their new sector
-            for sector in self.sectors.values() {
-                if let Ok(mut account) = sector.from_sector.try_recv() {
-                    let client_id = account.client_id.expect("This needs to have a client ID");
-                    
-                    let target_sector =
-                        {
-                            let ship = account.ship.as_mut().expect("Ship must exist");
-                            ship.target_sector.take().expect("There must be a target sector")
-                        };
-                    
-                    let ref sector = self.sectors[&target_sector];
-                    
-                    sector.to_sector.send(account);
-                    sector.ack.recv();
-                    self.slot.transfer_client(client_id, sector.slot_id);
+        }
+    }
+    
+    // Gets every account back to the login server and keeps any more from going out to sectors
+    fn log_out_all(&mut self, account_receiver: &Receiver<AccountBox>) {
+        self.shutting_down = true;
+        
+        for sector in self.sectors.values() {
+            let (done_sender, done_receiver) = channel();
+            sector.commands.send(SectorCommand::LogOutAll(done_sender));
+            done_receiver.recv();
+        }
+        
+        // Sectors send jumping accounts off before they get to the log out command, so everything
+        // on its way here is already waiting
+        while let Ok(account) = self.jump_receiver.try_recv() {
+            self.handle_jump(account);
+        }
+        while let Ok(account) = account_receiver.try_recv() {
+            self.handle_login(account);
+        }
+    }
+    
+    fn handle_slot_msg(&mut self, slot_msg: SlotInMsg, account_receiver: &Receiver<AccountBox>,
+                       resume_receiver: &Receiver<(SessionToken, ClientId)>) {
+        match slot_msg {
+            SlotInMsg::Joined(client_id) => {
+                println!("Client {} joined the star map", client_id);
+            },
+            SlotInMsg::ReceivedPacket(client_id, mut packet) => {
+            },
+            SlotInMsg::Disconnected(client_id) => {
+                // Accounts and resume requests are always sent here before their clients, so if the
+                // client's account hasn't been handled yet, it's already waiting. Handling everything
+                // that's waiting logs it out. If it isn't there, it was already sent on to a sector,
+                // which hears about the disconnect too.
+                self.disconnected_clients.insert(client_id);
+                while let Ok(account) = account_receiver.try_recv() {
+                    self.handle_login(account);
                 }
+                while let Ok(account) = self.jump_receiver.try_recv() {
+                    self.handle_jump(account);
+                }
+                while let Ok((session_token, resume_client_id)) = resume_receiver.try_recv() {
+                    self.handle_resume(session_token, resume_client_id);
+                }
+                self.disconnected_clients.remove(&client_id);
+            },
+        }
+    }
+    
+    fn handle_login(&mut self, mut account: AccountBox) {
+        let client_id = account.client_id.expect("This needs to have a client ID");
+        
+        if self.disconnected_clients.remove(&client_id) {
+            self.log_out(account);
+            return;
+        }
+        
+        if self.shutting_down {
+            self.slot.disconnect_client(client_id);
+            self.log_out(account);
+            return;
+        }
+    
+        let sector_data: Vec<SectorData> = self.sectors.iter().map(|(_, s)| s.data.clone()).collect();
+    
+        let mut sectors_packet = OutPacket::new();
+        sectors_packet.write(&ClientPacketId::StarMap).ok().expect("Failed to write star map packet ID");
+        sectors_packet.write(&sector_data).ok().expect("Failed to write SectorData");
+        self.slot.send(client_id, sectors_packet);
+        self.slot.send(client_id, self.build_status_packet());
+    
+        // The galaxy might have changed since the account last logged out
+        if !self.sectors.contains_key(&account.sector) {
+            println!("Sector {} is gone, sending {} to the start sector", account.sector.0, account.username);
+            account.sector = self.start_sector;
+        }
+        
+        if let Some(session_token) = account.session_token {
+            self.sessions.insert(session_token, account.sector);
+        }
+        
+        let ref sector = self.sectors[&account.sector];
+        
+        sector.to_sector.send(account);
+        sector.ack.recv();
+        self.slot.transfer_client(client_id, sector.slot_id);
+    }
+    
+    // Asks the sector a session's account was last sent to for the client's ship back. The sector
+    // answers with a Resumed event.
+    fn handle_resume(&mut self, session_token: SessionToken, client_id: ClientId) {
+        if self.disconnected_clients.remove(&client_id) {
+            // Disconnected again before it even got here
+            return;
+        }
+        
+        let asked =
+            match self.sessions.get(&session_token).and_then(|sector_id| self.sectors.get(sector_id)) {
+                Some(sector) => sector.resume.send((session_token, client_id)).is_ok(),
+                None => false,
+            };
+        
+        if !asked {
+            // The session ended before the client made it back
+            self.sessions.remove(&session_token);
+            send_resume_failed(&self.slot, client_id);
+        }
+    }
+    
+    // Sends a resuming client to its ship, or keeps looking for the ship if it jumped away
+    fn handle_resumed(&mut self, result: ResumeResult) {
+        if result.resumed {
+            self.slot.transfer_client(result.client_id, self.sectors[&result.sector_id].slot_id);
+            return;
+        }
+        
+        // Sectors send jumping accounts here before answering, so if the account left on a jump
+        // it's already waiting. Once it's sent on, the session points at its new sector.
+        while let Ok(account) = self.jump_receiver.try_recv() {
+            self.handle_jump(account);
+        }
+        
+        if self.sessions.get(&result.session_token) == Some(&result.sector_id) {
+            // The session ended before the client made it back
+            self.sessions.remove(&result.session_token);
+            send_resume_failed(&self.slot, result.client_id);
+        } else {
+            self.handle_resume(result.session_token, result.client_id);
+        }
+    }
+    
+    // Sends a jumping ship to its new sector
+    fn handle_jump(&mut self, mut account: AccountBox) {
+        let client_id = account.client_id.expect("This needs to have a client ID");
+        
+        let mut target_sector =
+            {
+                let ship = account.ship.as_mut().expect("Ship must exist");
+                ship.target_sector.take().expect("There must be a target sector")
+            };
+        
+        // Sectors check jumps before letting ships go, so this shouldn't happen. Don't take the
+        // star map down over it if it does.
+        if !self.sectors.contains_key(&target_sector) {
+            println!("{} jumped to sector {}, which doesn't exist. Sending them to the start sector.", account.username, target_sector.0);
+            target_sector = self.start_sector;
+        }
+        account.sector = target_sector;
+        
+        if self.disconnected_clients.remove(&client_id) {
+            self.log_out(account);
+            return;
+        }
+        
+        if self.shutting_down {
+            self.slot.disconnect_client(client_id);
+            self.log_out(account);
+            return;
+        }
+        
+        if let Some(session_token) = account.session_token {
+            self.sessions.insert(session_token, target_sector);
+        }
+        
+        // Save the account in between sectors, when its ship is in storage
+        if let Some(ref account_db) = self.account_db {
+            if let Err(e) = account_db.lock().unwrap().save(&account) {
+                println!("Failed to save account {}: {}", account.username, e);
             }
         }
+        
+        let ref sector = self.sectors[&target_sector];
+        
+        sector.to_sector.send(account);
+        sector.ack.recv();
+        self.slot.transfer_client(client_id, sector.slot_id);
+    }
+    
+    // Packet telling clients who's in each sector, as of the sectors' last reports
+    fn build_status_packet(&self) -> OutPacket {
+        let statuses: Vec<SectorStatus> = self.statuses.values().map(|status| status.clone()).collect();
+        
+        let mut packet = OutPacket::new();
+        packet.write(&ClientPacketId::SectorStatus).ok().expect("Failed to write sector status packet ID");
+        packet.write(&statuses).ok().expect("Failed to write sector statuses");
+        packet
+    }
+    
+    // Sends an account back to the login server
+    fn log_out(&mut self, account: AccountBox) {
+        if let Some(session_token) = account.session_token {
+            self.sessions.remove(&session_token);
+        }
+        
+        self.logout_sender.send(account);
     }
 }
\ No newline at end of file
@@ -13,6 +13,12 @@ use net::{ClientId, ServerSlot, ServerSlotId, SlotInMsg, InPacket, OutPacket};
 use ship::{Ship, ShipId, ShipRef, ShipStored};
 use sim::SimEvents;
 
+// Things that wake up a station
+enum StationEvent {
+    Slot(SlotInMsg),
+    Account(AccountBox), // Account arrived from the star map
+}
+
 pub struct SectorState {
     slot: ServerSlot,
     star_map_slot_id: ServerSlotId,
@@ -31,10 +37,21 @@ impl SectorState {
     }
     
     pub fn run(&mut self, to_map_sender: Sender<AccountBox>, from_map_receiver: Receiver<AccountBox>) {
-        loop {        
+        loop {
+            // Sleep until something happens
+            let event = {
+                let slot_receiver = self.slot.get_receiver();
+                let from_map_receiver = &from_map_receiver;
+                
+                select! {
+                    msg = slot_receiver.recv() => StationEvent::Slot(msg.ok().expect("Station slot closed")),
+                    account = from_map_receiver.recv() => StationEvent::Account(account.ok().expect("Star map channel closed"))
+                }
+            };
+            
             ///////////////////////////////////////////////////////////
             // Receiver ServerSlot messages
-            if let Ok(msg) = self.slot.try_receive() {
+            if let StationEvent::Slot(msg) = event {
                 match msg {
                     SlotInMsg::Joined(client_id) => {
                         println!("Client {} joined battle {}", client_id, self.slot.get_id());
@@ -46,7 +63,7 @@ impl SectorState {
             
             ///////////////////////////////////////////////////////////
             // Receive new clients
-            if let Ok(mut account) = from_map_receiver.try_recv() {
+            else if let StationEvent::Account(mut account) = event {
                 let client_id = account.client_id.expect("This must have a client ID");
                 
                 // Add the client to the waiting list